@@ -4,6 +4,9 @@ use near_sdk::AccountId;
 
 use crate::*;
 
+/// Gas reserved to execute a single timeout action, including the cross-contract calls it makes
+pub const GAS_TIMEOUT_ACTION: Gas = Gas(70_000_000_000_000);
+
 /// Function call arguments.
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
 #[cfg_attr(not(target_arch = "wasm32"), derive(Debug))]
@@ -20,7 +23,7 @@ pub struct ActionCall {
 #[serde(crate = "near_sdk::serde")]
 pub enum ActionType {
     /// Transfers given amount of `token_id` from this DAO to `receiver_id`.
-    /// If `msg` is not None, calls `ft_transfer_call` with given `msg`. Fails if this base token.
+    /// If `msg` is not None, calls `ft_transfer_call` with given `msg`, crediting back any refunded amount. Ignored for base token.
     /// For `ft_transfer` and `ft_transfer_call` `memo` is the `description` of the proposal.
     Transfer {
         /// Can be "" for $NEAR or a valid account id.
@@ -37,7 +40,7 @@ pub enum ActionType {
         /// Can be "" for $NEAR or a valid token account id.
         #[serde(with = "serde_with::rust::string_empty_as_none")]
         token_id: Option<AccountId>,
-        /// NOTE: If sending FT, storage deposit needs to be done outside this budget or it will fail.
+        /// NOTE: If sending FT to an unregistered recipient, storage deposit is paid from the treasury storage budget, not this budget.
         receiver_id: AccountId,
         /// For a whole number to be paid each time
         amount: Option<U128>,
@@ -94,6 +97,23 @@ pub enum ActionType {
         method_name: String,
         hash: Base58CryptoHash,
     },
+
+    /// Transfers a held NFT to `receiver_id`, EX: prize payouts or vesting-style handovers
+    /// If `msg` is not None, calls `nft_transfer_call` with given `msg`, the token stays in holdings if returned.
+    NftTransfer {
+        nft_contract: AccountId,
+        token_id: String,
+        receiver_id: AccountId,
+        msg: Option<String>,
+    },
+
+    /// Withdraws matured unstake tranches from a pool, scheduled by `unstake` when croncat is not configured
+    /// If the pool is not unlocked yet (EX: epochs ran longer than estimated), it gets rescheduled
+    StakeWithdraw {
+        pool_account_id: AccountId,
+        /// Epoch the scheduled tranche unlocks at
+        unlock_epoch: u64,
+    },
 }
 
 impl ActionType {
@@ -107,6 +127,24 @@ impl ActionType {
             ActionType::FunctionCall { .. } => "function_call",
             ActionType::UpgradeSelf { .. } => "upgrade_self",
             ActionType::UpgradeRemote { .. } => "upgrade_remote",
+            ActionType::NftTransfer { .. } => "nft_transfer",
+            ActionType::StakeWithdraw { .. } => "stake_withdraw",
+        }
+    }
+
+    /// Gas reserved to execute this action, including the cross-contract calls it makes
+    pub fn required_gas(&self) -> Gas {
+        match self {
+            ActionType::Transfer {
+                token_id: Some(_),
+                msg: Some(_),
+                ..
+            } => GAS_TIMEOUT_ACTION + ft_impl::GAS_FT_TRANSFER_CALL,
+            ActionType::NftTransfer { msg: Some(_), .. } => {
+                GAS_TIMEOUT_ACTION + nft_impl::GAS_NFT_TRANSFER_CALL
+            }
+            ActionType::StakeWithdraw { .. } => GAS_TIMEOUT_ACTION + staking::GAS_STAKE_WITHDRAW,
+            _ => GAS_TIMEOUT_ACTION,
         }
     }
 }
@@ -117,6 +155,14 @@ pub enum ActionTime {
     Cadence,
 }
 
+/// Storage an account pays for a stored action, released once it gets executed or removed
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ActionStorage {
+    pub account_id: AccountId,
+    pub bytes: U64,
+}
+
 /// Function call arguments.
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
@@ -130,9 +176,32 @@ pub struct Action {
     cadence: Option<String>,
     /// The action payload holding specific data based on type
     payload: ActionType,
+    /// Who pays for storing this action, None when the treasury pays
+    #[serde(default, skip_deserializing)]
+    storage: Option<ActionStorage>,
 }
 
 impl Action {
+    /// Single trigger action, executed once the timeout (in nanos) has passed
+    pub(crate) fn new_timeout(timeout: u128, payload: ActionType) -> Self {
+        Action {
+            priority: 0,
+            timeout: Some(U128::from(timeout)),
+            cadence: None,
+            payload,
+            storage: None,
+        }
+    }
+
+    /// Whether two actions are the same, regardless of who pays for their storage
+    pub fn same_as(&self, other: &Action) -> bool {
+        let mut a = self.clone();
+        let mut b = other.clone();
+        a.storage = None;
+        b.storage = None;
+        a.try_to_vec().unwrap_or_default() == b.try_to_vec().unwrap_or_default()
+    }
+
     /// Returns label of policy for given type of proposal.
     pub fn get_time_type(&self) -> ActionTime {
         if self.timeout.is_some() {
@@ -143,6 +212,47 @@ impl Action {
         }
         ActionTime::Immediate
     }
+
+    /// Event payload for when this action is stored or immediately executed
+    pub fn to_create_event(&self) -> events::ActionCreate {
+        events::ActionCreate {
+            label: self.payload.to_label().to_string(),
+            priority: self.priority,
+            timeout: self.timeout,
+            cadence: self.cadence.clone(),
+        }
+    }
+
+    /// Event payload for when this action payload gets executed
+    pub fn to_execute_event(&self) -> events::ActionExecute {
+        events::ActionExecute {
+            label: self.payload.to_label().to_string(),
+            timeout: self.timeout,
+            cadence: self.cadence.clone(),
+        }
+    }
+
+    /// Event payload for when this action is removed without executing
+    pub fn to_remove_event(&self) -> events::ActionRemove {
+        events::ActionRemove {
+            label: self.payload.to_label().to_string(),
+            timeout: self.timeout,
+            cadence: self.cadence.clone(),
+        }
+    }
+}
+
+/// Actions stored before the upgrade are paid for by the treasury
+impl From<migrate::ActionV1> for Action {
+    fn from(old: migrate::ActionV1) -> Self {
+        Action {
+            priority: old.priority,
+            timeout: old.timeout,
+            cadence: old.cadence,
+            payload: old.payload,
+            storage: None,
+        }
+    }
 }
 
 #[near_bindgen]
@@ -202,60 +312,91 @@ impl Contract {
     }
 
     /// Accept a list of actions, parse for when and how they should get stored
+    /// NOTE: Accounts other than the owner pay for stored actions from their storage deposit, until executed or removed
     ///
     /// ```bash
     /// near call treasury.testnet create_actions '{"actions": [{ ...Action... }]}' --accountId treasury.testnet
     /// ```
     pub fn create_actions(&mut self, actions: Vec<Action>) {
+        let proposer = env::predecessor_account_id();
         for action in actions.iter() {
             // Make sure action is allowed
             if self.is_allowed_action(&action.payload) {
+                action.to_create_event().emit();
+                let mut action = action.clone();
+                action.storage = if proposer != self.owner_id {
+                    Some(ActionStorage {
+                        account_id: proposer.clone(),
+                        bytes: U64::from(0),
+                    })
+                } else {
+                    None
+                };
+
                 // Check if action is time based OR cadence based
                 match action.get_time_type() {
                     ActionTime::Timeout => {
                         assert!(action.timeout.is_some());
                         let timeout = action.timeout.unwrap_or(U128::from(0));
                         assert_ne!(timeout.0, 0);
-                        log!(
-                            "block {:?}, tssss: {:?}, ts: {:?}",
-                            u128::from(env::block_timestamp()),
-                            timeout,
-                            timeout.0
-                        );
                         assert!(u128::from(env::block_timestamp()) < timeout.0);
-
-                        // get the next timestamp, then check where to add to the duration tree
-                        let mut ts_actions = self
-                            .timeout_actions
-                            .get(&timeout.0)
-                            .unwrap_or(VecDeque::new());
-
-                        // place with priority, then write to storage
-                        if action.priority > 0 {
-                            ts_actions.push_front(action.clone());
-                        } else {
-                            ts_actions.push_back(action.clone());
-                        }
-                        self.timeout_actions.insert(&timeout.0, &ts_actions);
+                        self.internal_insert_timeout_action(action);
                     }
                     ActionTime::Cadence => {
-                        let cadence_key = action.cadence.clone().unwrap();
-                        self.cadence_actions.insert(&cadence_key, &action.clone());
+                        self.internal_insert_cadence_action(action);
                     }
                     ActionTime::Immediate => {
-                        self.call_action(action.clone());
+                        action.storage = None;
+                        self.call_action(action);
                     }
                 }
             }
         }
     }
 
-    // TODO:
+    /// Remove a stored action before it gets executed
+    /// NOTE: Timeout actions are matched on their full payload, cadence actions only by cadence
     ///
     /// ```bash
     /// near call treasury.testnet remove_actions '{"action": { ...Action... }}' --accountId treasury.testnet
     /// ```
-    // pub fn remove_actions(&mut self, action: Action) {}
+    pub fn remove_actions(&mut self, action: Action) {
+        self.assert_owner();
+        match action.get_time_type() {
+            ActionTime::Timeout => {
+                let timeout = action.timeout.unwrap();
+                let mut ts_actions = self
+                    .timeout_actions
+                    .get(&timeout.0)
+                    .expect("No actions found for timeout");
+                let index = ts_actions
+                    .iter()
+                    .position(|a| a.same_as(&action))
+                    .expect("Action not found");
+                let removed = ts_actions.remove(index).unwrap();
+
+                // Clean up the tree key if nothing is left to execute
+                if ts_actions.is_empty() {
+                    self.timeout_actions.remove(&timeout.0);
+                } else {
+                    self.timeout_actions.insert(&timeout.0, &ts_actions);
+                }
+                self.internal_release_action_storage(&removed);
+                removed.to_remove_event().emit();
+            }
+            ActionTime::Cadence => {
+                let removed = self
+                    .cadence_actions
+                    .remove(&action.cadence.unwrap())
+                    .expect("No action found for cadence");
+                self.internal_release_action_storage(&removed);
+                removed.to_remove_event().emit();
+            }
+            ActionTime::Immediate => {
+                env::panic_str("Immediate actions are never stored");
+            }
+        }
+    }
 
     // TODO:
     ///
@@ -266,24 +407,30 @@ impl Contract {
 
     /// View if there are any actions that need calling
     /// Used for periodically checking if some action needs to occur, and time has passed so it can
+    /// Returns:
+    /// (
+    /// has due actions,
+    /// due timeouts, in execution order,
+    /// total due actions still pending,
+    /// )
     ///
     /// ```bash
     /// near view treasury.testnet has_timeout_actions
     /// ```
-    pub fn has_timeout_actions(&self) -> (bool, Vec<U128>) {
-        if self.timeout_actions.len() == 0 {
-            return (false, Vec::new());
-        }
+    pub fn has_timeout_actions(&self) -> (bool, Vec<U128>, U64) {
         let block_ts = u128::from(env::block_timestamp());
-        let key = self.timeout_actions.floor_key(&block_ts);
-        let mut timeouts: Vec<U128> = self
-            .timeout_actions
-            .to_vec()
-            .iter()
-            .map(|(k, _)| U128::from(*k))
-            .collect();
-        timeouts.retain(|t| t.0 < block_ts);
-        (key.is_some(), timeouts)
+        let mut timeouts: Vec<U128> = Vec::new();
+        let mut pending: u64 = 0;
+
+        // Tree is ordered by timeout, so stop at the first one in the future
+        for (timeout, actions) in self.timeout_actions.iter() {
+            if timeout > block_ts {
+                break;
+            }
+            timeouts.push(U128::from(timeout));
+            pending += actions.len() as u64;
+        }
+        (!timeouts.is_empty(), timeouts, U64::from(pending))
     }
 
     // TODO: Validate if this can be "trusted" to be called as expected, otherwise deprecate.
@@ -302,44 +449,59 @@ impl Contract {
     }
 
     /// Called by croncat trigger
+    /// Executes due actions in timeout & priority order, for as long as there is gas left to do so.
+    /// Anything not reached stays queued for the next call.
     ///
     /// ```bash
-    /// near call treasury.testnet call_timeout_actions --accountId manager_v1.croncat.testnet
+    /// near call treasury.testnet call_timeout_actions --accountId manager_v1.croncat.testnet --gas 300000000000000
     /// ```
     pub fn call_timeout_actions(&mut self) {
-        let (has, keys) = self.has_timeout_actions();
-        assert_eq!(has, true, "No actions to execute");
-        let mut actions_total = 0;
-        let max_chunks = 10;
-
-        // Attempt to process a total of 10 actions, packing from one or more queues
-        for key in keys.iter() {
-            if actions_total > max_chunks {
+        let block_ts = u128::from(env::block_timestamp());
+        let mut actions_total: u64 = 0;
+
+        while let Some(key) = self.timeout_actions.min() {
+            if key > block_ts {
                 break;
             }
 
-            // Get a subset of the queue based on the key
-            if let Some(tmp_queue) = self.timeout_actions.get(&key.0) {
-                let mut subset = tmp_queue;
-                let queue = subset.split_off(max_chunks - actions_total);
-
-                // update storage removing the subset we will process
-                self.timeout_actions.insert(&key.0, &queue);
+            // Leave enough room for this action & the remaining execution
+            let mut queue = self.timeout_actions.get(&key).unwrap_or_default();
+            let required_gas = queue
+                .front()
+                .map(|action| action.payload.required_gas().0)
+                .unwrap_or(0);
+            let gas_left = env::prepaid_gas().0.saturating_sub(env::used_gas().0);
+            if gas_left < required_gas {
+                break;
+            }
 
-                // iterate the subset to process all actions
-                for action in subset.iter() {
-                    self.call_action(action.clone());
-                }
+            // Pop the next action, update storage before executing so the queue is never replayed
+            let action = queue.pop_front();
+            if queue.is_empty() {
+                self.timeout_actions.remove(&key);
+            } else {
+                self.timeout_actions.insert(&key, &queue);
             }
 
-            actions_total += 1;
+            if let Some(action) = action {
+                self.internal_release_action_storage(&action);
+                self.call_action(action);
+                actions_total += 1;
+            }
         }
+
+        assert_ne!(
+            actions_total, 0,
+            "No actions to execute, or not enough gas attached"
+        );
     }
 
     // TODO: Finish impls
     /// Execute and action based on its payload type
     // NOTE: Could be great to get these setup as batched TXNs
     fn call_action(&mut self, action: Action) -> PromiseOrValue<()> {
+        action.to_execute_event().emit();
+
         // match the right type to its function
         match action.payload {
             ActionType::Transfer {
@@ -347,9 +509,14 @@ impl Contract {
                 receiver_id,
                 amount,
                 msg,
-            } => {
-                self.action_transfer(&token_id, &receiver_id, amount, msg);
-            }
+            } => match (token_id, msg) {
+                (Some(token_id), Some(msg)) => {
+                    self.action_transfer_call(token_id, receiver_id, amount, msg);
+                }
+                (token_id, msg) => {
+                    self.action_transfer(&token_id, &receiver_id, amount, msg);
+                }
+            },
             ActionType::Budget {
                 token_id,
                 receiver_id,
@@ -359,6 +526,20 @@ impl Contract {
             } => {
                 self.action_budget(token_id, receiver_id, amount, amount_percentile, msg);
             }
+            ActionType::NftTransfer {
+                nft_contract,
+                token_id,
+                receiver_id,
+                msg,
+            } => {
+                self.action_nft_transfer(nft_contract, token_id, receiver_id, msg);
+            }
+            ActionType::StakeWithdraw {
+                pool_account_id,
+                unlock_epoch,
+            } => {
+                self.action_stake_withdraw(pool_account_id, unlock_epoch);
+            }
             // TBD:
             ActionType::Swap { .. } => return PromiseOrValue::Value(()),
             ActionType::Harvest { .. } => return PromiseOrValue::Value(()),
@@ -381,18 +562,94 @@ impl Contract {
         amount: U128,
         msg: Option<String>,
     ) -> PromiseOrValue<()> {
-        if token_id.is_none() {
-            Promise::new(receiver_id.clone()).transfer(amount.0).into()
-        } else {
-            ext_fungible_token::ft_transfer(
-                receiver_id.clone(),
+        if let Some(token_id) = token_id {
+            // Skip instead of panic, so other actions in the same execution still go through
+            if !self.has_ft_balance(token_id, amount.0) {
+                events::FtTransferFailure {
+                    token_id: token_id.clone(),
+                    receiver_id: receiver_id.clone(),
+                    amount,
+                }
+                .emit();
+                return PromiseOrValue::Value(());
+            }
+            return self
+                .internal_ft_transfer(token_id.clone(), receiver_id.clone(), amount, msg, None)
+                .into();
+        }
+
+        events::FtTransfer {
+            token_id: None,
+            receiver_id: receiver_id.clone(),
+            amount,
+            memo: msg,
+        }
+        .emit();
+        Promise::new(receiver_id.clone()).transfer(amount.0).into()
+    }
+
+    /// FT transfer into a contract using `ft_transfer_call`, any unused amount gets credited back
+    fn action_transfer_call(
+        &mut self,
+        token_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<()> {
+        // Skip instead of panic, so other actions in the same execution still go through
+        if !self.has_ft_balance(&token_id, amount.0) {
+            events::FtTransferFailure {
+                token_id,
+                receiver_id,
                 amount,
-                msg,
-                token_id.as_ref().unwrap().clone(),
-                ONE_YOCTO,
-                GAS_FOR_FT_TRANSFER,
-            )
+            }
+            .emit();
+            return PromiseOrValue::Value(());
+        }
+        self.internal_ft_transfer(token_id, receiver_id, amount, None, Some(msg))
             .into()
+    }
+
+    /// NFT transfer of a held token, optionally using `nft_transfer_call`
+    fn action_nft_transfer(
+        &mut self,
+        nft_contract: AccountId,
+        token_id: String,
+        receiver_id: AccountId,
+        msg: Option<String>,
+    ) -> PromiseOrValue<()> {
+        // Skip instead of panic, so other actions in the same execution still go through
+        let held = self
+            .nft_holdings
+            .get(&nft_contract)
+            .map_or(false, |tokens| tokens.contains(&token_id));
+        if !held {
+            events::NftTransferFailure {
+                nft_contract_id: nft_contract,
+                token_id,
+                receiver_id,
+            }
+            .emit();
+            return PromiseOrValue::Value(());
+        }
+        self.internal_nft_transfer(nft_contract, token_id, receiver_id, msg)
+            .into()
+    }
+
+    /// Withdraw matured unstakes, or try again later if the pool is still locked
+    fn action_stake_withdraw(&mut self, pool_account_id: AccountId, unlock_epoch: u64) {
+        // Skip instead of panic, the tranche may have been withdrawn already
+        let scheduled = self
+            .stake_pending_delegations
+            .get(&pool_account_id)
+            .map_or(false, |tranches| {
+                tranches.iter().any(|t| t.unlock_epoch <= unlock_epoch)
+            });
+        if !scheduled {
+            return;
+        }
+        if self.internal_withdraw(pool_account_id.clone()).is_none() {
+            self.internal_schedule_withdraw(pool_account_id, unlock_epoch);
         }
     }
 
@@ -419,3 +676,268 @@ impl Contract {
         self.action_transfer(&token_id, &receiver_id, final_amount, msg);
     }
 }
+
+impl Contract {
+    /// Queues a timeout action, priority actions go in front of others with the same timeout
+    /// NOTE: Charges the bytes it added to whoever pays for the action storage, if anyone
+    pub(crate) fn internal_insert_timeout_action(&mut self, mut action: Action) {
+        let timeout = action.timeout.expect("Action has no timeout").0;
+        let initial_storage_usage = env::storage_usage();
+        let mut ts_actions = self
+            .timeout_actions
+            .get(&timeout)
+            .unwrap_or(VecDeque::new());
+
+        // place with priority, then write to storage
+        let priority = action.priority > 0;
+        if priority {
+            ts_actions.push_front(action.clone());
+        } else {
+            ts_actions.push_back(action.clone());
+        }
+        self.timeout_actions.insert(&timeout, &ts_actions);
+
+        // Keep the bytes charged on the action, which does not change its size
+        if self.internal_charge_action_storage(&mut action, initial_storage_usage) {
+            if priority {
+                ts_actions[0] = action;
+            } else {
+                let last = ts_actions.len() - 1;
+                ts_actions[last] = action;
+            }
+            self.timeout_actions.insert(&timeout, &ts_actions);
+        }
+    }
+
+    /// Stores a cadence action, replacing (& releasing) any previous action with the same cadence
+    fn internal_insert_cadence_action(&mut self, mut action: Action) {
+        let cadence_key = action.cadence.clone().expect("Action has no cadence");
+        if let Some(replaced) = self.cadence_actions.remove(&cadence_key) {
+            self.internal_release_action_storage(&replaced);
+            replaced.to_remove_event().emit();
+        }
+
+        let initial_storage_usage = env::storage_usage();
+        self.cadence_actions.insert(&cadence_key, &action);
+        if self.internal_charge_action_storage(&mut action, initial_storage_usage) {
+            self.cadence_actions.insert(&cadence_key, &action);
+        }
+    }
+
+    /// Charges the bytes added since `initial_storage_usage` to whoever pays for the action, returns whether anyone did
+    fn internal_charge_action_storage(
+        &mut self,
+        action: &mut Action,
+        initial_storage_usage: StorageUsage,
+    ) -> bool {
+        let storage = match action.storage.as_mut() {
+            Some(storage) => storage,
+            None => return false,
+        };
+        let bytes = env::storage_usage().saturating_sub(initial_storage_usage);
+        storage.bytes = U64::from(bytes);
+        let account_id = storage.account_id.clone();
+        self.internal_storage_charge(&account_id, bytes);
+        true
+    }
+
+    /// Gives back the bytes an action was charged for, once it is executed or removed
+    fn internal_release_action_storage(&mut self, action: &Action) {
+        if let Some(storage) = &action.storage {
+            self.internal_storage_release(&storage.account_id, storage.bytes.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    fn transfer(token_id: Option<AccountId>, receiver_id: AccountId) -> ActionType {
+        ActionType::Transfer {
+            token_id,
+            receiver_id,
+            amount: U128::from(ONE_NEAR),
+            msg: None,
+        }
+    }
+
+    fn set_time(timestamp: u64, prepaid_gas: Gas) {
+        testing_env!(get_context(accounts(1))
+            .block_timestamp(timestamp)
+            .prepaid_gas(prepaid_gas)
+            .build());
+    }
+
+    #[test]
+    fn test_call_timeout_actions_in_order() {
+        let mut contract = setup_contract();
+        let due = u128::from(BLOCK_START_TS) + 10;
+        contract.internal_insert_timeout_action(Action::new_timeout(
+            due + 10,
+            transfer(None, accounts(2)),
+        ));
+        contract
+            .internal_insert_timeout_action(Action::new_timeout(due, transfer(None, accounts(3))));
+        contract.internal_insert_timeout_action(Action {
+            priority: 1,
+            ..Action::new_timeout(due, transfer(None, accounts(4)))
+        });
+        contract.internal_insert_timeout_action(Action::new_timeout(
+            due + 1_000,
+            transfer(None, accounts(5)),
+        ));
+
+        set_time(BLOCK_START_TS + 100, Gas(300_000_000_000_000));
+        assert_eq!(
+            contract.has_timeout_actions(),
+            (
+                true,
+                vec![U128::from(due), U128::from(due + 10)],
+                U64::from(3)
+            )
+        );
+        contract.call_timeout_actions();
+
+        // Earliest timeout first, priority actions first within a timeout
+        let receivers: Vec<serde_json::Value> = get_events("ft_transfer")
+            .iter()
+            .map(|event| event["receiver_id"].clone())
+            .collect();
+        assert_eq!(receivers, vec!["eugene", "danny", "charlie"]);
+
+        // Drained timeouts are removed, future ones stay queued
+        assert_eq!(contract.timeout_actions.len(), 1);
+        assert_eq!(
+            contract.has_timeout_actions(),
+            (false, vec![], U64::from(0))
+        );
+    }
+
+    #[test]
+    fn test_call_timeout_actions_gas_bound() {
+        let mut contract = setup_contract();
+        let token_id = accounts(5);
+        contract.ft_balances.insert(&token_id, &(10 * ONE_NEAR));
+        let due = u128::from(BLOCK_START_TS) + 10;
+        for receiver_id in [accounts(2), accounts(3), accounts(4)] {
+            contract.internal_insert_timeout_action(Action::new_timeout(
+                due,
+                transfer(Some(token_id.clone()), receiver_id),
+            ));
+        }
+
+        // Each token transfer attaches the gas of its storage check & transfer, so the third one does not fit
+        set_time(BLOCK_START_TS + 100, Gas(200_000_000_000_000));
+        contract.call_timeout_actions();
+        assert_eq!(get_events("ft_transfer").len(), 2);
+        assert_eq!(
+            contract.has_timeout_actions(),
+            (true, vec![U128::from(due)], U64::from(1))
+        );
+        assert_eq!(contract.ft_balances.get(&token_id), Some(8 * ONE_NEAR));
+
+        // The next call picks up where the last one stopped
+        set_time(BLOCK_START_TS + 200, Gas(150_000_000_000_000));
+        contract.call_timeout_actions();
+        assert_eq!(get_events("ft_transfer").len(), 1);
+        assert!(contract.timeout_actions.is_empty());
+    }
+
+    #[test]
+    fn test_call_timeout_actions_transfer_call() {
+        let mut contract = setup_contract();
+        let token_id = accounts(5);
+        contract.ft_balances.insert(&token_id, &ONE_NEAR);
+        let payload = ActionType::Transfer {
+            token_id: Some(token_id.clone()),
+            receiver_id: accounts(2),
+            amount: U128::from(2 * ONE_NEAR),
+            msg: Some("deposit".to_string()),
+        };
+        assert_eq!(
+            payload.required_gas(),
+            GAS_TIMEOUT_ACTION + ft_impl::GAS_FT_TRANSFER_CALL
+        );
+        assert_eq!(
+            transfer(None, accounts(2)).required_gas(),
+            GAS_TIMEOUT_ACTION
+        );
+        contract.internal_insert_timeout_action(Action::new_timeout(
+            u128::from(BLOCK_START_TS) + 10,
+            payload,
+        ));
+
+        // Short on balance, so the action is skipped instead of failing the whole execution
+        set_time(BLOCK_START_TS + 100, Gas(300_000_000_000_000));
+        contract.call_timeout_actions();
+        assert!(get_events("ft_transfer").is_empty());
+        assert_eq!(
+            get_events("ft_transfer_failure")[0]["amount"],
+            "2000000000000000000000000"
+        );
+        assert_eq!(contract.ft_balances.get(&token_id), Some(ONE_NEAR));
+        assert!(contract.timeout_actions.is_empty());
+    }
+
+    #[test]
+    fn test_call_timeout_actions_nft_transfer() {
+        let mut contract = setup_contract();
+        let nft_contract = accounts(5);
+        contract
+            .nft_holdings
+            .insert(&nft_contract, &vec!["1".to_string()]);
+        let nft_transfer = |token_id: &str, msg: Option<String>| ActionType::NftTransfer {
+            nft_contract: nft_contract.clone(),
+            token_id: token_id.to_string(),
+            receiver_id: accounts(2),
+            msg,
+        };
+        assert_eq!(
+            nft_transfer("1", Some("stake".to_string())).required_gas(),
+            GAS_TIMEOUT_ACTION + nft_impl::GAS_NFT_TRANSFER_CALL
+        );
+        assert_eq!(nft_transfer("1", None).required_gas(), GAS_TIMEOUT_ACTION);
+        let due = u128::from(BLOCK_START_TS) + 10;
+        contract.internal_insert_timeout_action(Action::new_timeout(due, nft_transfer("1", None)));
+        contract.internal_insert_timeout_action(Action::new_timeout(due, nft_transfer("2", None)));
+
+        // Tokens no longer held are skipped, the rest move to pending until confirmed
+        set_time(BLOCK_START_TS + 100, Gas(300_000_000_000_000));
+        contract.call_timeout_actions();
+        assert_eq!(get_events("nft_transfer")[0]["token_id"], "1");
+        assert_eq!(get_events("nft_transfer_failure")[0]["token_id"], "2");
+        assert_eq!(contract.nft_holdings.get(&nft_contract), Some(vec![]));
+        assert_eq!(
+            contract.get_nft_pending(nft_contract),
+            vec!["1".to_string()]
+        );
+        assert!(contract.timeout_actions.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "No actions to execute, or not enough gas attached")]
+    fn test_call_timeout_actions_not_enough_gas() {
+        let mut contract = setup_contract();
+        contract.internal_insert_timeout_action(Action::new_timeout(
+            u128::from(BLOCK_START_TS) + 10,
+            transfer(None, accounts(2)),
+        ));
+        set_time(BLOCK_START_TS + 100, Gas(GAS_TIMEOUT_ACTION.0 - 1));
+        contract.call_timeout_actions();
+    }
+
+    #[test]
+    #[should_panic(expected = "No actions to execute, or not enough gas attached")]
+    fn test_call_timeout_actions_none_due() {
+        let mut contract = setup_contract();
+        contract.internal_insert_timeout_action(Action::new_timeout(
+            u128::from(BLOCK_START_TS) + 1_000,
+            transfer(None, accounts(2)),
+        ));
+        contract.call_timeout_actions();
+    }
+}
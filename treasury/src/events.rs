@@ -0,0 +1,504 @@
+use crate::*;
+
+/// NEP-297 standard name & version for all treasury events
+/// REF: https://nomicon.io/Standards/EventsFormat
+pub const EVENT_STANDARD: &str = "croncat_treasury";
+pub const EVENT_VERSION: &str = "1.0.0";
+
+/// Envelope for an event, serialized into the `EVENT_JSON:` log prefix
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct EventLog<'a, T: Serialize> {
+    standard: &'a str,
+    version: &'a str,
+    event: &'a str,
+    data: [&'a T; 1],
+}
+
+/// Writes the NEP-297 formatted log line for a single event payload
+fn emit_event<T: Serialize>(event: &str, data: &T) {
+    let log = EventLog {
+        standard: EVENT_STANDARD,
+        version: EVENT_VERSION,
+        event,
+        data: [data],
+    };
+    env::log_str(&format!(
+        "EVENT_JSON:{}",
+        serde_json::to_string(&log).expect("Could not serialize event")
+    ));
+}
+
+/// Owner updated one or more core settings
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SettingsUpdate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner_id: Option<AccountId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub croncat_id: Option<AccountId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stake_threshold: Option<StakeThreshold>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stake_refresh_period: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stake_epoch_length: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_liquid_unstake_fee_bps: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ft_balances_refresh_period: Option<U64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ft_storage_budget: Option<U128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wrap_id: Option<AccountId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wrap_near_float: Option<U128>,
+}
+
+impl SettingsUpdate {
+    pub fn emit(&self) {
+        emit_event("settings_update", self);
+    }
+}
+
+/// Payable account was added or removed
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PayableAccountUpdate {
+    pub account_id: AccountId,
+    pub approved: bool,
+}
+
+impl PayableAccountUpdate {
+    pub fn emit(&self) {
+        emit_event("payable_account_update", self);
+    }
+}
+
+/// Action was stored for future execution, or executed immediately
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ActionCreate {
+    pub label: String,
+    pub priority: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<U128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cadence: Option<String>,
+}
+
+impl ActionCreate {
+    pub fn emit(&self) {
+        emit_event("action_create", self);
+    }
+}
+
+/// Action payload was executed
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ActionExecute {
+    pub label: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<U128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cadence: Option<String>,
+}
+
+impl ActionExecute {
+    pub fn emit(&self) {
+        emit_event("action_execute", self);
+    }
+}
+
+/// Action was removed before it executed
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ActionRemove {
+    pub label: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<U128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cadence: Option<String>,
+}
+
+impl ActionRemove {
+    pub fn emit(&self) {
+        emit_event("action_remove", self);
+    }
+}
+
+/// Outgoing fungible token or native NEAR transfer
+/// NOTE: `token_id` of None means $NEAR
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FtTransfer {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<AccountId>,
+    pub receiver_id: AccountId,
+    pub amount: U128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>,
+}
+
+impl FtTransfer {
+    pub fn emit(&self) {
+        emit_event("ft_transfer", self);
+    }
+}
+
+/// Outgoing fungible token transfer that did not go through, balance was restored
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FtTransferFailure {
+    pub token_id: AccountId,
+    pub receiver_id: AccountId,
+    pub amount: U128,
+}
+
+impl FtTransferFailure {
+    pub fn emit(&self) {
+        emit_event("ft_transfer_failure", self);
+    }
+}
+
+/// Portion of an outgoing `ft_transfer_call` that was refunded to the treasury
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FtRefund {
+    pub token_id: AccountId,
+    pub receiver_id: AccountId,
+    pub amount: U128,
+}
+
+impl FtRefund {
+    pub fn emit(&self) {
+        emit_event("ft_refund", self);
+    }
+}
+
+/// Storage deposit paid to register a recipient with a fungible token
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FtStorageDeposit {
+    pub token_id: AccountId,
+    pub account_id: AccountId,
+    pub amount: U128,
+}
+
+impl FtStorageDeposit {
+    pub fn emit(&self) {
+        emit_event("ft_storage_deposit", self);
+    }
+}
+
+/// Stored fungible token balance did not match the token contract
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FtBalanceDrift {
+    pub token_id: AccountId,
+    pub stored: U128,
+    pub actual: U128,
+}
+
+impl FtBalanceDrift {
+    pub fn emit(&self) {
+        emit_event("ft_balance_drift", self);
+    }
+}
+
+/// Incoming fungible token transfer, tracked via `ft_on_transfer`
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FtDeposit {
+    pub token_id: AccountId,
+    pub sender_id: AccountId,
+    pub amount: U128,
+    /// True when the token was not accepted and the full amount was returned
+    pub rejected: bool,
+}
+
+impl FtDeposit {
+    pub fn emit(&self) {
+        emit_event("ft_deposit", self);
+    }
+}
+
+/// NEAR was wrapped into wNEAR, or unwrapped back
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WrapNear {
+    pub amount: U128,
+    pub wrapped: bool,
+}
+
+impl WrapNear {
+    pub fn emit(&self) {
+        emit_event("wrap_near", self);
+    }
+}
+
+/// Incoming non-fungible token transfer, tracked via `nft_on_transfer`
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NftDeposit {
+    pub nft_contract_id: AccountId,
+    pub token_id: String,
+    pub sender_id: AccountId,
+    /// True when the contract was not accepted and the token was returned
+    pub rejected: bool,
+}
+
+impl NftDeposit {
+    pub fn emit(&self) {
+        emit_event("nft_deposit", self);
+    }
+}
+
+/// Outgoing non-fungible token transfer
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NftTransfer {
+    pub nft_contract_id: AccountId,
+    pub token_id: String,
+    pub receiver_id: AccountId,
+}
+
+impl NftTransfer {
+    pub fn emit(&self) {
+        emit_event("nft_transfer", self);
+    }
+}
+
+/// Outgoing non-fungible token transfer that did not go through, token was restored to holdings
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NftTransferFailure {
+    pub nft_contract_id: AccountId,
+    pub token_id: String,
+    pub receiver_id: AccountId,
+}
+
+impl NftTransferFailure {
+    pub fn emit(&self) {
+        emit_event("nft_transfer_failure", self);
+    }
+}
+
+/// Approval for a held non-fungible token was granted or revoked
+/// NOTE: `account_id` of None means all approvals for the token were revoked
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NftApprovalUpdate {
+    pub nft_contract_id: AccountId,
+    pub token_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<AccountId>,
+    pub approved: bool,
+}
+
+impl NftApprovalUpdate {
+    pub fn emit(&self) {
+        emit_event("nft_approval_update", self);
+    }
+}
+
+/// Per-pool plan carried out by a single `auto_stake` call
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakePlan {
+    pub steps: Vec<staking::StakePlanStep>,
+}
+
+impl StakePlan {
+    pub fn emit(&self) {
+        emit_event("stake_plan", self);
+    }
+}
+
+/// Staking step against a single pool
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakeUpdate {
+    pub pool_account_id: AccountId,
+    /// Will be None when the amount is decided by the pool, EX: unstake_all
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount: Option<U128>,
+}
+
+impl StakeUpdate {
+    pub fn emit_stake(&self) {
+        emit_event("stake", self);
+    }
+
+    pub fn emit_unstake(&self) {
+        emit_event("unstake", self);
+    }
+
+    pub fn emit_withdraw(&self) {
+        emit_event("withdraw", self);
+    }
+
+    pub fn emit_liquid_unstake(&self) {
+        emit_event("liquid_unstake", self);
+    }
+
+    pub fn emit_harvest(&self) {
+        emit_event("harvest", self);
+    }
+}
+
+/// Harvest pipeline finished, or stopped at a failed stage
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HarvestComplete {
+    /// Index in the harvest history
+    pub index: U64,
+    pub record: harvest::HarvestRecord,
+}
+
+impl HarvestComplete {
+    pub fn emit(&self) {
+        emit_event("harvest_complete", self);
+    }
+}
+
+/// Withdraw received a different amount than the matured tranches held
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakeWithdrawReconcile {
+    pub pool_account_id: AccountId,
+    pub expected: U128,
+    pub received: U128,
+    /// Shortfall put back into the unstake queue
+    pub restored: U128,
+}
+
+impl StakeWithdrawReconcile {
+    pub fn emit(&self) {
+        emit_event("withdraw_reconcile", self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::test_utils::{accounts, get_logs};
+    use near_sdk::testing_env;
+
+    #[test]
+    fn test_event_format() {
+        setup_contract();
+        PayableAccountUpdate {
+            account_id: accounts(2),
+            approved: true,
+        }
+        .emit();
+        assert_eq!(
+            get_logs(),
+            vec![
+                r#"EVENT_JSON:{"standard":"croncat_treasury","version":"1.0.0","event":"payable_account_update","data":[{"account_id":"charlie","approved":true}]}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_settings_update_event() {
+        let mut contract = setup_contract();
+        contract.update_settings(
+            None,
+            Some(accounts(3)),
+            None,
+            Some(U64::from(60)),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        );
+        // Only the settings that changed are included
+        assert_eq!(
+            get_events("settings_update"),
+            vec![serde_json::json!({
+                "croncat_id": "danny",
+                "stake_refresh_period": "60",
+            })]
+        );
+    }
+
+    #[test]
+    fn test_payable_account_events() {
+        let mut contract = setup_contract();
+        testing_env!(get_context(accounts(1)).attached_deposit(1).build());
+        contract.add_payable_account(accounts(2));
+        contract.remove_payable_account(accounts(2));
+        assert_eq!(
+            get_events("payable_account_update"),
+            vec![
+                serde_json::json!({"account_id": "charlie", "approved": true}),
+                serde_json::json!({"account_id": "charlie", "approved": false}),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_action_events() {
+        let mut contract = setup_contract();
+        let payload = serde_json::json!({
+            "Transfer": {
+                "token_id": "",
+                "receiver_id": "charlie",
+                "amount": "1",
+                "msg": null,
+            },
+        });
+        let action: Action = serde_json::from_value(serde_json::json!({
+            "priority": 0,
+            "timeout": (BLOCK_START_TS + 1_000).to_string(),
+            "cadence": null,
+            "payload": payload,
+        }))
+        .unwrap();
+
+        // Actions that are not allowed are skipped silently
+        contract.create_actions(vec![action.clone()]);
+        assert!(get_events("action_create").is_empty());
+
+        contract.add_allowed_actions(vec![serde_json::from_value(payload).unwrap()]);
+        contract.create_actions(vec![action.clone()]);
+        contract.remove_actions(action);
+        let expected = serde_json::json!({
+            "label": "transfer",
+            "timeout": (BLOCK_START_TS + 1_000).to_string(),
+        });
+        assert_eq!(
+            get_events("action_create"),
+            vec![serde_json::json!({
+                "label": "transfer",
+                "priority": 0,
+                "timeout": (BLOCK_START_TS + 1_000).to_string(),
+            })]
+        );
+        assert_eq!(get_events("action_remove"), vec![expected]);
+    }
+
+    #[test]
+    fn test_stake_events() {
+        let mut contract = setup_contract();
+        contract.add_staking_pool(accounts(2), None, None, None, None, None);
+        let mut delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        delegation.balance = 10 * ONE_NEAR;
+        contract.stake_delegations.insert(&accounts(2), &delegation);
+
+        contract.unstake(accounts(2), Some(U128::from(ONE_NEAR)));
+        contract.unstake(accounts(2), None);
+        assert_eq!(
+            get_events("unstake"),
+            vec![
+                serde_json::json!({"pool_account_id": "charlie", "amount": ONE_NEAR.to_string()}),
+                serde_json::json!({"pool_account_id": "charlie"}),
+            ]
+        );
+    }
+}
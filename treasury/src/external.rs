@@ -9,7 +9,6 @@ pub struct PoolBalance {
     pub can_withdraw: bool,
 }
 
-// TODO: Remove debug
 /// REF: https://github.com/Narwallets/meta-pool/blob/master/metapool/src/types.rs#L117
 #[derive(Deserialize, Debug)]
 #[serde(crate = "near_sdk::serde")]
@@ -35,6 +34,17 @@ pub struct MetaPoolBalance {
     pub nslp_share_bp: u16,
 }
 
+/// REF: https://github.com/linear-protocol/LiNEAR/blob/main/contracts/linear/src/view.rs
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct LinearAccountDetails {
+    pub account_id: AccountId,
+    pub unstaked_balance: U128,
+    pub staked_balance: U128, // stake_shares * LiNEAR price
+    pub stake_shares: U128,
+    pub can_withdraw: bool,
+}
+
 /// CRONCAT
 #[derive(BorshDeserialize, BorshSerialize, Debug, Serialize, Deserialize, PartialEq)]
 #[serde(crate = "near_sdk::serde")]
@@ -70,6 +80,7 @@ pub trait Croncat {
         arguments: Option<Base64VecU8>,
     ) -> Base64VecU8;
     fn remove_task(&mut self, task_hash: Base64VecU8);
+    fn refill_balance(&mut self, task_hash: Base64VecU8);
     fn proxy_call(&mut self);
     fn get_info(
         &mut self,
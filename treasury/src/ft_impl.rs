@@ -3,16 +3,77 @@ use crate::*;
 pub const GAS_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
 pub const GAS_FT_BALANCE_OF: Gas = Gas(20_000_000_000_000);
 pub const GAS_FT_BALANCE_OF_CALLBACK: Gas = Gas(10_000_000_000_000);
+pub const GAS_FT_COMPUTE_BALANCE_OF: Gas = Gas(5_000_000_000_000);
+pub const GAS_FT_TRANSFER_CALLBACK: Gas = Gas(10_000_000_000_000);
+pub const GAS_FT_METADATA: Gas = Gas(5_000_000_000_000);
+pub const GAS_FT_METADATA_CALLBACK: Gas = Gas(10_000_000_000_000);
+pub const GAS_FT_STORAGE_VIEW: Gas = Gas(5_000_000_000_000);
+pub const GAS_FT_STORAGE_DEPOSIT: Gas = Gas(10_000_000_000_000);
+pub const GAS_FT_STORAGE_CHECK_CALLBACK: Gas = Gas(45_000_000_000_000);
+pub const GAS_FT_STORAGE_DEPOSIT_CALLBACK: Gas = Gas(30_000_000_000_000);
+pub const GAS_FT_TRANSFER_CALL: Gas = Gas(50_000_000_000_000);
+pub const GAS_FT_TRANSFER_CALL_CALLBACK: Gas = Gas(10_000_000_000_000);
+/// Fallback for tokens that do not report storage bounds, 0.00125 NEAR
+pub const FT_STORAGE_DEPOSIT_DEFAULT: Balance = 1_250_000_000_000_000_000_000;
+/// Default time between balance refreshes, 12hrs in nanoseconds
+pub const FT_BALANCES_REFRESH_PERIOD: u64 = 12 * 60 * 60 * 1_000_000_000;
+/// Most tokens refreshed per `compute_ft_balances` call by the croncat trigger, ~15 Tgas each
+pub const FT_BALANCES_REFRESH_PAGE_LIMIT: u64 = 10;
+/// Most records kept in FT history, older ones get overwritten
+pub const FT_HISTORY_MAX_LEN: u64 = 1_000;
+/// Longest deposit `msg` kept in FT history, in bytes
+pub const FT_HISTORY_MSG_MAX_LEN: usize = 256;
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct FungibleTokenBalance {
     pub account_id: AccountId,
-    pub balance: Balance,
+    pub balance: U128,
+    /// Only known for registered tokens
+    pub symbol: Option<String>,
+    pub decimals: Option<u8>,
+    /// Decimals-aware balance, EX: "1.5" for 1500000000000000000000000 wNEAR
+    pub amount: Option<String>,
 }
 
-// TODO:
-// * storage deposit???
+/// Registered token, with cached NEP-148 metadata & per-token settings
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FungibleTokenRegistration {
+    pub symbol: String,
+    pub decimals: u8,
+    /// sha256 of the icon data, keeps the cache small while still allowing icon verification
+    pub icon_hash: Option<Base64VecU8>,
+    /// Outgoing transfers can never take the balance below this amount
+    pub min_reserve: U128,
+    /// Whether `compute_ft_balances` includes this token
+    pub auto_refresh: bool,
+    /// Whether `ft_on_transfer` accepts this token
+    pub accept_deposits: bool,
+}
+
+/// Kinds of fungible token movements kept in history
+#[derive(BorshDeserialize, BorshSerialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum FungibleTokenRecordKind {
+    Deposit,
+    Transfer,
+    /// Portion of an outgoing `ft_transfer_call` the receiver handed back
+    Refund,
+}
+
+/// History entry of a fungible token movement
+#[derive(BorshDeserialize, BorshSerialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FungibleTokenRecord {
+    pub kind: FungibleTokenRecordKind,
+    pub ft_account_id: AccountId,
+    /// The sender for deposits, receiver for transfers & refunds
+    pub account_id: AccountId,
+    pub amount: U128,
+    pub msg: Option<String>,
+    pub block_timestamp: U64,
+}
 
 #[near_bindgen]
 impl Contract {
@@ -51,9 +112,15 @@ impl Contract {
         for i in start..end {
             if let Some(account_id) = keys.get(i) {
                 if let Some(balance) = self.ft_balances.get(&account_id) {
+                    let registration = self.ft_registry.get(&account_id);
                     result.push(FungibleTokenBalance {
                         account_id,
-                        balance,
+                        balance: U128::from(balance),
+                        symbol: registration.as_ref().map(|r| r.symbol.clone()),
+                        decimals: registration.as_ref().map(|r| r.decimals),
+                        amount: registration
+                            .as_ref()
+                            .map(|r| utils::format_decimals(balance, r.decimals)),
                     });
                 }
             }
@@ -72,8 +139,190 @@ impl Contract {
         U128::from(self.ft_balances.get(&account_id).unwrap_or(0))
     }
 
+    /// Registered Fungible Tokens, with cached metadata & settings
+    ///
+    /// ```bash
+    /// near view treasury.testnet get_ft_registration '{"ft_account_id": "wrap.testnet"}'
+    /// ```
+    pub fn get_ft_registration(
+        &self,
+        ft_account_id: AccountId,
+    ) -> Option<FungibleTokenRegistration> {
+        self.ft_registry.get(&ft_account_id)
+    }
+
+    /// Register a token, fetching & caching its metadata
+    /// NOTE: Re-registering refreshes the metadata and replaces the settings
+    ///
+    /// ```bash
+    /// near call treasury.testnet register_ft '{"ft_account_id": "wrap.testnet", "min_reserve": "1000000000000000000000000", "auto_refresh": true, "accept_deposits": true}' --accountId treasury.testnet
+    /// ```
+    pub fn register_ft(
+        &mut self,
+        ft_account_id: AccountId,
+        min_reserve: Option<U128>,
+        auto_refresh: Option<bool>,
+        accept_deposits: Option<bool>,
+    ) {
+        self.assert_owner();
+        let p1 = env::promise_create(
+            ft_account_id.clone(),
+            "ft_metadata",
+            json!({}).to_string().as_bytes(),
+            NO_DEPOSIT,
+            GAS_FT_METADATA,
+        );
+
+        let p2 = env::promise_then(
+            p1,
+            env::current_account_id(),
+            "register_ft_callback",
+            json!({
+                "ft_account_id": ft_account_id,
+                "min_reserve": min_reserve.unwrap_or(U128::from(0)),
+                "auto_refresh": auto_refresh.unwrap_or(true),
+                "accept_deposits": accept_deposits.unwrap_or(true),
+            })
+            .to_string()
+            .as_bytes(),
+            NO_DEPOSIT,
+            GAS_FT_METADATA_CALLBACK,
+        );
+
+        env::promise_return(p2);
+    }
+
+    /// CALLBACK for register_ft
+    #[private]
+    pub fn register_ft_callback(
+        &mut self,
+        ft_account_id: AccountId,
+        min_reserve: U128,
+        auto_refresh: bool,
+        accept_deposits: bool,
+    ) {
+        assert!(is_promise_success(), "Could not get token metadata");
+        let metadata: FungibleTokenMetadata = match env::promise_result(0) {
+            PromiseResult::Successful(result) => {
+                serde_json::de::from_slice(&result).expect("Invalid token metadata")
+            }
+            _ => unreachable!(),
+        };
+
+        self.ft_registry.insert(
+            &ft_account_id,
+            &FungibleTokenRegistration {
+                symbol: metadata.symbol,
+                decimals: metadata.decimals,
+                icon_hash: metadata
+                    .icon
+                    .map(|icon| Base64VecU8::from(env::sha256(icon.as_bytes()))),
+                min_reserve,
+                auto_refresh,
+                accept_deposits,
+            },
+        );
+
+        // Make sure the token shows up in balances, even before anything was received
+        if self.ft_balances.get(&ft_account_id).is_none() {
+            self.ft_balances.insert(&ft_account_id, &0);
+        }
+    }
+
+    /// Update the settings of a registered token, without refreshing metadata
+    ///
+    /// ```bash
+    /// near call treasury.testnet update_ft_registration '{"ft_account_id": "wrap.testnet", "accept_deposits": false}' --accountId treasury.testnet
+    /// ```
+    pub fn update_ft_registration(
+        &mut self,
+        ft_account_id: AccountId,
+        min_reserve: Option<U128>,
+        auto_refresh: Option<bool>,
+        accept_deposits: Option<bool>,
+    ) {
+        self.assert_owner();
+        let mut registration = self
+            .ft_registry
+            .get(&ft_account_id)
+            .expect("Token not registered");
+
+        if let Some(min_reserve) = min_reserve {
+            registration.min_reserve = min_reserve;
+        }
+        if let Some(auto_refresh) = auto_refresh {
+            registration.auto_refresh = auto_refresh;
+        }
+        if let Some(accept_deposits) = accept_deposits {
+            registration.accept_deposits = accept_deposits;
+        }
+        self.ft_registry.insert(&ft_account_id, &registration);
+    }
+
+    /// Remove a token registration, balance tracking is unaffected
+    ///
+    /// ```bash
+    /// near call treasury.testnet unregister_ft '{"ft_account_id": "wrap.testnet"}' --accountId treasury.testnet
+    /// ```
+    pub fn unregister_ft(&mut self, ft_account_id: AccountId) {
+        self.assert_owner();
+        self.ft_registry.remove(&ft_account_id);
+    }
+
+    /// Fungible Token History, oldest first
+    /// NOTE: Only the latest 1000 records are kept
+    ///
+    /// ```bash
+    /// near view treasury.testnet get_ft_history '{"from_index": 0, "limit": 10}'
+    /// ```
+    pub fn get_ft_history(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<U64>,
+    ) -> Vec<FungibleTokenRecord> {
+        self.ft_history.page(
+            from_index.map(|i| i.0).unwrap_or(0),
+            limit.map(|l| l.0).unwrap_or(10),
+        )
+    }
+
+    /// Tokens accepted by `ft_on_transfer`
+    /// NOTE: Empty means any token is accepted
+    ///
+    /// ```bash
+    /// near view treasury.testnet get_ft_whitelist
+    /// ```
+    pub fn get_ft_whitelist(&self) -> Vec<AccountId> {
+        self.ft_whitelist.to_vec()
+    }
+
+    /// Manage accepted fungible tokens
+    /// NOTE: Not specifying any means any token can be deposited
+    ///
+    /// ```bash
+    /// near call treasury.testnet add_ft_whitelist '{"ft_account_id": "wrap.testnet"}' --accountId treasury.testnet --depositYocto 1
+    /// ```
+    #[payable]
+    pub fn add_ft_whitelist(&mut self, ft_account_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.ft_whitelist.insert(&ft_account_id);
+    }
+
+    /// Remove an accepted fungible token
+    ///
+    /// ```bash
+    /// near call treasury.testnet remove_ft_whitelist '{"ft_account_id": "wrap.testnet"}' --accountId treasury.testnet --depositYocto 1
+    /// ```
+    #[payable]
+    pub fn remove_ft_whitelist(&mut self, ft_account_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.ft_whitelist.remove(&ft_account_id);
+    }
+
     /// Transfer Fungible Token
-    /// NOTE: Assumes storage deposit has occurred for recipient
+    /// NOTE: If the recipient is not registered with the token, storage deposit is paid from the storage budget
     ///
     /// ```bash
     /// near call treasury.testnet ft_transfer '{"ft_account_id": "wrap.testnet", "to_account_id": "user.account.testnet", "to_amount": "100000000000000000000000000000000"}' --accountId treasury.testnet
@@ -83,7 +332,7 @@ impl Contract {
         ft_account_id: AccountId,
         to_amount: U128,
         to_account_id: AccountId,
-    ) {
+    ) -> Promise {
         assert_eq!(
             self.owner_id,
             env::predecessor_account_id(),
@@ -97,26 +346,228 @@ impl Contract {
             .expect("No token balance found");
         assert!(ft_balance >= to_amount.0, "Transfer amount too high");
 
-        // NOTE: Lame accounting here, should be changed to callback
-        let mut total = to_amount.0;
-        total = total.saturating_add(ft_balance);
-        self.ft_balances
-            .insert(&env::predecessor_account_id(), &total);
+        self.internal_ft_transfer(ft_account_id, to_account_id, to_amount, None, None)
+    }
 
-        let p = env::promise_create(
-            ft_account_id,
-            "ft_transfer",
-            json!({
-                "receiver_id": to_account_id,
-                "amount": to_amount,
-            })
-            .to_string()
-            .as_bytes(),
-            ONE_YOCTO,
-            GAS_FT_TRANSFER,
+    /// CALLBACK for any outgoing ft_transfer
+    /// Keeps the debit if the transfer went through, otherwise restores the balance
+    #[private]
+    pub fn ft_transfer_callback(
+        &mut self,
+        ft_account_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+    ) -> bool {
+        self.internal_ft_settle(&ft_account_id, amount.0);
+        if is_promise_success() {
+            self.ft_history.push(&FungibleTokenRecord {
+                kind: FungibleTokenRecordKind::Transfer,
+                ft_account_id,
+                account_id: receiver_id,
+                amount,
+                msg: memo,
+                block_timestamp: U64::from(env::block_timestamp()),
+            });
+            return true;
+        }
+
+        // Transfer failed, put the tokens back
+        self.internal_ft_restore(ft_account_id, receiver_id, amount);
+        false
+    }
+
+    /// CALLBACK for any outgoing ft_transfer_call
+    /// The token returns the amount the receiver actually used, anything else was refunded to the treasury
+    #[private]
+    pub fn ft_transfer_call_callback(
+        &mut self,
+        ft_account_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> U128 {
+        self.internal_ft_settle(&ft_account_id, amount.0);
+        let used = match env::promise_result(0) {
+            PromiseResult::Successful(result) => {
+                serde_json::de::from_slice::<U128>(&result).unwrap_or(amount)
+            }
+            _ => {
+                // Transfer failed, put the tokens back
+                self.internal_ft_restore(ft_account_id, receiver_id, amount);
+                return U128::from(0);
+            }
+        };
+        let used = U128::from(u128::min(used.0, amount.0));
+        let refund = amount.0 - used.0;
+        let block_timestamp = U64::from(env::block_timestamp());
+
+        if used.0 > 0 {
+            self.ft_history.push(&FungibleTokenRecord {
+                kind: FungibleTokenRecordKind::Transfer,
+                ft_account_id: ft_account_id.clone(),
+                account_id: receiver_id.clone(),
+                amount: used,
+                msg: Some(msg.clone()),
+                block_timestamp,
+            });
+        }
+        if refund > 0 {
+            let balance = self.ft_balances.get(&ft_account_id).unwrap_or(0);
+            self.ft_balances
+                .insert(&ft_account_id, &balance.saturating_add(refund));
+            self.ft_history.push(&FungibleTokenRecord {
+                kind: FungibleTokenRecordKind::Refund,
+                ft_account_id: ft_account_id.clone(),
+                account_id: receiver_id.clone(),
+                amount: U128::from(refund),
+                msg: Some(msg),
+                block_timestamp,
+            });
+            events::FtRefund {
+                token_id: ft_account_id,
+                receiver_id,
+                amount: U128::from(refund),
+            }
+            .emit();
+        }
+
+        used
+    }
+
+    /// CALLBACK for the recipient storage check of any outgoing ft_transfer or ft_transfer_call
+    /// Registers the recipient out of the storage budget if needed, then sends the transfer
+    #[private]
+    pub fn ft_storage_check_callback(
+        &mut self,
+        ft_account_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: Option<String>,
+    ) -> PromiseOrValue<bool> {
+        assert_eq!(
+            env::promise_results_count(),
+            2,
+            "Expected 2 promise results."
         );
 
-        env::promise_return(p);
+        // Tokens without storage management get treated as registered
+        let is_registered = match env::promise_result(0) {
+            PromiseResult::Successful(result) => {
+                serde_json::de::from_slice::<Option<StorageBalance>>(&result)
+                    .map(|storage| storage.is_some())
+                    .unwrap_or(true)
+            }
+            _ => true,
+        };
+        if is_registered {
+            return self
+                .internal_ft_transfer_promise(ft_account_id, receiver_id, amount, memo, msg)
+                .into();
+        }
+
+        let storage_deposit = match env::promise_result(1) {
+            PromiseResult::Successful(result) => {
+                serde_json::de::from_slice::<StorageBalanceBounds>(&result)
+                    .map(|bounds| bounds.min.0)
+                    .unwrap_or(FT_STORAGE_DEPOSIT_DEFAULT)
+            }
+            _ => FT_STORAGE_DEPOSIT_DEFAULT,
+        };
+        if storage_deposit > self.ft_storage_budget {
+            self.internal_ft_settle(&ft_account_id, amount.0);
+            self.internal_ft_restore(ft_account_id, receiver_id, amount);
+            return PromiseOrValue::Value(false);
+        }
+
+        // Pay for the recipient registration, keeping track of spend per token
+        self.ft_storage_budget -= storage_deposit;
+        let spent = self.ft_storage_spent.get(&ft_account_id).unwrap_or(0);
+        self.ft_storage_spent
+            .insert(&ft_account_id, &spent.saturating_add(storage_deposit));
+        events::FtStorageDeposit {
+            token_id: ft_account_id.clone(),
+            account_id: receiver_id.clone(),
+            amount: U128::from(storage_deposit),
+        }
+        .emit();
+
+        let gas = if msg.is_some() {
+            GAS_FT_STORAGE_DEPOSIT_CALLBACK + GAS_FT_TRANSFER_CALL
+        } else {
+            GAS_FT_STORAGE_DEPOSIT_CALLBACK
+        };
+        Promise::new(ft_account_id.clone())
+            .function_call(
+                "storage_deposit".to_string(),
+                json!({
+                    "account_id": receiver_id,
+                    "registration_only": true,
+                })
+                .to_string()
+                .into_bytes(),
+                storage_deposit,
+                GAS_FT_STORAGE_DEPOSIT,
+            )
+            .then(
+                Promise::new(env::current_account_id()).function_call(
+                    "ft_storage_deposit_callback".to_string(),
+                    json!({
+                        "ft_account_id": ft_account_id,
+                        "receiver_id": receiver_id,
+                        "amount": amount,
+                        "memo": memo,
+                        "msg": msg,
+                        "storage_deposit": U128::from(storage_deposit),
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NO_DEPOSIT,
+                    gas,
+                ),
+            )
+            .into()
+    }
+
+    /// CALLBACK for the recipient storage_deposit, sends the transfer once the recipient is registered
+    /// If the registration failed, the deposit was refunded so it goes back into the storage budget
+    /// NOTE: A registration that went through stays spent even if the transfer fails, the recipient keeps it
+    #[private]
+    pub fn ft_storage_deposit_callback(
+        &mut self,
+        ft_account_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: Option<String>,
+        storage_deposit: U128,
+    ) -> PromiseOrValue<bool> {
+        if is_promise_success() {
+            return self
+                .internal_ft_transfer_promise(ft_account_id, receiver_id, amount, memo, msg)
+                .into();
+        }
+
+        self.ft_storage_budget = self.ft_storage_budget.saturating_add(storage_deposit.0);
+        let spent = self
+            .ft_storage_spent
+            .get(&ft_account_id)
+            .unwrap_or(0)
+            .saturating_sub(storage_deposit.0);
+        self.ft_storage_spent.insert(&ft_account_id, &spent);
+        self.internal_ft_settle(&ft_account_id, amount.0);
+        self.internal_ft_restore(ft_account_id, receiver_id, amount);
+        PromiseOrValue::Value(false)
+    }
+
+    /// Storage deposits paid for recipients of a fungible token
+    ///
+    /// ```bash
+    /// near view treasury.testnet get_ft_storage_spent '{"ft_account_id": "wrap.testnet"}'
+    /// ```
+    pub fn get_ft_storage_spent(&self, ft_account_id: AccountId) -> U128 {
+        U128::from(self.ft_storage_spent.get(&ft_account_id).unwrap_or(0))
     }
 
     /// Get & Store Fungible Token Balance
@@ -166,17 +617,22 @@ impl Contract {
                 let amount: U128 = serde_json::de::from_slice(&result)
                     .expect("Could not get balance from fungible token");
 
-                // Update the token balance
-                self.ft_balances.insert(&ft_account_id, &amount.0);
+                // Update the token balance, unless a transfer is in flight as it is already debited
+                if self.ft_pending.get(&ft_account_id).is_none() {
+                    self.ft_balances.insert(&ft_account_id, &amount.0);
+                }
             }
             PromiseResult::Failed => {}
         }
     }
 
     /// Compute Fungible Token Balances for Supported FTs
+    /// NOTE: Registered tokens with auto refresh turned off are skipped
+    /// Fans out `ft_balance_of` for every token in the page, then stores all results in a single callback
+    /// NOTE: Each call moves the refresh on to the next page, the refresh is done once the last page is stored
     ///
     /// ```bash
-    /// near call treasury.testnet compute_ft_balances '{"from_index": 0, "limit": 10}'
+    /// near call treasury.testnet compute_ft_balances '{"from_index": "0", "limit": "10"}' --gas 300000000000000
     /// ```
     pub fn compute_ft_balances(&mut self, from_index: Option<U64>, limit: Option<U64>) {
         let mut start = 0;
@@ -186,16 +642,849 @@ impl Contract {
             start = from_index.0;
         }
         if let Some(limit) = limit {
-            end = u64::min(start + limit.0, self.ft_balances.len());
+            end = start + limit.0;
         }
+        end = u64::min(end, self.ft_balances.len());
 
-        // Return all tasks within range
+        // Get all tokens within range
         let keys = self.ft_balances.keys_as_vector();
-        for i in start..end {
-            if let Some(account_id) = keys.get(i) {
-                // TODO: Trigger promise to get FT balance
-                log!("get balance for {:?}", account_id);
+        let ft_account_ids: Vec<AccountId> = (start..end)
+            .filter_map(|i| keys.get(i))
+            .filter(|ft_account_id| self.is_ft_auto_refresh(ft_account_id))
+            .collect();
+
+        // Nothing to refresh in this page, move on to the next one
+        if ft_account_ids.is_empty() {
+            self.internal_ft_refresh_advance(end);
+            return;
+        }
+
+        let promises: Vec<_> = ft_account_ids
+            .iter()
+            .map(|ft_account_id| {
+                env::promise_create(
+                    ft_account_id.clone(),
+                    "ft_balance_of",
+                    json!({
+                        "account_id": env::current_account_id().to_string(),
+                    })
+                    .to_string()
+                    .as_bytes(),
+                    NO_DEPOSIT,
+                    GAS_FT_COMPUTE_BALANCE_OF,
+                )
+            })
+            .collect();
+
+        let p = env::promise_then(
+            env::promise_and(&promises),
+            env::current_account_id(),
+            "compute_ft_balances_callback",
+            json!({
+                "ft_account_ids": ft_account_ids,
+                "next_index": U64::from(end),
+            })
+            .to_string()
+            .as_bytes(),
+            NO_DEPOSIT,
+            Gas(GAS_FT_BALANCE_OF_CALLBACK.0 * ft_account_ids.len() as u64),
+        );
+
+        env::promise_return(p);
+    }
+
+    /// CALLBACK for compute_ft_balances
+    /// Results come back in the same order as `ft_account_ids`, failed lookups keep their stored balance
+    /// NOTE: Tokens with transfers in flight keep their stored balance, it is already debited but the token may not be yet
+    #[private]
+    pub fn compute_ft_balances_callback(
+        &mut self,
+        ft_account_ids: Vec<AccountId>,
+        next_index: U64,
+    ) {
+        assert_eq!(
+            env::promise_results_count(),
+            ft_account_ids.len() as u64,
+            "Expected 1 promise result per token."
+        );
+
+        for (i, ft_account_id) in ft_account_ids.iter().enumerate() {
+            if self.ft_pending.get(ft_account_id).is_some() {
+                continue;
+            }
+            if let PromiseResult::Successful(result) = env::promise_result(i as u64) {
+                if let Ok(amount) = serde_json::de::from_slice::<U128>(&result) {
+                    let stored = self.ft_balances.get(ft_account_id).unwrap_or(0);
+
+                    // Report anything that moved without the treasury knowing
+                    if stored != amount.0 {
+                        events::FtBalanceDrift {
+                            token_id: ft_account_id.clone(),
+                            stored: U128::from(stored),
+                            actual: amount,
+                        }
+                        .emit();
+                    }
+                    self.ft_balances.insert(ft_account_id, &amount.0);
+                }
             }
         }
+
+        self.internal_ft_refresh_advance(next_index.0);
+    }
+
+    /// Check if fungible token balances are due for a refresh, based on the refresh period
+    /// NOTE: Never due if every token has auto refresh turned off
+    /// Stays due until every page is refreshed, returns the `compute_ft_balances` arguments of the next page
+    ///
+    /// ```bash
+    /// near view treasury.testnet needs_ft_balances_refresh
+    /// ```
+    pub fn needs_ft_balances_refresh(&self) -> external::CroncatTriggerResponse {
+        let due = self
+            .ft_balances
+            .keys()
+            .any(|ft_account_id| self.is_ft_auto_refresh(&ft_account_id))
+            && (self.ft_balances_refresh_index > 0
+                || env::block_timestamp()
+                    >= self
+                        .ft_balances_refreshed_at
+                        .saturating_add(self.ft_balances_refresh_period));
+
+        (
+            due,
+            Base64VecU8::from(
+                json!({
+                    "from_index": U64::from(self.ft_balances_refresh_index),
+                    "limit": U64::from(FT_BALANCES_REFRESH_PAGE_LIMIT),
+                })
+                .to_string()
+                .into_bytes(),
+            ),
+        )
+    }
+}
+
+impl Contract {
+    /// Whether the tracked balance of a token covers an amount
+    /// NOTE: Respects the minimum reserve of registered tokens
+    pub(crate) fn has_ft_balance(&self, ft_account_id: &AccountId, amount: Balance) -> bool {
+        self.ft_balances.get(ft_account_id).unwrap_or(0)
+            >= amount.saturating_add(self.get_ft_min_reserve(ft_account_id))
+    }
+
+    /// Whether balance refreshes include a token, unregistered tokens are always included
+    pub(crate) fn is_ft_auto_refresh(&self, ft_account_id: &AccountId) -> bool {
+        self.ft_registry
+            .get(ft_account_id)
+            .map(|registration| registration.auto_refresh)
+            .unwrap_or(true)
+    }
+
+    /// Move the balance refresh on to the next page, or finish it after the last page
+    pub(crate) fn internal_ft_refresh_advance(&mut self, next_index: u64) {
+        if next_index >= self.ft_balances.len() {
+            self.ft_balances_refresh_index = 0;
+            self.ft_balances_refreshed_at = env::block_timestamp();
+        } else {
+            self.ft_balances_refresh_index = next_index;
+        }
+    }
+
+    /// Keep track of an outgoing amount that is debited, but not confirmed by the token yet
+    pub(crate) fn internal_ft_pending_add(&mut self, ft_account_id: &AccountId, amount: Balance) {
+        let pending = self.ft_pending.get(ft_account_id).unwrap_or(0);
+        self.ft_pending
+            .insert(ft_account_id, &pending.saturating_add(amount));
+    }
+
+    /// Outgoing amount is confirmed (or restored), the token balance can be refreshed again once none are left
+    pub(crate) fn internal_ft_settle(&mut self, ft_account_id: &AccountId, amount: Balance) {
+        let pending = self
+            .ft_pending
+            .get(ft_account_id)
+            .unwrap_or(0)
+            .saturating_sub(amount);
+        if pending > 0 {
+            self.ft_pending.insert(ft_account_id, &pending);
+        } else {
+            self.ft_pending.remove(ft_account_id);
+        }
+    }
+
+    /// Minimum reserve of a token, 0 when unregistered
+    pub(crate) fn get_ft_min_reserve(&self, ft_account_id: &AccountId) -> Balance {
+        self.ft_registry
+            .get(ft_account_id)
+            .map(|registration| registration.min_reserve.0)
+            .unwrap_or(0)
+    }
+
+    /// Debit the tracked balance then check the recipient is registered with the token before sending
+    /// If `msg` is specified, sends with `ft_transfer_call` instead
+    /// NOTE: Debiting up front keeps the same balance from being spent twice while the transfer is in flight
+    /// NOTE: The amount stays pending until the transfer callback, so balance refreshes do not undo the debit
+    pub(crate) fn internal_ft_transfer(
+        &mut self,
+        ft_account_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: Option<String>,
+    ) -> Promise {
+        let balance = self.ft_balances.get(&ft_account_id).unwrap_or(0);
+        assert!(balance >= amount.0, "Transfer amount too high");
+        assert!(
+            balance - amount.0 >= self.get_ft_min_reserve(&ft_account_id),
+            "Transfer amount exceeds token reserve"
+        );
+        self.ft_balances
+            .insert(&ft_account_id, &(balance - amount.0));
+        self.internal_ft_pending_add(&ft_account_id, amount.0);
+
+        events::FtTransfer {
+            token_id: Some(ft_account_id.clone()),
+            receiver_id: receiver_id.clone(),
+            amount,
+            memo: memo.clone(),
+        }
+        .emit();
+
+        Promise::new(ft_account_id.clone())
+            .function_call(
+                "storage_balance_of".to_string(),
+                json!({
+                    "account_id": receiver_id,
+                })
+                .to_string()
+                .into_bytes(),
+                NO_DEPOSIT,
+                GAS_FT_STORAGE_VIEW,
+            )
+            .and(Promise::new(ft_account_id.clone()).function_call(
+                "storage_balance_bounds".to_string(),
+                json!({}).to_string().into_bytes(),
+                NO_DEPOSIT,
+                GAS_FT_STORAGE_VIEW,
+            ))
+            .then(
+                Promise::new(env::current_account_id()).function_call(
+                    "ft_storage_check_callback".to_string(),
+                    json!({
+                        "ft_account_id": ft_account_id,
+                        "receiver_id": receiver_id,
+                        "amount": amount,
+                        "memo": memo,
+                        "msg": msg,
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NO_DEPOSIT,
+                    if msg.is_some() {
+                        GAS_FT_STORAGE_CHECK_CALLBACK + GAS_FT_TRANSFER_CALL
+                    } else {
+                        GAS_FT_STORAGE_CHECK_CALLBACK
+                    },
+                ),
+            )
+    }
+
+    /// Chain the ft_transfer (or ft_transfer_call) & its confirmation
+    pub(crate) fn internal_ft_transfer_promise(
+        &self,
+        ft_account_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: Option<String>,
+    ) -> Promise {
+        let (transfer, callback) = match msg {
+            Some(msg) => (
+                ext_fungible_token::ft_transfer_call(
+                    receiver_id.clone(),
+                    amount,
+                    memo,
+                    msg.clone(),
+                    ft_account_id.clone(),
+                    ONE_YOCTO,
+                    GAS_FT_TRANSFER_CALL,
+                ),
+                Promise::new(env::current_account_id()).function_call(
+                    "ft_transfer_call_callback".to_string(),
+                    json!({
+                        "ft_account_id": ft_account_id,
+                        "receiver_id": receiver_id,
+                        "amount": amount,
+                        "msg": msg,
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NO_DEPOSIT,
+                    GAS_FT_TRANSFER_CALL_CALLBACK,
+                ),
+            ),
+            None => (
+                ext_fungible_token::ft_transfer(
+                    receiver_id.clone(),
+                    amount,
+                    memo.clone(),
+                    ft_account_id.clone(),
+                    ONE_YOCTO,
+                    GAS_FT_TRANSFER,
+                ),
+                Promise::new(env::current_account_id()).function_call(
+                    "ft_transfer_callback".to_string(),
+                    json!({
+                        "ft_account_id": ft_account_id,
+                        "receiver_id": receiver_id,
+                        "amount": amount,
+                        "memo": memo,
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NO_DEPOSIT,
+                    GAS_FT_TRANSFER_CALLBACK,
+                ),
+            ),
+        };
+
+        transfer.then(callback)
+    }
+
+    /// Put back tokens that were debited for a transfer that did not go through
+    pub(crate) fn internal_ft_restore(
+        &mut self,
+        ft_account_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) {
+        let balance = self.ft_balances.get(&ft_account_id).unwrap_or(0);
+        self.ft_balances
+            .insert(&ft_account_id, &balance.saturating_add(amount.0));
+        events::FtTransferFailure {
+            token_id: ft_account_id,
+            receiver_id,
+            amount,
+        }
+        .emit();
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    /// Automatically track incoming fungible tokens, called by the token contract upon `ft_transfer_call`
+    /// NOTE: If the whitelist is empty, any token is accepted. If it has any tokens, all other tokens get returned in full
+    /// NOTE: Registered tokens that do not accept deposits also get returned in full
+    /// NOTE: Returned deposits are still logged, with `rejected` set so indexers do not count them
+    ///
+    /// ```bash
+    /// near call wrap.testnet ft_transfer_call '{"receiver_id": "treasury.testnet", "amount": "1000000000000000000000000", "msg": "grant payment"}' --accountId user.testnet --depositYocto 1 --gas 100000000000000
+    /// ```
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let ft_account_id = env::predecessor_account_id();
+        let whitelisted =
+            self.ft_whitelist.is_empty() || self.ft_whitelist.contains(&ft_account_id);
+        let accepted = self
+            .ft_registry
+            .get(&ft_account_id)
+            .map(|registration| registration.accept_deposits)
+            .unwrap_or(true);
+        let rejected = !whitelisted || !accepted;
+
+        events::FtDeposit {
+            token_id: ft_account_id.clone(),
+            sender_id: sender_id.clone(),
+            amount,
+            rejected,
+        }
+        .emit();
+        if rejected {
+            return PromiseOrValue::Value(amount);
+        }
+
+        // Credit the token balance, then keep record of who sent it
+        let balance = self.ft_balances.get(&ft_account_id).unwrap_or(0);
+        self.ft_balances
+            .insert(&ft_account_id, &balance.saturating_add(amount.0));
+        self.ft_history.push(&FungibleTokenRecord {
+            kind: FungibleTokenRecordKind::Deposit,
+            ft_account_id,
+            account_id: sender_id,
+            amount,
+            msg: if msg.is_empty() {
+                None
+            } else {
+                Some(utils::truncate_string(msg, FT_HISTORY_MSG_MAX_LEN))
+            },
+            block_timestamp: U64::from(env::block_timestamp()),
+        });
+
+        PromiseOrValue::Value(U128::from(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    fn register(contract: &mut Contract, ft_account_id: &AccountId, accept_deposits: bool) {
+        contract.ft_registry.insert(
+            ft_account_id,
+            &FungibleTokenRegistration {
+                symbol: "TKN".to_string(),
+                decimals: 24,
+                icon_hash: None,
+                min_reserve: U128::from(0),
+                auto_refresh: true,
+                accept_deposits,
+            },
+        );
+    }
+
+    fn deposit(contract: &mut Contract, ft_account_id: AccountId, amount: u128, msg: &str) -> u128 {
+        testing_env!(get_context(ft_account_id).build());
+        match contract.ft_on_transfer(accounts(2), U128::from(amount), msg.to_string()) {
+            PromiseOrValue::Value(refund) => refund.0,
+            PromiseOrValue::Promise(_) => panic!("Expected a value"),
+        }
+    }
+
+    fn transfer(contract: &mut Contract, ft_account_id: AccountId, amount: u128) {
+        testing_env!(get_context(accounts(1)).build());
+        contract.ft_transfer(ft_account_id, U128::from(amount), accounts(2));
+    }
+
+    #[test]
+    fn test_ft_transfer_confirmed() {
+        let mut contract = setup_contract();
+        contract.ft_balances.insert(&accounts(3), &1_000);
+        transfer(&mut contract, accounts(3), 400);
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(600));
+        assert_eq!(contract.ft_pending.get(&accounts(3)), Some(400));
+        assert_eq!(get_events("ft_transfer")[0]["amount"], "400");
+
+        set_callback_context(
+            &mut get_context(accounts(0)),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        assert!(contract.ft_transfer_callback(accounts(3), accounts(2), U128::from(400), None));
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(600));
+        assert_eq!(contract.ft_pending.get(&accounts(3)), None);
+        let history = contract.get_ft_history(None, None);
+        assert!(matches!(history[0].kind, FungibleTokenRecordKind::Transfer));
+        assert_eq!(history[0].account_id, accounts(2));
+        assert_eq!(history[0].amount, U128::from(400));
+    }
+
+    #[test]
+    fn test_ft_transfer_failed() {
+        let mut contract = setup_contract();
+        contract.ft_balances.insert(&accounts(3), &1_000);
+        transfer(&mut contract, accounts(3), 400);
+
+        set_callback_context(&mut get_context(accounts(0)), vec![PromiseResult::Failed]);
+        assert!(!contract.ft_transfer_callback(accounts(3), accounts(2), U128::from(400), None));
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(1_000));
+        assert_eq!(contract.ft_pending.get(&accounts(3)), None);
+        assert!(contract.get_ft_history(None, None).is_empty());
+        let events = get_events("ft_transfer_failure");
+        assert_eq!(events[0]["token_id"], accounts(3).to_string());
+        assert_eq!(events[0]["amount"], "400");
+    }
+
+    #[test]
+    #[should_panic(expected = "Transfer amount too high")]
+    fn test_ft_transfer_too_high() {
+        let mut contract = setup_contract();
+        contract.ft_balances.insert(&accounts(3), &1_000);
+        transfer(&mut contract, accounts(3), 1_001);
+    }
+
+    #[test]
+    #[should_panic(expected = "Must be owner")]
+    fn test_ft_transfer_not_owner() {
+        let mut contract = setup_contract();
+        contract.ft_balances.insert(&accounts(3), &1_000);
+        testing_env!(get_context(accounts(2)).build());
+        contract.ft_transfer(accounts(3), U128::from(400), accounts(2));
+    }
+
+    fn storage_check(contract: &mut Contract, registered: bool, min: u128) -> PromiseOrValue<bool> {
+        let storage_balance = if registered {
+            Some(StorageBalance {
+                total: U128::from(min),
+                available: U128::from(0),
+            })
+        } else {
+            None
+        };
+        set_callback_context(
+            &mut get_context(accounts(0)),
+            vec![
+                json_result(&storage_balance),
+                json_result(&StorageBalanceBounds {
+                    min: U128::from(min),
+                    max: None,
+                }),
+            ],
+        );
+        contract.ft_storage_check_callback(accounts(3), accounts(2), U128::from(400), None, None)
+    }
+
+    #[test]
+    fn test_ft_storage_check_registered() {
+        let mut contract = setup_contract();
+        contract.ft_balances.insert(&accounts(3), &1_000);
+        contract.ft_storage_budget = 5_000;
+        transfer(&mut contract, accounts(3), 400);
+
+        assert!(matches!(
+            storage_check(&mut contract, true, 2_000),
+            PromiseOrValue::Promise(_)
+        ));
+        assert_eq!(contract.ft_storage_budget, 5_000);
+        assert_eq!(contract.get_ft_storage_spent(accounts(3)), U128::from(0));
+        assert!(get_events("ft_storage_deposit").is_empty());
+    }
+
+    #[test]
+    fn test_ft_storage_check_pays_registration() {
+        let mut contract = setup_contract();
+        contract.ft_balances.insert(&accounts(3), &1_000);
+        contract.ft_storage_budget = 5_000;
+        transfer(&mut contract, accounts(3), 400);
+
+        assert!(matches!(
+            storage_check(&mut contract, false, 2_000),
+            PromiseOrValue::Promise(_)
+        ));
+        assert_eq!(contract.ft_storage_budget, 3_000);
+        assert_eq!(
+            contract.get_ft_storage_spent(accounts(3)),
+            U128::from(2_000)
+        );
+        let events = get_events("ft_storage_deposit");
+        assert_eq!(events[0]["account_id"], accounts(2).to_string());
+        assert_eq!(events[0]["amount"], "2000");
+
+        // Registration failed, so the deposit was refunded & the transfer is cancelled
+        set_callback_context(&mut get_context(accounts(0)), vec![PromiseResult::Failed]);
+        assert!(matches!(
+            contract.ft_storage_deposit_callback(
+                accounts(3),
+                accounts(2),
+                U128::from(400),
+                None,
+                None,
+                U128::from(2_000)
+            ),
+            PromiseOrValue::Value(false)
+        ));
+        assert_eq!(contract.ft_storage_budget, 5_000);
+        assert_eq!(contract.get_ft_storage_spent(accounts(3)), U128::from(0));
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(1_000));
+        assert_eq!(contract.ft_pending.get(&accounts(3)), None);
+    }
+
+    #[test]
+    fn test_ft_storage_check_over_budget() {
+        let mut contract = setup_contract();
+        contract.ft_balances.insert(&accounts(3), &1_000);
+        contract.ft_storage_budget = 1_000;
+        transfer(&mut contract, accounts(3), 400);
+
+        assert!(matches!(
+            storage_check(&mut contract, false, 2_000),
+            PromiseOrValue::Value(false)
+        ));
+        assert_eq!(contract.ft_storage_budget, 1_000);
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(1_000));
+        assert_eq!(contract.ft_pending.get(&accounts(3)), None);
+        assert_eq!(get_events("ft_transfer_failure").len(), 1);
+    }
+
+    #[test]
+    fn test_compute_ft_balances_callback() {
+        let mut contract = setup_contract();
+        contract.ft_balances.insert(&accounts(2), &100);
+        contract.ft_balances.insert(&accounts(3), &100);
+        contract.ft_balances.insert(&accounts(4), &100);
+        contract.ft_balances.insert(&accounts(5), &100);
+        contract.ft_pending.insert(&accounts(4), &50);
+
+        set_callback_context(
+            &mut get_context(accounts(0)),
+            vec![
+                json_result(&U128::from(100)),
+                json_result(&U128::from(150)),
+                json_result(&U128::from(0)),
+                PromiseResult::Failed,
+            ],
+        );
+        contract.compute_ft_balances_callback(
+            vec![accounts(2), accounts(3), accounts(4), accounts(5)],
+            U64::from(2),
+        );
+
+        // Only the drifted balance is reported, pending & failed lookups keep the stored balance
+        assert_eq!(contract.ft_balances.get(&accounts(2)), Some(100));
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(150));
+        assert_eq!(contract.ft_balances.get(&accounts(4)), Some(100));
+        assert_eq!(contract.ft_balances.get(&accounts(5)), Some(100));
+        let events = get_events("ft_balance_drift");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["token_id"], accounts(3).to_string());
+        assert_eq!(events[0]["stored"], "100");
+        assert_eq!(events[0]["actual"], "150");
+        assert_eq!(contract.ft_balances_refresh_index, 2);
+        assert_eq!(contract.ft_balances_refreshed_at, 0);
+    }
+
+    #[test]
+    fn test_compute_ft_balances_skips_page() {
+        let mut contract = setup_contract();
+        contract.ft_balances.insert(&accounts(2), &100);
+        contract.ft_balances.insert(&accounts(3), &100);
+        register(&mut contract, &accounts(2), true);
+        contract.update_ft_registration(accounts(2), None, Some(false), None);
+
+        // Page without any auto refreshed token moves straight on to the next one
+        contract.compute_ft_balances(Some(U64::from(0)), Some(U64::from(1)));
+        assert_eq!(contract.ft_balances_refresh_index, 1);
+        contract.compute_ft_balances(Some(U64::from(1)), Some(U64::from(1)));
+        assert_eq!(contract.ft_balances_refresh_index, 1);
+    }
+
+    #[test]
+    fn test_needs_ft_balances_refresh() {
+        let mut contract = setup_contract();
+        testing_env!(get_context(accounts(1)).build());
+        // Nothing to refresh without any tokens
+        assert!(!contract.needs_ft_balances_refresh().0);
+
+        contract.ft_balances.insert(&accounts(2), &100);
+        contract.ft_balances.insert(&accounts(3), &100);
+        let (due, args) = contract.needs_ft_balances_refresh();
+        assert!(due);
+        let args: serde_json::Value = serde_json::from_slice(&args.0).unwrap();
+        assert_eq!(args["from_index"], "0");
+
+        // Stays due until the last page is refreshed
+        contract.internal_ft_refresh_advance(1);
+        let (due, args) = contract.needs_ft_balances_refresh();
+        assert!(due);
+        let args: serde_json::Value = serde_json::from_slice(&args.0).unwrap();
+        assert_eq!(args["from_index"], "1");
+        contract.internal_ft_refresh_advance(2);
+        assert_eq!(contract.ft_balances_refresh_index, 0);
+        assert_eq!(contract.ft_balances_refreshed_at, BLOCK_START_TS);
+        assert!(!contract.needs_ft_balances_refresh().0);
+
+        testing_env!(get_context(accounts(1))
+            .block_timestamp(BLOCK_START_TS + FT_BALANCES_REFRESH_PERIOD)
+            .build());
+        assert!(contract.needs_ft_balances_refresh().0);
+    }
+
+    fn transfer_call(contract: &mut Contract, result: PromiseResult) -> U128 {
+        contract.ft_balances.insert(&accounts(3), &1_000);
+        testing_env!(get_context(accounts(1)).build());
+        contract.internal_ft_transfer(
+            accounts(3),
+            accounts(2),
+            U128::from(400),
+            None,
+            Some("deposit".to_string()),
+        );
+        set_callback_context(&mut get_context(accounts(0)), vec![result]);
+        contract.ft_transfer_call_callback(
+            accounts(3),
+            accounts(2),
+            U128::from(400),
+            "deposit".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_ft_transfer_call_refund() {
+        let mut contract = setup_contract();
+        let used = transfer_call(&mut contract, json_result(&U128::from(150)));
+        assert_eq!(used, U128::from(150));
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(850));
+        assert_eq!(contract.ft_pending.get(&accounts(3)), None);
+
+        let history = contract.get_ft_history(None, None);
+        assert_eq!(history.len(), 2);
+        assert!(matches!(history[0].kind, FungibleTokenRecordKind::Transfer));
+        assert_eq!(history[0].amount, U128::from(150));
+        assert!(matches!(history[1].kind, FungibleTokenRecordKind::Refund));
+        assert_eq!(history[1].amount, U128::from(250));
+        assert_eq!(history[1].msg, Some("deposit".to_string()));
+        assert_eq!(get_events("ft_refund")[0]["amount"], "250");
+    }
+
+    #[test]
+    fn test_ft_transfer_call_used() {
+        let mut contract = setup_contract();
+        // Never counts more than was sent as used
+        let used = transfer_call(&mut contract, json_result(&U128::from(1_000)));
+        assert_eq!(used, U128::from(400));
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(600));
+        assert_eq!(contract.get_ft_history(None, None).len(), 1);
+        assert!(get_events("ft_refund").is_empty());
+    }
+
+    #[test]
+    fn test_ft_transfer_call_failed() {
+        let mut contract = setup_contract();
+        let used = transfer_call(&mut contract, PromiseResult::Failed);
+        assert_eq!(used, U128::from(0));
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(1_000));
+        assert_eq!(contract.ft_pending.get(&accounts(3)), None);
+        assert!(contract.get_ft_history(None, None).is_empty());
+        assert_eq!(get_events("ft_transfer_failure").len(), 1);
+    }
+
+    fn metadata() -> serde_json::Value {
+        json!({
+            "spec": "ft-1.0.0",
+            "name": "Wrapped NEAR",
+            "symbol": "wNEAR",
+            "icon": "data:image/svg+xml,<svg></svg>",
+            "reference": null,
+            "reference_hash": null,
+            "decimals": 24,
+        })
+    }
+
+    #[test]
+    fn test_register_ft() {
+        let mut contract = setup_contract();
+        set_callback_context(
+            &mut get_context(accounts(0)),
+            vec![json_result(&metadata())],
+        );
+        contract.register_ft_callback(accounts(3), U128::from(ONE_NEAR), false, true);
+
+        let registration = contract.get_ft_registration(accounts(3)).unwrap();
+        assert_eq!(registration.symbol, "wNEAR");
+        assert_eq!(registration.decimals, 24);
+        assert_eq!(
+            registration.icon_hash,
+            Some(Base64VecU8::from(env::sha256(
+                "data:image/svg+xml,<svg></svg>".as_bytes()
+            )))
+        );
+        assert_eq!(registration.min_reserve, U128::from(ONE_NEAR));
+        assert!(!registration.auto_refresh);
+        assert!(registration.accept_deposits);
+        // Shows up in balances right away, with a readable amount once there is any
+        assert_eq!(contract.get_ft_list(), vec![accounts(3)]);
+        contract
+            .ft_balances
+            .insert(&accounts(3), &(ONE_NEAR * 25 / 10));
+        let balances = contract.ft_balances(None, None);
+        assert_eq!(balances[0].symbol, Some("wNEAR".to_string()));
+        assert_eq!(balances[0].amount, Some("2.5".to_string()));
+
+        testing_env!(get_context(accounts(1)).build());
+        contract.update_ft_registration(accounts(3), None, Some(true), Some(false));
+        let registration = contract.get_ft_registration(accounts(3)).unwrap();
+        assert_eq!(registration.min_reserve, U128::from(ONE_NEAR));
+        assert!(registration.auto_refresh);
+        assert!(!registration.accept_deposits);
+
+        contract.unregister_ft(accounts(3));
+        assert!(contract.get_ft_registration(accounts(3)).is_none());
+        assert_eq!(
+            contract.ft_balance_of(accounts(3)),
+            U128::from(ONE_NEAR * 25 / 10)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Could not get token metadata")]
+    fn test_register_ft_failed() {
+        let mut contract = setup_contract();
+        set_callback_context(&mut get_context(accounts(0)), vec![PromiseResult::Failed]);
+        contract.register_ft_callback(accounts(3), U128::from(0), true, true);
+    }
+
+    #[test]
+    #[should_panic(expected = "Transfer amount exceeds token reserve")]
+    fn test_ft_transfer_respects_reserve() {
+        let mut contract = setup_contract();
+        register(&mut contract, &accounts(3), true);
+        contract.update_ft_registration(accounts(3), Some(U128::from(700)), None, None);
+        contract.ft_balances.insert(&accounts(3), &1_000);
+        transfer(&mut contract, accounts(3), 400);
+    }
+
+    #[test]
+    fn test_ft_on_transfer_accepts_any_token() {
+        let mut contract = setup_contract();
+        assert_eq!(deposit(&mut contract, accounts(3), 100, "grant payment"), 0);
+        assert_eq!(deposit(&mut contract, accounts(3), 50, ""), 0);
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(150));
+
+        let history = contract.get_ft_history(None, None);
+        assert_eq!(history.len(), 2);
+        assert!(matches!(history[0].kind, FungibleTokenRecordKind::Deposit));
+        assert_eq!(history[0].account_id, accounts(2));
+        assert_eq!(history[0].msg, Some("grant payment".to_string()));
+        assert_eq!(history[1].msg, None);
+
+        let events = get_events("ft_deposit");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["amount"], "50");
+        assert_eq!(events[0]["rejected"], false);
+    }
+
+    #[test]
+    fn test_ft_on_transfer_whitelist() {
+        let mut contract = setup_contract();
+        contract.ft_whitelist.insert(&accounts(3));
+
+        assert_eq!(deposit(&mut contract, accounts(4), 100, ""), 100);
+        assert_eq!(contract.ft_balances.get(&accounts(4)), None);
+        assert_eq!(get_events("ft_deposit")[0]["rejected"], true);
+
+        assert_eq!(deposit(&mut contract, accounts(3), 100, ""), 0);
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(100));
+        assert_eq!(contract.get_ft_history(None, None).len(), 1);
+    }
+
+    #[test]
+    fn test_ft_on_transfer_registration() {
+        let mut contract = setup_contract();
+        register(&mut contract, &accounts(3), false);
+        register(&mut contract, &accounts(4), true);
+
+        assert_eq!(deposit(&mut contract, accounts(3), 100, ""), 100);
+        assert_eq!(contract.ft_balances.get(&accounts(3)), None);
+        assert_eq!(get_events("ft_deposit")[0]["rejected"], true);
+
+        assert_eq!(deposit(&mut contract, accounts(4), 100, ""), 0);
+        assert_eq!(contract.ft_balances.get(&accounts(4)), Some(100));
+        assert_eq!(contract.get_ft_history(None, None).len(), 1);
+    }
+
+    #[test]
+    fn test_ft_on_transfer_truncates_msg() {
+        let mut contract = setup_contract();
+        deposit(&mut contract, accounts(3), 100, &"a".repeat(300));
+        let history = contract.get_ft_history(None, None);
+        assert_eq!(
+            history[0].msg.as_ref().map(|msg| msg.len()),
+            Some(FT_HISTORY_MSG_MAX_LEN)
+        );
     }
 }
@@ -0,0 +1,874 @@
+use crate::*;
+
+/// Gas for the pool yield function, when run as the first pipeline stage
+pub const GAS_HARVEST_YIELD: Gas = Gas(50_000_000_000_000);
+/// Gas for a single stage callback to run, not including the calls it makes
+pub const GAS_HARVEST_STAGE: Gas = Gas(5_000_000_000_000);
+/// Gas for the token balance views of the pipeline
+pub const GAS_HARVEST_BALANCE_OF: Gas = Gas(10_000_000_000_000);
+
+// Each stage callback carries the gas for every stage after it
+pub const GAS_HARVEST_RESTAKE_CALLBACK: Gas = GAS_HARVEST_STAGE;
+pub const GAS_HARVEST_UNWRAP_CALLBACK: Gas = Gas(GAS_HARVEST_STAGE.0
+    + staking::GAS_STAKE_DEPOSIT_AND_STAKE.0
+    + staking::GAS_STAKE_DEPOSIT_AND_STAKE_CALLBACK.0
+    + GAS_HARVEST_RESTAKE_CALLBACK.0);
+pub const GAS_HARVEST_SWAP_REFRESH_CALLBACK: Gas =
+    Gas(GAS_HARVEST_STAGE.0 + wrap::GAS_WRAP_NEAR_WITHDRAW.0 + GAS_HARVEST_UNWRAP_CALLBACK.0);
+pub const GAS_HARVEST_SWAP_CALLBACK: Gas =
+    Gas(GAS_HARVEST_STAGE.0 + GAS_HARVEST_BALANCE_OF.0 + GAS_HARVEST_SWAP_REFRESH_CALLBACK.0);
+pub const GAS_HARVEST_SWAP_BALANCE_CALLBACK: Gas = Gas(GAS_HARVEST_STAGE.0
+    + ft_impl::GAS_FT_TRANSFER_CALL.0
+    + ft_impl::GAS_FT_TRANSFER_CALL_CALLBACK.0
+    + GAS_HARVEST_SWAP_CALLBACK.0);
+pub const GAS_HARVEST_REFRESH_CALLBACK: Gas =
+    Gas(GAS_HARVEST_STAGE.0 + GAS_HARVEST_BALANCE_OF.0 + GAS_HARVEST_SWAP_BALANCE_CALLBACK.0);
+pub const GAS_HARVEST_CALLBACK: Gas =
+    Gas(GAS_HARVEST_STAGE.0 + GAS_HARVEST_BALANCE_OF.0 + GAS_HARVEST_REFRESH_CALLBACK.0);
+pub const GAS_HARVEST_START_CALLBACK: Gas =
+    Gas(GAS_HARVEST_STAGE.0 + GAS_HARVEST_YIELD.0 + GAS_HARVEST_CALLBACK.0);
+/// Gas for the whole pipeline, ~280 Tgas
+/// NOTE: Reads the harvested token before harvesting, and the token swapped into right before swapping
+pub const GAS_HARVEST_PIPELINE: Gas =
+    Gas(GAS_BASE_FEE.0 + GAS_HARVEST_BALANCE_OF.0 + GAS_HARVEST_START_CALLBACK.0);
+
+/// Harvest runs kept in the history, oldest get dropped first
+pub const HARVEST_HISTORY_MAX_LEN: u64 = 1_000;
+/// Harvest runs paid for up front by `schedule_harvest`, if not specified
+pub const HARVEST_SCHEDULE_RUNS: u64 = 30;
+
+/// Swap rates are per 10^24 units of the harvested token
+pub const HARVEST_RATE_DENOMINATOR: u128 = 1_000_000_000_000_000_000_000_000;
+
+/// DEX swap for harvested tokens, using a REF-style instant swap with `ft_transfer_call`
+/// REF: https://github.com/ref-finance/ref-contracts/blob/main/ref-exchange/src/token_receiver.rs
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HarvestRoute {
+    /// EX: v2.ref-finance.near
+    pub dex_id: AccountId,
+    pub pool_id: u64,
+    /// Unwrapped & restaked if this is the wrap token, otherwise kept in the treasury
+    pub token_out: AccountId,
+    /// Minimum token_out received per 10^24 harvested units, protects the swap from slippage
+    pub min_rate: U128,
+}
+
+/// Stage a harvest got to, each stage has its own callback
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum HarvestStage {
+    Harvest,
+    Refresh,
+    Swap,
+    Unwrap,
+    Restake,
+    Done,
+}
+
+/// Single run of the harvest pipeline, with the amount produced at each stage
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct HarvestRecord {
+    pub pool_account_id: AccountId,
+    pub token_id: AccountId,
+    pub stage: HarvestStage,
+    /// The pipeline stopped at `stage`
+    pub failed: bool,
+    pub harvested: U128,
+    pub swapped: U128,
+    pub unwrapped: U128,
+    pub restaked: U128,
+    pub block_timestamp: U64,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Configure the harvest pipeline of a pool, used by `yield_harvest`
+    /// NOTE: Without a token, `yield_harvest` only calls the yield function
+    ///
+    /// ```bash
+    /// near call treasury.testnet set_harvest_config '{"pool_account_id": "meta-v2.pool.testnet", "token_id": "token.meta.pool.testnet", "route": {"dex_id": "ref-finance-101.testnet", "pool_id": 79, "token_out": "wrap.testnet", "min_rate": "250000000000000000000000"}}' --accountId treasury.testnet
+    /// ```
+    pub fn set_harvest_config(
+        &mut self,
+        pool_account_id: AccountId,
+        token_id: Option<AccountId>,
+        route: Option<HarvestRoute>,
+    ) {
+        self.assert_owner();
+        let mut delegation = self
+            .stake_delegations
+            .get(&pool_account_id)
+            .expect("Stake delegation doesnt exist");
+        assert!(
+            delegation.yield_function.is_some(),
+            "Yield unsupported for this pool"
+        );
+        assert!(
+            route.is_none() || token_id.is_some(),
+            "Route needs a harvest token"
+        );
+        delegation.harvest_token_id = token_id;
+        delegation.harvest_route = route;
+        self.stake_delegations.insert(&pool_account_id, &delegation);
+    }
+
+    /// Run the harvest pipeline of a pool on a croncat cadence, replacing any previous schedule
+    /// The task is funded for a number of runs (default 30) from the treasury balance, see `refill_harvest` to fund more
+    ///
+    /// ```bash
+    /// near call treasury.testnet schedule_harvest '{"pool_account_id": "meta-v2.pool.testnet", "cadence": "0 0 * * * *", "runs": 30}' --accountId treasury.testnet
+    /// ```
+    pub fn schedule_harvest(
+        &mut self,
+        pool_account_id: AccountId,
+        cadence: String,
+        runs: Option<u64>,
+    ) -> Promise {
+        self.assert_owner();
+        let croncat_id = self
+            .croncat_id
+            .clone()
+            .expect("No croncat account configured");
+        let delegation = self
+            .stake_delegations
+            .get(&pool_account_id)
+            .expect("Stake delegation doesnt exist");
+        assert!(
+            delegation.yield_function.is_some(),
+            "Yield unsupported for this pool"
+        );
+        if let Some(task_hash) = delegation.harvest_task_hash {
+            external::croncat::remove_task(
+                task_hash,
+                croncat_id.clone(),
+                NO_DEPOSIT,
+                staking::GAS_CRONCAT_REMOVE_TASK,
+            );
+        }
+
+        external::croncat::create_task(
+            env::current_account_id().to_string(),
+            "yield_harvest".to_string(),
+            cadence,
+            Some(true),
+            Some(U128::from(NO_DEPOSIT)),
+            Some(u64::from(GAS_HARVEST_PIPELINE)),
+            Some(Base64VecU8::from(
+                json!({
+                    "pool_account_id": pool_account_id,
+                })
+                .to_string()
+                .into_bytes(),
+            )),
+            croncat_id,
+            staking::CRONCAT_CREATE_TASK_FEE
+                + self.get_harvest_runs_cost(runs.unwrap_or(HARVEST_SCHEDULE_RUNS)),
+            staking::GAS_CRONCAT_CREATE_TASK,
+        )
+        .then(
+            Promise::new(env::current_account_id()).function_call(
+                "callback_schedule_harvest".to_string(),
+                json!({
+                    "pool_account_id": pool_account_id,
+                })
+                .to_string()
+                .into_bytes(),
+                NO_DEPOSIT,
+                staking::GAS_CRONCAT_CREATE_TASK_CALLBACK,
+            ),
+        )
+    }
+
+    /// CALLBACK for schedule_harvest, keeps the task hash so the schedule can be replaced or removed
+    #[private]
+    pub fn callback_schedule_harvest(&mut self, pool_account_id: AccountId) {
+        if let PromiseResult::Successful(result) = env::promise_result(0) {
+            let task_hash: Base64VecU8 =
+                serde_json::de::from_slice(&result).expect("Could not get task hash");
+            if let Some(mut delegation) = self.stake_delegations.get(&pool_account_id) {
+                delegation.harvest_task_hash = Some(task_hash);
+                self.stake_delegations.insert(&pool_account_id, &delegation);
+            }
+        }
+    }
+
+    /// Fund more runs of the scheduled harvest of a pool, from the treasury balance
+    ///
+    /// ```bash
+    /// near call treasury.testnet refill_harvest '{"pool_account_id": "meta-v2.pool.testnet", "runs": 30}' --accountId treasury.testnet
+    /// ```
+    pub fn refill_harvest(&mut self, pool_account_id: AccountId, runs: u64) -> Promise {
+        self.assert_owner();
+        assert_ne!(runs, 0, "Nothing to refill");
+        let croncat_id = self
+            .croncat_id
+            .clone()
+            .expect("No croncat account configured");
+        let task_hash = self
+            .stake_delegations
+            .get(&pool_account_id)
+            .expect("Stake delegation doesnt exist")
+            .harvest_task_hash
+            .expect("No harvest scheduled");
+
+        external::croncat::refill_balance(
+            task_hash,
+            croncat_id,
+            self.get_harvest_runs_cost(runs),
+            staking::GAS_CRONCAT_REFILL_BALANCE,
+        )
+    }
+
+    /// Stop the scheduled harvest of a pool
+    ///
+    /// ```bash
+    /// near call treasury.testnet unschedule_harvest '{"pool_account_id": "meta-v2.pool.testnet"}' --accountId treasury.testnet
+    /// ```
+    pub fn unschedule_harvest(&mut self, pool_account_id: AccountId) -> Promise {
+        self.assert_owner();
+        let croncat_id = self
+            .croncat_id
+            .clone()
+            .expect("No croncat account configured");
+        let mut delegation = self
+            .stake_delegations
+            .get(&pool_account_id)
+            .expect("Stake delegation doesnt exist");
+        let task_hash = delegation
+            .harvest_task_hash
+            .take()
+            .expect("No harvest scheduled");
+        self.stake_delegations.insert(&pool_account_id, &delegation);
+
+        external::croncat::remove_task(
+            task_hash,
+            croncat_id,
+            NO_DEPOSIT,
+            staking::GAS_CRONCAT_REMOVE_TASK,
+        )
+    }
+
+    /// Harvest pipeline runs, oldest first
+    /// NOTE: Only the latest 1000 runs are kept, `from_index` is relative to the oldest one kept
+    ///
+    /// ```bash
+    /// near view treasury.testnet get_harvest_history '{"from_index": "0", "limit": "10"}'
+    /// ```
+    pub fn get_harvest_history(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<U64>,
+    ) -> Vec<HarvestRecord> {
+        self.stake_harvests.page(
+            from_index.map(|i| i.0).unwrap_or(0),
+            limit.map(|l| l.0).unwrap_or(10),
+        )
+    }
+
+    /// 0. CALLBACK for the token balance before harvesting, calls the pool yield function
+    #[private]
+    pub fn callback_harvest_start(&mut self, index: u64, yield_function: String) {
+        let record = self.stake_harvests.get(index).expect("No harvest found");
+        let balance_before = match Self::get_harvest_balance_result() {
+            Some(balance) => balance,
+            None => return self.internal_harvest_update(index, record, true),
+        };
+
+        // Make a yield harvest call, including yocto since most include FT that needs txns with priveledges
+        Promise::new(record.pool_account_id.clone())
+            .function_call(
+                yield_function,
+                json!({}).to_string().into_bytes(),
+                ONE_YOCTO,
+                GAS_HARVEST_YIELD,
+            )
+            .then(
+                Promise::new(env::current_account_id()).function_call(
+                    "callback_harvest".to_string(),
+                    json!({
+                        "index": index,
+                        "balance_before": balance_before,
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NO_DEPOSIT,
+                    GAS_HARVEST_CALLBACK,
+                ),
+            );
+    }
+
+    /// 1. CALLBACK for the pool yield function, fetches the harvested token balance
+    #[private]
+    pub fn callback_harvest(&mut self, index: u64, balance_before: U128) {
+        let mut record = self.stake_harvests.get(index).expect("No harvest found");
+        if !is_promise_success() {
+            return self.internal_harvest_update(index, record, true);
+        }
+
+        record.stage = HarvestStage::Refresh;
+        ext_fungible_token::ft_balance_of(
+            env::current_account_id(),
+            record.token_id.clone(),
+            NO_DEPOSIT,
+            GAS_HARVEST_BALANCE_OF,
+        )
+        .then(
+            Promise::new(env::current_account_id()).function_call(
+                "callback_harvest_refresh".to_string(),
+                json!({
+                    "index": index,
+                    "balance_before": balance_before,
+                })
+                .to_string()
+                .into_bytes(),
+                NO_DEPOSIT,
+                GAS_HARVEST_REFRESH_CALLBACK,
+            ),
+        );
+        self.internal_harvest_update(index, record, false);
+    }
+
+    /// 2. CALLBACK for ft_balance_of, credits what was harvested then swaps or unwraps it
+    /// NOTE: Harvested amount is measured against the balance read right before harvesting, so untracked tokens are not counted
+    #[private]
+    pub fn callback_harvest_refresh(&mut self, index: u64, balance_before: U128) {
+        let mut record = self.stake_harvests.get(index).expect("No harvest found");
+        let balance = match Self::get_harvest_balance_result() {
+            Some(balance) => balance,
+            None => return self.internal_harvest_update(index, record, true),
+        };
+        record.harvested = U128::from(balance.0.saturating_sub(balance_before.0));
+        let stored = self.ft_balances.get(&record.token_id).unwrap_or(0);
+        self.ft_balances
+            .insert(&record.token_id, &stored.saturating_add(record.harvested.0));
+        if record.harvested.0 == 0 {
+            record.stage = HarvestStage::Done;
+            return self.internal_harvest_update(index, record, false);
+        }
+
+        let route = self
+            .stake_delegations
+            .get(&record.pool_account_id)
+            .and_then(|delegation| delegation.harvest_route);
+        match route {
+            Some(route) => {
+                // Read the token swapped into right before swapping, to measure the swap against
+                record.stage = HarvestStage::Swap;
+                ext_fungible_token::ft_balance_of(
+                    env::current_account_id(),
+                    route.token_out,
+                    NO_DEPOSIT,
+                    GAS_HARVEST_BALANCE_OF,
+                )
+                .then(
+                    Promise::new(env::current_account_id()).function_call(
+                        "callback_harvest_swap_balance".to_string(),
+                        json!({
+                            "index": index,
+                        })
+                        .to_string()
+                        .into_bytes(),
+                        NO_DEPOSIT,
+                        GAS_HARVEST_SWAP_BALANCE_CALLBACK,
+                    ),
+                );
+                self.internal_harvest_update(index, record, false);
+            }
+            None if Some(&record.token_id) == self.wrap_id.as_ref() => {
+                let amount = record.harvested.0;
+                self.internal_harvest_unwrap(index, record, amount);
+            }
+            None => {
+                record.stage = HarvestStage::Done;
+                self.internal_harvest_update(index, record, false);
+            }
+        }
+    }
+
+    /// 3. CALLBACK for ft_balance_of of the token swapped into, sends the harvested amount to the DEX route
+    /// NOTE: The harvested amount stays credited if the swap cant start
+    #[private]
+    pub fn callback_harvest_swap_balance(&mut self, index: u64) {
+        let record = self.stake_harvests.get(index).expect("No harvest found");
+        let route = self
+            .stake_delegations
+            .get(&record.pool_account_id)
+            .and_then(|delegation| delegation.harvest_route);
+        let (route, swap_balance_before) = match (route, Self::get_harvest_balance_result()) {
+            (Some(route), Some(balance)) => (route, balance),
+            _ => return self.internal_harvest_update(index, record, true),
+        };
+        let amount = record.harvested;
+        let stored = self.ft_balances.get(&record.token_id).unwrap_or(0);
+        self.ft_balances
+            .insert(&record.token_id, &stored.saturating_sub(amount.0));
+        self.internal_ft_pending_add(&record.token_id, amount.0);
+        events::FtTransfer {
+            token_id: Some(record.token_id.clone()),
+            receiver_id: route.dex_id.clone(),
+            amount,
+            memo: None,
+        }
+        .emit();
+
+        let min_amount_out = (U256::from(amount.0) * U256::from(route.min_rate.0)
+            / U256::from(HARVEST_RATE_DENOMINATOR))
+        .as_u128();
+        let msg = json!({
+            "actions": [{
+                "pool_id": route.pool_id,
+                "token_in": record.token_id,
+                "token_out": route.token_out,
+                "min_amount_out": U128::from(min_amount_out),
+            }],
+        })
+        .to_string();
+        let swap_stored_before = U128::from(self.ft_balances.get(&route.token_out).unwrap_or(0));
+        self.internal_ft_transfer_promise(
+            record.token_id.clone(),
+            route.dex_id,
+            amount,
+            None,
+            Some(msg),
+        )
+        .then(
+            Promise::new(env::current_account_id()).function_call(
+                "callback_harvest_swap".to_string(),
+                json!({
+                    "index": index,
+                    "swap_balance_before": swap_balance_before,
+                    "swap_stored_before": swap_stored_before,
+                })
+                .to_string()
+                .into_bytes(),
+                NO_DEPOSIT,
+                GAS_HARVEST_SWAP_CALLBACK,
+            ),
+        );
+    }
+
+    /// 4. CALLBACK for the swap ft_transfer_call, fetches the balance of the token swapped into
+    /// NOTE: Refunds of unused tokens are credited back by ft_transfer_call_callback
+    #[private]
+    pub fn callback_harvest_swap(
+        &mut self,
+        index: u64,
+        swap_balance_before: U128,
+        swap_stored_before: U128,
+    ) {
+        let record = self.stake_harvests.get(index).expect("No harvest found");
+        let used = match env::promise_result(0) {
+            PromiseResult::Successful(result) => {
+                serde_json::de::from_slice::<U128>(&result).unwrap_or(U128::from(0))
+            }
+            _ => U128::from(0),
+        };
+        if used.0 == 0 {
+            return self.internal_harvest_update(index, record, true);
+        }
+
+        let route = self
+            .stake_delegations
+            .get(&record.pool_account_id)
+            .and_then(|delegation| delegation.harvest_route)
+            .expect("No harvest route");
+        ext_fungible_token::ft_balance_of(
+            env::current_account_id(),
+            route.token_out,
+            NO_DEPOSIT,
+            GAS_HARVEST_BALANCE_OF,
+        )
+        .then(
+            Promise::new(env::current_account_id()).function_call(
+                "callback_harvest_swap_refresh".to_string(),
+                json!({
+                    "index": index,
+                    "swap_balance_before": swap_balance_before,
+                    "swap_stored_before": swap_stored_before,
+                })
+                .to_string()
+                .into_bytes(),
+                NO_DEPOSIT,
+                GAS_HARVEST_SWAP_REFRESH_CALLBACK,
+            ),
+        );
+    }
+
+    /// 5. CALLBACK for ft_balance_of of the token swapped into, unwraps it if it is the wrap token
+    /// NOTE: Swapped amount is measured against the balance read right before swapping
+    /// NOTE: Any of the token credited meanwhile (EX: deposits, wraps) is tracked already, so is not counted as swapped
+    #[private]
+    pub fn callback_harvest_swap_refresh(
+        &mut self,
+        index: u64,
+        swap_balance_before: U128,
+        swap_stored_before: U128,
+    ) {
+        let mut record = self.stake_harvests.get(index).expect("No harvest found");
+        let route = self
+            .stake_delegations
+            .get(&record.pool_account_id)
+            .and_then(|delegation| delegation.harvest_route)
+            .expect("No harvest route");
+        let balance = match Self::get_harvest_balance_result() {
+            Some(balance) => balance,
+            None => return self.internal_harvest_update(index, record, true),
+        };
+        let stored = self.ft_balances.get(&route.token_out).unwrap_or(0);
+        let credited = stored.saturating_sub(swap_stored_before.0);
+        record.swapped = U128::from(
+            balance
+                .0
+                .saturating_sub(swap_balance_before.0)
+                .saturating_sub(credited),
+        );
+        self.ft_balances
+            .insert(&route.token_out, &stored.saturating_add(record.swapped.0));
+
+        if record.swapped.0 > 0 && Some(&route.token_out) == self.wrap_id.as_ref() {
+            let amount = record.swapped.0;
+            self.internal_harvest_unwrap(index, record, amount);
+        } else {
+            record.stage = HarvestStage::Done;
+            self.internal_harvest_update(index, record, false);
+        }
+    }
+
+    /// 6. CALLBACK for near_withdraw, restakes the unwrapped NEAR into the pool it was harvested from
+    #[private]
+    pub fn callback_harvest_unwrap(&mut self, index: u64, wrap_id: AccountId, amount: U128) {
+        let mut record = self.stake_harvests.get(index).expect("No harvest found");
+        self.internal_ft_settle(&wrap_id, amount.0);
+        if !is_promise_success() {
+            let balance = self.ft_balances.get(&wrap_id).unwrap_or(0);
+            self.ft_balances
+                .insert(&wrap_id, &balance.saturating_add(amount.0));
+            return self.internal_harvest_update(index, record, true);
+        }
+        events::WrapNear {
+            amount,
+            wrapped: false,
+        }
+        .emit();
+
+        record.unwrapped = amount;
+        record.stage = HarvestStage::Restake;
+        let p = self.internal_deposit_and_stake(record.pool_account_id.clone(), amount.0);
+        env::promise_then(
+            p,
+            env::current_account_id(),
+            "callback_harvest_restake",
+            json!({
+                "index": index,
+                "amount": amount,
+            })
+            .to_string()
+            .as_bytes(),
+            NO_DEPOSIT,
+            GAS_HARVEST_RESTAKE_CALLBACK,
+        );
+        self.internal_harvest_update(index, record, false);
+    }
+
+    /// 7. CALLBACK for deposit_and_stake, the NEAR stays liquid if the pool did not take it
+    /// NOTE: The deposit bookkeeping is already reverted by callback_deposit_and_stake
+    #[private]
+    pub fn callback_harvest_restake(&mut self, index: u64, amount: U128) {
+        let mut record = self.stake_harvests.get(index).expect("No harvest found");
+        let staked = match env::promise_result(0) {
+            PromiseResult::Successful(result) => {
+                serde_json::de::from_slice::<bool>(&result).unwrap_or(false)
+            }
+            _ => false,
+        };
+        if !staked {
+            return self.internal_harvest_update(index, record, true);
+        }
+
+        record.restaked = amount;
+        record.stage = HarvestStage::Done;
+        self.internal_harvest_update(index, record, false);
+    }
+}
+
+impl Contract {
+    /// Starts the harvest pipeline of a pool, recording the run in the harvest history
+    /// Reads the balance of the harvested token first, to measure the harvest against
+    pub(crate) fn internal_harvest(
+        &mut self,
+        pool_account_id: AccountId,
+        yield_function: String,
+        token_id: AccountId,
+    ) -> Promise {
+        let index = self.stake_harvests.push(&HarvestRecord {
+            pool_account_id: pool_account_id.clone(),
+            token_id: token_id.clone(),
+            stage: HarvestStage::Harvest,
+            failed: false,
+            harvested: U128::from(0),
+            swapped: U128::from(0),
+            unwrapped: U128::from(0),
+            restaked: U128::from(0),
+            block_timestamp: U64::from(env::block_timestamp()),
+        });
+
+        ext_fungible_token::ft_balance_of(
+            env::current_account_id(),
+            token_id,
+            NO_DEPOSIT,
+            GAS_HARVEST_BALANCE_OF,
+        )
+        .then(
+            Promise::new(env::current_account_id()).function_call(
+                "callback_harvest_start".to_string(),
+                json!({
+                    "index": index,
+                    "yield_function": yield_function,
+                })
+                .to_string()
+                .into_bytes(),
+                NO_DEPOSIT,
+                GAS_HARVEST_START_CALLBACK,
+            ),
+        )
+    }
+
+    /// Balance returned by a single ft_balance_of, None if the read failed
+    fn get_harvest_balance_result() -> Option<U128> {
+        if env::promise_results_count() != 1 {
+            return None;
+        }
+        match env::promise_result(0) {
+            PromiseResult::Successful(result) => serde_json::de::from_slice::<U128>(&result).ok(),
+            _ => None,
+        }
+    }
+
+    /// NEAR paid to croncat for a number of harvest pipeline runs
+    pub(crate) fn get_harvest_runs_cost(&self, runs: u64) -> Balance {
+        u128::from(GAS_HARVEST_PIPELINE.0)
+            .saturating_mul(GAS_BASE_PRICE)
+            .saturating_mul(u128::from(runs))
+    }
+
+    /// Debit the wNEAR balance, then withdraw it as NEAR for restaking
+    fn internal_harvest_unwrap(&mut self, index: u64, mut record: HarvestRecord, amount: Balance) {
+        let wrap_id = self.wrap_id.clone().expect("No wrap account configured");
+        let balance = self.ft_balances.get(&wrap_id).unwrap_or(0);
+        self.ft_balances
+            .insert(&wrap_id, &balance.saturating_sub(amount));
+        self.internal_ft_pending_add(&wrap_id, amount);
+
+        record.stage = HarvestStage::Unwrap;
+        Promise::new(wrap_id.clone())
+            .function_call(
+                "near_withdraw".to_string(),
+                json!({
+                    "amount": U128::from(amount),
+                })
+                .to_string()
+                .into_bytes(),
+                ONE_YOCTO,
+                wrap::GAS_WRAP_NEAR_WITHDRAW,
+            )
+            .then(
+                Promise::new(env::current_account_id()).function_call(
+                    "callback_harvest_unwrap".to_string(),
+                    json!({
+                        "index": index,
+                        "wrap_id": wrap_id,
+                        "amount": U128::from(amount),
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NO_DEPOSIT,
+                    GAS_HARVEST_UNWRAP_CALLBACK,
+                ),
+            );
+        self.internal_harvest_update(index, record, false);
+    }
+
+    /// Store the progress of a harvest, emitting an event once it finished or failed
+    fn internal_harvest_update(&mut self, index: u64, mut record: HarvestRecord, failed: bool) {
+        record.failed = failed;
+        self.stake_harvests.replace(index, &record);
+        if failed || record.stage == HarvestStage::Done {
+            events::HarvestComplete {
+                index: U64::from(index),
+                record,
+            }
+            .emit();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    /// Pool accounts(2) harvesting token accounts(3), optionally swapped on DEX accounts(5) into wNEAR accounts(4)
+    fn setup(route: bool) -> Contract {
+        let mut contract = setup_contract();
+        contract.wrap_id = Some(accounts(4));
+        contract.add_staking_pool(
+            accounts(2),
+            None,
+            None,
+            Some("harvest_meta".to_string()),
+            None,
+            None,
+        );
+        contract.set_harvest_config(
+            accounts(2),
+            Some(accounts(3)),
+            route.then(|| HarvestRoute {
+                dex_id: accounts(5),
+                pool_id: 79,
+                token_out: accounts(4),
+                min_rate: U128::from(HARVEST_RATE_DENOMINATOR / 4),
+            }),
+        );
+
+        // The pipeline takes up most of the gas of a call
+        testing_env!(get_context(accounts(1)).build());
+        contract.internal_harvest(accounts(2), "harvest_meta".to_string(), accounts(3));
+        contract
+    }
+
+    fn get_record(contract: &Contract) -> HarvestRecord {
+        contract.stake_harvests.get(0).unwrap()
+    }
+
+    fn balance_result(balance: u128) -> PromiseResult {
+        json_result(&U128::from(balance))
+    }
+
+    #[test]
+    fn test_harvest_swap_and_restake() {
+        let mut contract = setup(true);
+        let mut context = get_context(accounts(0));
+        set_callback_context(&mut context, vec![balance_result(100)]);
+        contract.callback_harvest_start(0, "harvest_meta".to_string());
+        set_callback_context(&mut context, vec![PromiseResult::Successful(vec![])]);
+        contract.callback_harvest(0, U128::from(100));
+        assert!(get_record(&contract).stage == HarvestStage::Refresh);
+
+        // Harvested is measured from the balance before harvesting
+        set_callback_context(&mut context, vec![balance_result(150)]);
+        contract.callback_harvest_refresh(0, U128::from(100));
+        assert_eq!(get_record(&contract).harvested.0, 50);
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(50));
+        assert!(get_record(&contract).stage == HarvestStage::Swap);
+
+        set_callback_context(&mut context, vec![balance_result(1_000)]);
+        contract.callback_harvest_swap_balance(0);
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(0));
+        assert_eq!(contract.ft_pending.get(&accounts(3)), Some(50));
+        assert_eq!(
+            get_events("ft_transfer")[0]["receiver_id"],
+            accounts(5).to_string()
+        );
+
+        // A wNEAR deposit lands during the swap, it is not counted as swapped
+        contract.ft_balances.insert(&accounts(4), &10);
+        set_callback_context(&mut context, vec![balance_result(50)]);
+        contract.callback_harvest_swap(0, U128::from(1_000), U128::from(0));
+        set_callback_context(&mut context, vec![balance_result(1_030)]);
+        contract.callback_harvest_swap_refresh(0, U128::from(1_000), U128::from(0));
+        let record = get_record(&contract);
+        assert_eq!(record.swapped.0, 20);
+        assert!(record.stage == HarvestStage::Unwrap);
+        assert_eq!(contract.ft_balances.get(&accounts(4)), Some(10));
+        assert_eq!(contract.ft_pending.get(&accounts(4)), Some(20));
+
+        set_callback_context(&mut context, vec![PromiseResult::Successful(vec![])]);
+        contract.callback_harvest_unwrap(0, accounts(4), U128::from(20));
+        assert!(contract.ft_pending.get(&accounts(4)).is_none());
+        assert_eq!(get_record(&contract).unwrapped.0, 20);
+        assert_eq!(
+            contract
+                .stake_delegations
+                .get(&accounts(2))
+                .unwrap()
+                .balance,
+            20
+        );
+
+        set_callback_context(&mut context, vec![json_result(&true)]);
+        contract.callback_harvest_restake(0, U128::from(20));
+        let record = get_record(&contract);
+        assert_eq!(record.restaked.0, 20);
+        assert!(record.stage == HarvestStage::Done);
+        assert!(!record.failed);
+        let events = get_events("harvest_complete");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["record"]["stage"], "done");
+        assert_eq!(contract.get_harvest_history(None, None).len(), 1);
+    }
+
+    #[test]
+    fn test_harvest_missing_balance_fails() {
+        let mut contract = setup(true);
+        let mut context = get_context(accounts(0));
+        set_callback_context(&mut context, vec![PromiseResult::Failed]);
+        contract.callback_harvest_start(0, "harvest_meta".to_string());
+        let record = get_record(&contract);
+        assert!(record.failed);
+        assert!(record.stage == HarvestStage::Harvest);
+        assert_eq!(get_events("harvest_complete").len(), 1);
+
+        // Nothing gets credited without a balance after harvesting
+        set_callback_context(&mut context, vec![PromiseResult::Failed]);
+        contract.callback_harvest_refresh(0, U128::from(100));
+        assert!(get_record(&contract).failed);
+        assert!(contract.ft_balances.get(&accounts(3)).is_none());
+
+        // Nor is the harvest sent to the DEX without the balance before swapping
+        contract.ft_balances.insert(&accounts(3), &50);
+        set_callback_context(&mut context, vec![PromiseResult::Failed]);
+        contract.callback_harvest_swap_balance(0);
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(50));
+        assert!(contract.ft_pending.get(&accounts(3)).is_none());
+    }
+
+    #[test]
+    fn test_harvest_swap_failed() {
+        let mut contract = setup(true);
+        let mut context = get_context(accounts(0));
+        set_callback_context(&mut context, vec![balance_result(0)]);
+        contract.callback_harvest_swap(0, U128::from(1_000), U128::from(0));
+        assert!(get_record(&contract).failed);
+
+        set_callback_context(&mut context, vec![PromiseResult::Failed]);
+        contract.callback_harvest(0, U128::from(100));
+        assert!(get_record(&contract).failed);
+        assert_eq!(get_events("harvest_complete").len(), 1);
+    }
+
+    #[test]
+    fn test_harvest_without_route() {
+        let mut contract = setup(false);
+        let mut context = get_context(accounts(0));
+
+        // Nothing harvested, the run is done
+        set_callback_context(&mut context, vec![balance_result(100)]);
+        contract.callback_harvest_refresh(0, U128::from(100));
+        let record = get_record(&contract);
+        assert!(record.stage == HarvestStage::Done);
+        assert_eq!(record.harvested.0, 0);
+
+        // Harvested token is kept in the treasury
+        set_callback_context(&mut context, vec![balance_result(130)]);
+        contract.callback_harvest_refresh(0, U128::from(100));
+        let record = get_record(&contract);
+        assert!(record.stage == HarvestStage::Done);
+        assert!(!record.failed);
+        assert_eq!(record.harvested.0, 30);
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(30));
+    }
+}
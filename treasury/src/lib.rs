@@ -1,17 +1,23 @@
 use near_contract_standards::fungible_token::core_impl::ext_fungible_token;
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::non_fungible_token::core::NonFungibleTokenReceiver;
+use near_contract_standards::non_fungible_token::metadata::TokenMetadata;
+use near_contract_standards::non_fungible_token::Token;
+use near_contract_standards::storage_management::{StorageBalance, StorageBalanceBounds};
 use near_sdk::{
     assert_one_yocto,
     borsh::{self, BorshDeserialize, BorshSerialize},
-    collections::{TreeMap, UnorderedMap, UnorderedSet},
+    collections::{LookupMap, TreeMap, UnorderedMap, UnorderedSet, Vector},
     env, ext_contract,
     json_types::{Base64VecU8, U128, U64},
-    log, near_bindgen,
+    near_bindgen,
     serde::{Deserialize, Serialize},
     serde_json,
     serde_json::json,
     utils::is_promise_success,
-    AccountId, Balance, BorshStorageKey, Gas, PanicOnDefault, Promise, PromiseOrValue,
-    PromiseResult,
+    AccountId, Balance, BorshStorageKey, Gas, IntoStorageKey, PanicOnDefault, Promise,
+    PromiseOrValue, PromiseResult, StorageUsage,
 };
 use std::collections::VecDeque;
 use uint::construct_uint;
@@ -22,15 +28,20 @@ construct_uint! {
 }
 
 mod actions;
+mod events;
 mod external;
 mod owner;
 mod staking;
 mod utils;
 mod views;
-// TODO:
-// mod storage_impl;
+mod storage_impl;
 mod ft_impl;
+mod harvest;
+mod migrate;
+#[cfg(test)]
+mod test_utils;
 mod nft_impl;
+mod wrap;
 
 use actions::Action;
 use staking::{StakeDelegation, StakeDelegationHumanFriendly, StakeThreshold};
@@ -58,6 +69,20 @@ pub enum StorageKeys {
     StakePools,
     StakePoolsPending,
     YieldFunctions,
+    FungibleTokenWhitelist,
+    FungibleTokenHistory,
+    FungibleTokenStorageSpent,
+    FungibleTokenRegistry,
+    NonFungibleTokenAllowlist,
+    NonFungibleTokenBlocklist,
+    NonFungibleTokenHistory,
+    NonFungibleTokenPending,
+    NonFungibleTokenApprovals,
+    NonFungibleTokenMetadata,
+    StorageAccounts,
+    StakeHarvests,
+    FungibleTokenPending,
+    NonFungibleTokenSyncing,
 }
 
 #[near_bindgen]
@@ -78,14 +103,42 @@ pub struct Contract {
     cadence_actions: UnorderedMap<String, Action>, // recurring items, using croncat cadence, only allowing 1 action per cadence for simplicity of non-pagination
     timeout_actions: TreeMap<u128, VecDeque<Action>>, // single trigger items, using croncat trigger upon a timeout/future timestamp
 
+    // Storage Management
+    storage_accounts: LookupMap<AccountId, storage_impl::StorageAccount>, // NEP-145 deposits of non-owner accounts, paying for the records they create
+    storage_account_usage: StorageUsage,
+
     // Token Standards
     ft_balances: UnorderedMap<AccountId, u128>,
+    ft_whitelist: UnorderedSet<AccountId>, // Accepted incoming tokens, when empty all tokens are accepted
+    ft_history: utils::BoundedHistory<ft_impl::FungibleTokenRecord>, // latest deposits & transfers, oldest get overwritten
+    ft_balances_refreshed_at: u64,
+    ft_balances_refresh_period: u64,
+    ft_balances_refresh_index: u64, // Next page of an unfinished balance refresh
+    ft_pending: UnorderedMap<AccountId, Balance>, // Outgoing amounts debited but not confirmed yet, balance refreshes skip these tokens
+    ft_storage_budget: Balance, // Remaining NEAR that can be spent registering FT recipients
+    ft_storage_spent: UnorderedMap<AccountId, Balance>,
+    ft_registry: UnorderedMap<AccountId, ft_impl::FungibleTokenRegistration>,
+    wrap_id: Option<AccountId>, // wNEAR contract, EX: wrap.near
+    wrap_near_float: Option<Balance>, // Target wNEAR balance kept topped up from liquid NEAR, optional
+    wrap_near_pending: bool, // A wrap is in flight, keeps the float from being topped up twice
     nft_holdings: UnorderedMap<AccountId, Vec<String>>,
+    nft_allowlist: UnorderedSet<AccountId>, // Accepted incoming NFT contracts, when empty all contracts not blocklisted are accepted
+    nft_blocklist: UnorderedSet<AccountId>, // Rejected incoming NFT contracts, EX: spam
+    nft_history: utils::BoundedHistory<nft_impl::NonFungibleTokenRecord>, // latest deposits & transfers, oldest get overwritten
+    nft_pending: UnorderedMap<AccountId, Vec<String>>, // Outgoing tokens awaiting transfer confirmation
+    nft_approvals: UnorderedMap<AccountId, Vec<nft_impl::NonFungibleTokenApproval>>, // Accounts approved to transfer held tokens, EX: marketplace listings
+    nft_metadata: LookupMap<String, TokenMetadata>, // Cached token metadata, keyed by "nft_account_id:token_id"
+    nft_syncing: LookupMap<AccountId, Vec<String>>, // Tokens seen so far by an unfinished holdings sync
 
     // Staking
     stake_threshold: StakeThreshold,
+    stake_refresh_period: u64, // How long delegation balances are considered fresh
+    stake_refresh_index: u64,  // Next page of an unfinished delegations refresh
+    stake_epoch_length: u64,   // Estimated epoch length, for scheduling withdraws once unstakes unlock
+    max_liquid_unstake_fee_bps: u64, // Liquid unstakes abort if the quoted fee is higher
     stake_delegations: UnorderedMap<AccountId, StakeDelegation>, // for near staking, can be metapool, or other pools directly
-    stake_pending_delegations: UnorderedMap<AccountId, StakeDelegation>, // for withdraw near staking
+    stake_pending_delegations: UnorderedMap<AccountId, Vec<staking::UnstakeTranche>>, // unstakes awaiting withdraw, oldest first per pool
+    stake_harvests: utils::BoundedHistory<harvest::HarvestRecord>, // latest harvest pipeline runs, with the amount produced at each stage
 
                                                                          // Yield harvesting
                                                                          // yield_functions: LookupMap<AccountId, String>, // Storage
@@ -102,21 +155,58 @@ impl Contract {
     /// ```
     #[init]
     pub fn new() -> Self {
-        Contract {
+        let mut this = Contract {
             paused: false,
             owner_id: env::signer_account_id(),
             approved_accounts_payable: UnorderedSet::new(StorageKeys::AccountsPayableApproved),
             approved_action_types: UnorderedSet::new(StorageKeys::ActionsApproved),
             ft_balances: UnorderedMap::new(StorageKeys::FungibleTokenBalances),
+            ft_whitelist: UnorderedSet::new(StorageKeys::FungibleTokenWhitelist),
+            ft_history: utils::BoundedHistory::new(
+                StorageKeys::FungibleTokenHistory,
+                ft_impl::FT_HISTORY_MAX_LEN,
+            ),
+            ft_balances_refreshed_at: 0,
+            ft_balances_refresh_period: ft_impl::FT_BALANCES_REFRESH_PERIOD,
+            ft_balances_refresh_index: 0,
+            ft_pending: UnorderedMap::new(StorageKeys::FungibleTokenPending),
+            ft_storage_budget: 0,
+            ft_storage_spent: UnorderedMap::new(StorageKeys::FungibleTokenStorageSpent),
+            ft_registry: UnorderedMap::new(StorageKeys::FungibleTokenRegistry),
+            wrap_id: None,
+            wrap_near_float: None,
+            wrap_near_pending: false,
             nft_holdings: UnorderedMap::new(StorageKeys::NonFungibleTokenHoldings),
+            nft_allowlist: UnorderedSet::new(StorageKeys::NonFungibleTokenAllowlist),
+            nft_blocklist: UnorderedSet::new(StorageKeys::NonFungibleTokenBlocklist),
+            nft_history: utils::BoundedHistory::new(
+                StorageKeys::NonFungibleTokenHistory,
+                nft_impl::NFT_HISTORY_MAX_LEN,
+            ),
+            nft_pending: UnorderedMap::new(StorageKeys::NonFungibleTokenPending),
+            nft_approvals: UnorderedMap::new(StorageKeys::NonFungibleTokenApprovals),
+            nft_metadata: LookupMap::new(StorageKeys::NonFungibleTokenMetadata),
+            nft_syncing: LookupMap::new(StorageKeys::NonFungibleTokenSyncing),
             croncat_id: None,
             cadence_actions: UnorderedMap::new(StorageKeys::ActionsCadence),
             timeout_actions: TreeMap::new(StorageKeys::ActionsTimeout),
+            storage_accounts: LookupMap::new(StorageKeys::StorageAccounts),
+            storage_account_usage: 0,
             stake_threshold: StakeThreshold::default(),
+            stake_refresh_period: staking::STAKE_REFRESH_PERIOD,
+            stake_refresh_index: 0,
+            stake_epoch_length: staking::STAKE_EPOCH_LENGTH,
+            max_liquid_unstake_fee_bps: staking::MAX_LIQUID_UNSTAKE_FEE_BPS,
             stake_delegations: UnorderedMap::new(StorageKeys::StakePools), // for near staking, can be metapool, or other pools directly
             stake_pending_delegations: UnorderedMap::new(StorageKeys::StakePoolsPending), // for withdraw near staking
+            stake_harvests: utils::BoundedHistory::new(
+                StorageKeys::StakeHarvests,
+                harvest::HARVEST_HISTORY_MAX_LEN,
+            ),
                                                                                           // yield_functions: LookupMap::new(StorageKeys::YieldFunctions),
-        }
+        };
+        this.measure_storage_account_usage();
+        this
     }
 }
 
@@ -0,0 +1,274 @@
+use crate::*;
+
+use near_sdk::BlockHeight;
+
+/// Action as stored before storage payers were tracked
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ActionV1 {
+    pub priority: u8,
+    pub timeout: Option<U128>,
+    pub cadence: Option<String>,
+    pub payload: actions::ActionType,
+}
+
+/// Stake delegation as stored before unstake tranches & rewards tracking
+/// NOTE: stake_pending_delegations used the same record, with the withdraw fields set
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StakeDelegationV1 {
+    pub init_balance: Balance,
+    pub balance: Balance,
+    pub start_block: BlockHeight,
+    pub withdraw_epoch: Option<u64>,
+    pub withdraw_balance: Option<Balance>,
+    pub withdraw_function: String,
+    pub liquid_unstake_function: Option<String>,
+    pub yield_function: Option<String>,
+}
+
+/// Contract state as deployed before the storage, token & staking upgrades
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct ContractV1 {
+    paused: bool,
+    owner_id: AccountId,
+    approved_accounts_payable: UnorderedSet<AccountId>,
+    approved_action_types: UnorderedSet<String>,
+    croncat_id: Option<AccountId>,
+    cadence_actions: UnorderedMap<String, ActionV1>,
+    timeout_actions: TreeMap<u128, VecDeque<ActionV1>>,
+    ft_balances: UnorderedMap<AccountId, u128>,
+    nft_holdings: UnorderedMap<AccountId, Vec<String>>,
+    stake_threshold: StakeThreshold,
+    stake_delegations: UnorderedMap<AccountId, StakeDelegationV1>,
+    stake_pending_delegations: UnorderedMap<AccountId, StakeDelegationV1>,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Upgrade the state of a contract deployed before the storage, token & staking upgrades
+    /// Logic:
+    /// - Settings, balances & holdings are kept, new settings get their defaults
+    /// - Stored actions are kept, the treasury pays for their storage
+    /// - Staked balances become the principal of each pool, with an even weight
+    /// - Pending withdraws become a single unstake tranche per pool, with its withdraw scheduled
+    ///
+    /// ```bash
+    /// near deploy --wasmFile res/treasury.wasm --initFunction migrate --initArgs '{}' --accountId treasury.testnet
+    /// ```
+    #[init(ignore_state)]
+    #[private]
+    pub fn migrate() -> Self {
+        let mut old: ContractV1 = env::state_read().expect("No state to migrate");
+
+        // Actions gained a storage payer, rewrite them in place
+        let cadence_actions = old.cadence_actions.to_vec();
+        old.cadence_actions.clear();
+        let mut new_cadence_actions: UnorderedMap<String, Action> =
+            UnorderedMap::new(StorageKeys::ActionsCadence);
+        for (cadence, action) in cadence_actions {
+            new_cadence_actions.insert(&cadence, &Action::from(action));
+        }
+        let timeout_actions = old.timeout_actions.to_vec();
+        old.timeout_actions.clear();
+        let mut new_timeout_actions: TreeMap<u128, VecDeque<Action>> =
+            TreeMap::new(StorageKeys::ActionsTimeout);
+        for (timeout, queue) in timeout_actions {
+            let queue: VecDeque<Action> = queue.into_iter().map(Action::from).collect();
+            new_timeout_actions.insert(&timeout, &queue);
+        }
+
+        // Delegations gained rewards tracking, unstakes became tranches
+        let delegations = old.stake_delegations.to_vec();
+        old.stake_delegations.clear();
+        let pending = old.stake_pending_delegations.to_vec();
+        old.stake_pending_delegations.clear();
+        let mut stake_delegations: UnorderedMap<AccountId, StakeDelegation> =
+            UnorderedMap::new(StorageKeys::StakePools);
+        for (pool_account_id, delegation) in delegations {
+            stake_delegations.insert(&pool_account_id, &StakeDelegation::from(delegation));
+        }
+        let mut stake_pending_delegations: UnorderedMap<AccountId, Vec<staking::UnstakeTranche>> =
+            UnorderedMap::new(StorageKeys::StakePoolsPending);
+        let mut pending_withdraws: Vec<(AccountId, u64)> = Vec::new();
+        for (pool_account_id, delegation) in pending {
+            let amount = delegation.withdraw_balance.unwrap_or(0);
+            if amount == 0 {
+                continue;
+            }
+            let unlock_epoch = delegation.withdraw_epoch.unwrap_or_default();
+            pending_withdraws.push((pool_account_id.clone(), unlock_epoch));
+            stake_pending_delegations.insert(
+                &pool_account_id,
+                &vec![staking::UnstakeTranche {
+                    amount: U128::from(amount),
+                    unlock_epoch,
+                    block_timestamp: U64::from(env::block_timestamp()),
+                    task_hash: None,
+                }],
+            );
+        }
+
+        let mut this = Contract {
+            paused: old.paused,
+            owner_id: old.owner_id,
+            approved_accounts_payable: old.approved_accounts_payable,
+            approved_action_types: old.approved_action_types,
+            ft_balances: old.ft_balances,
+            ft_whitelist: UnorderedSet::new(StorageKeys::FungibleTokenWhitelist),
+            ft_history: utils::BoundedHistory::new(
+                StorageKeys::FungibleTokenHistory,
+                ft_impl::FT_HISTORY_MAX_LEN,
+            ),
+            ft_balances_refreshed_at: 0,
+            ft_balances_refresh_period: ft_impl::FT_BALANCES_REFRESH_PERIOD,
+            ft_balances_refresh_index: 0,
+            ft_pending: UnorderedMap::new(StorageKeys::FungibleTokenPending),
+            ft_storage_budget: 0,
+            ft_storage_spent: UnorderedMap::new(StorageKeys::FungibleTokenStorageSpent),
+            ft_registry: UnorderedMap::new(StorageKeys::FungibleTokenRegistry),
+            wrap_id: None,
+            wrap_near_float: None,
+            wrap_near_pending: false,
+            nft_holdings: old.nft_holdings,
+            nft_allowlist: UnorderedSet::new(StorageKeys::NonFungibleTokenAllowlist),
+            nft_blocklist: UnorderedSet::new(StorageKeys::NonFungibleTokenBlocklist),
+            nft_history: utils::BoundedHistory::new(
+                StorageKeys::NonFungibleTokenHistory,
+                nft_impl::NFT_HISTORY_MAX_LEN,
+            ),
+            nft_pending: UnorderedMap::new(StorageKeys::NonFungibleTokenPending),
+            nft_approvals: UnorderedMap::new(StorageKeys::NonFungibleTokenApprovals),
+            nft_metadata: LookupMap::new(StorageKeys::NonFungibleTokenMetadata),
+            nft_syncing: LookupMap::new(StorageKeys::NonFungibleTokenSyncing),
+            croncat_id: old.croncat_id,
+            cadence_actions: new_cadence_actions,
+            timeout_actions: new_timeout_actions,
+            storage_accounts: LookupMap::new(StorageKeys::StorageAccounts),
+            storage_account_usage: 0,
+            stake_threshold: old.stake_threshold,
+            stake_refresh_period: staking::STAKE_REFRESH_PERIOD,
+            stake_refresh_index: 0,
+            stake_epoch_length: staking::STAKE_EPOCH_LENGTH,
+            max_liquid_unstake_fee_bps: staking::MAX_LIQUID_UNSTAKE_FEE_BPS,
+            stake_delegations,
+            stake_pending_delegations,
+            stake_harvests: utils::BoundedHistory::new(
+                StorageKeys::StakeHarvests,
+                harvest::HARVEST_HISTORY_MAX_LEN,
+            ),
+        };
+
+        // Nothing scheduled the withdraws pending before the upgrade, same as a new unstake would
+        for (pool_account_id, unlock_epoch) in pending_withdraws {
+            this.internal_schedule_withdraw(pool_account_id, unlock_epoch);
+        }
+        this.measure_storage_account_usage();
+        this
+    }
+}
+
+impl From<StakeDelegationV1> for StakeDelegation {
+    fn from(old: StakeDelegationV1) -> Self {
+        // Pools with only a liquid unstake function default to MetaPool, same as add_staking_pool
+        let liquid_staking_provider = old
+            .liquid_unstake_function
+            .as_ref()
+            .map(|_| staking::LiquidStakingProvider::MetaPool);
+        StakeDelegation {
+            init_balance: old.init_balance,
+            balance: old.balance,
+            start_block: old.start_block,
+            withdraw_function: old.withdraw_function,
+            liquid_unstake_function: old.liquid_unstake_function,
+            liquid_staking_provider,
+            yield_function: old.yield_function,
+            harvest_token_id: None,
+            harvest_route: None,
+            harvest_task_hash: None,
+            weight: 1,
+            // Rewards before the upgrade are unknown, so the staked balance counts as principal
+            total_deposited: old.balance,
+            total_withdrawn: 0,
+            principal: old.balance,
+            rewards_realized: 0,
+            snapshots: Vec::new(),
+            unstaked_balance: 0,
+            can_withdraw: false,
+            last_refreshed: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    fn delegation_v1(balance: Balance, withdraw: Option<(u64, Balance)>) -> StakeDelegationV1 {
+        StakeDelegationV1 {
+            init_balance: balance,
+            balance,
+            start_block: BLOCK_START_BLOCK,
+            withdraw_epoch: withdraw.map(|(epoch, _)| epoch),
+            withdraw_balance: withdraw.map(|(_, amount)| amount),
+            withdraw_function: "withdraw_all".to_string(),
+            liquid_unstake_function: None,
+            yield_function: None,
+        }
+    }
+
+    #[test]
+    fn test_migrate() {
+        testing_env!(get_context(accounts(0)).epoch_height(100).build());
+        let mut old = ContractV1 {
+            paused: false,
+            owner_id: accounts(1),
+            approved_accounts_payable: UnorderedSet::new(StorageKeys::AccountsPayableApproved),
+            approved_action_types: UnorderedSet::new(StorageKeys::ActionsApproved),
+            croncat_id: None,
+            cadence_actions: UnorderedMap::new(StorageKeys::ActionsCadence),
+            timeout_actions: TreeMap::new(StorageKeys::ActionsTimeout),
+            ft_balances: UnorderedMap::new(StorageKeys::FungibleTokenBalances),
+            nft_holdings: UnorderedMap::new(StorageKeys::NonFungibleTokenHoldings),
+            stake_threshold: StakeThreshold::default(),
+            stake_delegations: UnorderedMap::new(StorageKeys::StakePools),
+            stake_pending_delegations: UnorderedMap::new(StorageKeys::StakePoolsPending),
+        };
+        old.ft_balances.insert(&accounts(3), &100);
+        old.stake_delegations
+            .insert(&accounts(4), &delegation_v1(ONE_NEAR, None));
+        old.stake_pending_delegations
+            .insert(&accounts(4), &delegation_v1(0, Some((102, ONE_NEAR))));
+        old.stake_pending_delegations
+            .insert(&accounts(5), &delegation_v1(0, Some((102, 0))));
+        env::state_write(&old);
+
+        let contract = Contract::migrate();
+        assert_eq!(contract.owner_id, accounts(1));
+        assert_eq!(contract.ft_balances.get(&accounts(3)), Some(100));
+        let delegation = contract.stake_delegations.get(&accounts(4)).unwrap();
+        assert_eq!(delegation.principal, ONE_NEAR);
+        assert_eq!(delegation.weight, 1);
+
+        // Only the pending withdraw with a balance becomes a tranche, with its withdraw scheduled
+        let tranches = contract
+            .stake_pending_delegations
+            .get(&accounts(4))
+            .unwrap();
+        assert_eq!(tranches.len(), 1);
+        assert_eq!(tranches[0].amount, U128::from(ONE_NEAR));
+        assert_eq!(tranches[0].unlock_epoch, 102);
+        assert!(contract
+            .stake_pending_delegations
+            .get(&accounts(5))
+            .is_none());
+
+        let timeouts = contract.timeout_actions.to_vec();
+        assert_eq!(timeouts.len(), 1);
+        let payload =
+            serde_json::to_value(&timeouts[0].1[0]).unwrap()["payload"]["StakeWithdraw"].clone();
+        assert_eq!(payload["pool_account_id"], accounts(4).to_string());
+        assert_eq!(payload["unlock_epoch"], 102);
+    }
+}
@@ -1,6 +1,22 @@
 use crate::*;
 
 pub const GAS_NFT_TRANSFER: Gas = Gas(10_000_000_000_000);
+pub const GAS_NFT_TRANSFER_CALL: Gas = Gas(50_000_000_000_000);
+pub const GAS_NFT_TRANSFER_CALLBACK: Gas = Gas(10_000_000_000_000);
+pub const GAS_NFT_APPROVE: Gas = Gas(10_000_000_000_000);
+pub const GAS_NFT_APPROVE_CALL: Gas = Gas(50_000_000_000_000);
+pub const GAS_NFT_REVOKE: Gas = Gas(10_000_000_000_000);
+pub const GAS_NFT_APPROVAL_CALLBACK: Gas = Gas(10_000_000_000_000);
+pub const GAS_NFT_IS_APPROVED: Gas = Gas(5_000_000_000_000);
+pub const GAS_NFT_TOKENS_FOR_OWNER: Gas = Gas(20_000_000_000_000);
+pub const GAS_NFT_SYNC_CALLBACK: Gas = Gas(40_000_000_000_000);
+
+/// Covers the approval storage on the NFT contract, any excess is refunded by the contract
+pub const NFT_APPROVAL_DEPOSIT: Balance = 10_000_000_000_000_000_000_000;
+/// Most records kept in NFT history, older ones get overwritten
+pub const NFT_HISTORY_MAX_LEN: u64 = 1_000;
+/// Longest deposit `msg` kept in NFT history, in bytes
+pub const NFT_HISTORY_MSG_MAX_LEN: usize = 256;
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize)]
 #[serde(crate = "near_sdk::serde")]
@@ -14,13 +30,53 @@ pub struct NonFungibleTokens {
 pub struct NonFungibleToken {
     id: String,
     owner_id: String,
+    title: Option<String>,
+    media: Option<String>,
+    metadata: Option<TokenMetadata>,
+}
+
+/// Account approved to transfer a held token, EX: marketplace listing
+#[derive(BorshDeserialize, BorshSerialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NonFungibleTokenApproval {
+    pub token_id: String,
+    pub account_id: AccountId,
+    pub msg: Option<String>,
+    pub block_timestamp: U64,
+}
+
+/// Kinds of non-fungible token movements kept in history
+#[derive(BorshDeserialize, BorshSerialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub enum NonFungibleTokenRecordKind {
+    Deposit,
+    Transfer,
+}
+
+/// History entry of a non-fungible token movement
+#[derive(BorshDeserialize, BorshSerialize, Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NonFungibleTokenRecord {
+    pub kind: NonFungibleTokenRecordKind,
+    pub nft_contract_id: AccountId,
+    pub token_id: String,
+    /// The sender for deposits, the receiver for transfers
+    pub account_id: AccountId,
+    /// Owner before the deposit, differs from the sender when sent by an approved account
+    pub previous_owner_id: Option<AccountId>,
+    pub msg: Option<String>,
+    pub block_timestamp: U64,
 }
 
 // TODO:
-// * receive?
 // * mint?
 // * storage deposit???
 
+/// Key for the token metadata cache
+fn nft_metadata_key(nft_account_id: &AccountId, token_id: &str) -> String {
+    format!("{}:{}", nft_account_id, token_id)
+}
+
 #[near_bindgen]
 impl Contract {
     /// Supported Fungible Tokens
@@ -81,13 +137,122 @@ impl Contract {
 
         token_ids
             .iter()
-            .map(|id| NonFungibleToken {
-                id: id.to_string(),
-                owner_id: env::current_account_id().to_string(),
+            .map(|id| {
+                let metadata = self.nft_metadata.get(&nft_metadata_key(&account_id, id));
+                NonFungibleToken {
+                    id: id.to_string(),
+                    owner_id: env::current_account_id().to_string(),
+                    title: metadata.as_ref().and_then(|m| m.title.clone()),
+                    media: metadata.as_ref().and_then(|m| m.media.clone()),
+                    metadata,
+                }
             })
             .collect()
     }
 
+    /// Outgoing tokens awaiting transfer confirmation
+    ///
+    /// ```bash
+    /// near view treasury.testnet get_nft_pending '{"nft_account_id": "image.testnet"}'
+    /// ```
+    pub fn get_nft_pending(&self, nft_account_id: AccountId) -> Vec<String> {
+        self.nft_pending.get(&nft_account_id).unwrap_or_default()
+    }
+
+    /// Active approvals for held tokens of an NFT contract
+    ///
+    /// ```bash
+    /// near view treasury.testnet get_nft_approvals '{"nft_account_id": "image.testnet"}'
+    /// ```
+    pub fn get_nft_approvals(&self, nft_account_id: AccountId) -> Vec<NonFungibleTokenApproval> {
+        self.nft_approvals.get(&nft_account_id).unwrap_or_default()
+    }
+
+    /// Non-Fungible Token History, oldest first
+    /// NOTE: Only the latest 1000 records are kept
+    ///
+    /// ```bash
+    /// near view treasury.testnet get_nft_history '{"from_index": 0, "limit": 10}'
+    /// ```
+    pub fn get_nft_history(
+        &self,
+        from_index: Option<U64>,
+        limit: Option<U64>,
+    ) -> Vec<NonFungibleTokenRecord> {
+        self.nft_history.page(
+            from_index.map(|i| i.0).unwrap_or(0),
+            limit.map(|l| l.0).unwrap_or(10),
+        )
+    }
+
+    /// NFT contracts accepted by `nft_on_transfer`
+    /// NOTE: Empty means any contract not on the blocklist is accepted
+    ///
+    /// ```bash
+    /// near view treasury.testnet get_nft_allowlist
+    /// ```
+    pub fn get_nft_allowlist(&self) -> Vec<AccountId> {
+        self.nft_allowlist.to_vec()
+    }
+
+    /// NFT contracts rejected by `nft_on_transfer`
+    ///
+    /// ```bash
+    /// near view treasury.testnet get_nft_blocklist
+    /// ```
+    pub fn get_nft_blocklist(&self) -> Vec<AccountId> {
+        self.nft_blocklist.to_vec()
+    }
+
+    /// Manage accepted NFT contracts
+    /// NOTE: Once any are specified, only allowlisted contracts can be deposited, so unknown contracts cannot fill the treasury storage
+    ///
+    /// ```bash
+    /// near call treasury.testnet add_nft_allowlist '{"nft_account_id": "image.testnet"}' --accountId treasury.testnet --depositYocto 1
+    /// ```
+    #[payable]
+    pub fn add_nft_allowlist(&mut self, nft_account_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.nft_allowlist.insert(&nft_account_id);
+    }
+
+    /// Remove an accepted NFT contract
+    ///
+    /// ```bash
+    /// near call treasury.testnet remove_nft_allowlist '{"nft_account_id": "image.testnet"}' --accountId treasury.testnet --depositYocto 1
+    /// ```
+    #[payable]
+    pub fn remove_nft_allowlist(&mut self, nft_account_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.nft_allowlist.remove(&nft_account_id);
+    }
+
+    /// Manage rejected NFT contracts, to keep spam out of holdings
+    ///
+    /// ```bash
+    /// near call treasury.testnet add_nft_blocklist '{"nft_account_id": "spam.testnet"}' --accountId treasury.testnet --depositYocto 1
+    /// ```
+    #[payable]
+    pub fn add_nft_blocklist(&mut self, nft_account_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.nft_blocklist.insert(&nft_account_id);
+    }
+
+    /// Remove a rejected NFT contract
+    ///
+    /// ```bash
+    /// near call treasury.testnet remove_nft_blocklist '{"nft_account_id": "spam.testnet"}' --accountId treasury.testnet --depositYocto 1
+    /// ```
+    #[payable]
+    pub fn remove_nft_blocklist(&mut self, nft_account_id: AccountId) {
+        assert_one_yocto();
+        self.assert_owner();
+        self.nft_blocklist.remove(&nft_account_id);
+    }
+
     /// Transfer Non-Fungible Token
     /// NOTE: Assumes storage deposit has occurred for recipient
     ///
@@ -99,40 +264,991 @@ impl Contract {
         nft_account_id: AccountId,
         to_token_id: String,
         to_account_id: AccountId,
-    ) {
-        assert_eq!(
-            self.owner_id,
-            env::predecessor_account_id(),
-            "Must be owner"
-        );
+    ) -> Promise {
+        self.assert_owner();
+        self.internal_nft_transfer(nft_account_id, to_token_id, to_account_id, None)
+    }
 
-        // Check if treasury holds the ft, and has enough balance
-        let mut tokens = self
+    /// Transfer Non-Fungible Token into a contract, EX: NFT staking or marketplace
+    /// NOTE: Token stays in holdings if the receiver returns it
+    ///
+    /// ```bash
+    /// near call treasury.testnet nft_transfer_call '{"nft_account_id": "image.testnet", "to_account_id": "market.testnet", "to_token_id": "1", "msg": "{\"price\":\"1000000000000000000000000\"}"}' --accountId treasury.testnet --gas 100000000000000
+    /// ```
+    pub fn nft_transfer_call(
+        &mut self,
+        nft_account_id: AccountId,
+        to_token_id: String,
+        to_account_id: AccountId,
+        msg: String,
+    ) -> Promise {
+        self.assert_owner();
+        self.internal_nft_transfer(nft_account_id, to_token_id, to_account_id, Some(msg))
+    }
+
+    /// Reconcile holdings with the NFT contract enumeration (NEP-181), caching token metadata
+    /// NOTE: A from_index of 0 (default) starts a sync, each page gets merged into holdings
+    /// NOTE: Tokens no longer owned are only dropped after the last page, the first one shorter than `limit`
+    ///
+    /// ```bash
+    /// near call treasury.testnet sync_nft_holdings '{"nft_account_id": "image.testnet", "from_index": "0", "limit": 50}' --accountId treasury.testnet --gas 100000000000000
+    /// ```
+    pub fn sync_nft_holdings(
+        &mut self,
+        nft_account_id: AccountId,
+        from_index: Option<U128>,
+        limit: Option<u64>,
+    ) -> Promise {
+        self.assert_owner_or_croncat();
+        let from_index = from_index.unwrap_or(U128::from(0));
+
+        Promise::new(nft_account_id.clone())
+            .function_call(
+                "nft_tokens_for_owner".to_string(),
+                json!({
+                    "account_id": env::current_account_id(),
+                    "from_index": from_index,
+                    "limit": limit,
+                })
+                .to_string()
+                .into_bytes(),
+                NO_DEPOSIT,
+                GAS_NFT_TOKENS_FOR_OWNER,
+            )
+            .then(
+                Promise::new(env::current_account_id()).function_call(
+                    "sync_nft_holdings_callback".to_string(),
+                    json!({
+                        "nft_account_id": nft_account_id,
+                        "from_index": from_index,
+                        "limit": limit,
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NO_DEPOSIT,
+                    GAS_NFT_SYNC_CALLBACK,
+                ),
+            )
+    }
+
+    /// CALLBACK for sync_nft_holdings, returns the amount of tokens synced
+    #[private]
+    pub fn sync_nft_holdings_callback(
+        &mut self,
+        nft_account_id: AccountId,
+        from_index: U128,
+        limit: Option<u64>,
+    ) -> u64 {
+        let tokens: Vec<Token> = match env::promise_result(0) {
+            PromiseResult::Successful(result) => {
+                serde_json::de::from_slice(&result).expect("Could not parse tokens")
+            }
+            _ => return 0,
+        };
+
+        // Tokens in flight are still owned on the NFT contract, but are tracked as pending
+        let pending = self.nft_pending.get(&nft_account_id).unwrap_or_default();
+        let mut holdings = self.nft_holdings.get(&nft_account_id).unwrap_or_default();
+        let mut synced = if from_index.0 == 0 {
+            Vec::new()
+        } else {
+            self.nft_syncing.get(&nft_account_id).unwrap_or_default()
+        };
+
+        for token in tokens.iter() {
+            if token.owner_id != env::current_account_id() || pending.contains(&token.token_id) {
+                continue;
+            }
+            if !holdings.contains(&token.token_id) {
+                holdings.push(token.token_id.clone());
+            }
+            if !synced.contains(&token.token_id) {
+                synced.push(token.token_id.clone());
+            }
+            if let Some(metadata) = &token.metadata {
+                self.nft_metadata.insert(
+                    &nft_metadata_key(&nft_account_id, &token.token_id),
+                    metadata,
+                );
+            }
+        }
+
+        // Last page, drop tokens (& their cached metadata) that did not show up in any page
+        if limit.map_or(true, |limit| (tokens.len() as u64) < limit) {
+            for token_id in holdings.iter() {
+                if !synced.contains(token_id) {
+                    self.nft_metadata
+                        .remove(&nft_metadata_key(&nft_account_id, token_id));
+                }
+            }
+            holdings.retain(|token_id| synced.contains(token_id));
+            self.nft_syncing.remove(&nft_account_id);
+        } else {
+            self.nft_syncing.insert(&nft_account_id, &synced);
+        }
+
+        self.nft_holdings.insert(&nft_account_id, &holdings);
+        tokens.len() as u64
+    }
+
+    /// Approve an account to transfer a held token, EX: list on a marketplace
+    /// NOTE: With `msg`, the NFT contract notifies the approved account via `nft_on_approve`
+    ///
+    /// ```bash
+    /// near call treasury.testnet nft_approve_for '{"nft_account_id": "image.testnet", "token_id": "1", "account_id": "market.testnet", "msg": "{\"sale_conditions\":\"1000000000000000000000000\"}"}' --accountId treasury.testnet --gas 100000000000000
+    /// ```
+    pub fn nft_approve_for(
+        &mut self,
+        nft_account_id: AccountId,
+        token_id: String,
+        account_id: AccountId,
+        msg: Option<String>,
+    ) -> Promise {
+        self.assert_owner();
+        let tokens = self
             .nft_holdings
             .get(&nft_account_id)
             .expect("NFT Account not found");
-        assert!(tokens.contains(&to_token_id), "NFT Token ID not found");
+        assert!(tokens.contains(&token_id), "NFT Token ID not found");
+        assert!(
+            env::account_balance().saturating_sub(NFT_APPROVAL_DEPOSIT) > MIN_BALANCE_FOR_STORAGE,
+            "Account Balance Under Minimum Balance"
+        );
+
+        let gas = if msg.is_some() {
+            GAS_NFT_APPROVE_CALL
+        } else {
+            GAS_NFT_APPROVE
+        };
+
+        Promise::new(nft_account_id.clone())
+            .function_call(
+                "nft_approve".to_string(),
+                json!({
+                    "token_id": token_id,
+                    "account_id": account_id,
+                    "msg": msg,
+                })
+                .to_string()
+                .into_bytes(),
+                NFT_APPROVAL_DEPOSIT,
+                gas,
+            )
+            .then(self.internal_nft_approval_callback(
+                nft_account_id,
+                token_id,
+                Some(account_id),
+                msg,
+                true,
+            ))
+    }
+
+    /// Revoke an account's approval for a held token, EX: delist from a marketplace
+    ///
+    /// ```bash
+    /// near call treasury.testnet nft_revoke_for '{"nft_account_id": "image.testnet", "token_id": "1", "account_id": "market.testnet"}' --accountId treasury.testnet
+    /// ```
+    pub fn nft_revoke_for(
+        &mut self,
+        nft_account_id: AccountId,
+        token_id: String,
+        account_id: AccountId,
+    ) -> Promise {
+        self.assert_owner();
+        Promise::new(nft_account_id.clone())
+            .function_call(
+                "nft_revoke".to_string(),
+                json!({
+                    "token_id": token_id,
+                    "account_id": account_id,
+                })
+                .to_string()
+                .into_bytes(),
+                ONE_YOCTO,
+                GAS_NFT_REVOKE,
+            )
+            .then(self.internal_nft_approval_callback(
+                nft_account_id,
+                token_id,
+                Some(account_id),
+                None,
+                false,
+            ))
+    }
 
-        // NOTE: Lame logic here, should be changed to callback
-        let index = tokens.iter().position(|x| x == &to_token_id);
-        if let Some(index) = index {
-            tokens.remove(index);
-            self.nft_holdings.insert(&nft_account_id, &tokens);
+    /// Revoke every approval for a held token
+    ///
+    /// ```bash
+    /// near call treasury.testnet nft_revoke_all_for '{"nft_account_id": "image.testnet", "token_id": "1"}' --accountId treasury.testnet
+    /// ```
+    pub fn nft_revoke_all_for(&mut self, nft_account_id: AccountId, token_id: String) -> Promise {
+        self.assert_owner();
+        Promise::new(nft_account_id.clone())
+            .function_call(
+                "nft_revoke_all".to_string(),
+                json!({
+                    "token_id": token_id,
+                })
+                .to_string()
+                .into_bytes(),
+                ONE_YOCTO,
+                GAS_NFT_REVOKE,
+            )
+            .then(self.internal_nft_approval_callback(nft_account_id, token_id, None, None, false))
+    }
+
+    /// CALLBACK for nft_approve_for, nft_revoke_for & nft_revoke_all_for
+    /// Only updates the recorded approvals once the NFT contract confirmed the change
+    /// NOTE: With `msg`, a failing `nft_on_approve` fails the promise even though the approval went through, so it gets checked with `nft_is_approved`
+    #[private]
+    pub fn nft_approval_callback(
+        &mut self,
+        nft_account_id: AccountId,
+        token_id: String,
+        account_id: Option<AccountId>,
+        msg: Option<String>,
+        approved: bool,
+    ) -> PromiseOrValue<bool> {
+        if !is_promise_success() {
+            if !approved || msg.is_none() {
+                return PromiseOrValue::Value(false);
+            }
+            return Promise::new(nft_account_id.clone())
+                .function_call(
+                    "nft_is_approved".to_string(),
+                    json!({
+                        "token_id": token_id,
+                        "approved_account_id": account_id,
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NO_DEPOSIT,
+                    GAS_NFT_IS_APPROVED,
+                )
+                .then(
+                    Promise::new(env::current_account_id()).function_call(
+                        "nft_is_approved_callback".to_string(),
+                        json!({
+                            "nft_account_id": nft_account_id,
+                            "token_id": token_id,
+                            "account_id": account_id,
+                            "msg": msg,
+                        })
+                        .to_string()
+                        .into_bytes(),
+                        NO_DEPOSIT,
+                        GAS_NFT_APPROVAL_CALLBACK,
+                    ),
+                )
+                .into();
         }
 
-        let p = env::promise_create(
-            nft_account_id,
-            "nft_transfer",
+        if approved {
+            let account_id = account_id.expect("Approval account required");
+            self.internal_nft_record_approval(nft_account_id, token_id, account_id, msg);
+        } else {
+            self.internal_nft_clear_approvals(&nft_account_id, &token_id, account_id.as_ref());
+            events::NftApprovalUpdate {
+                nft_contract_id: nft_account_id,
+                token_id,
+                account_id,
+                approved,
+            }
+            .emit();
+        }
+        PromiseOrValue::Value(true)
+    }
+
+    /// CALLBACK for nft_is_approved, records the approval if it went through despite `nft_on_approve` failing
+    #[private]
+    pub fn nft_is_approved_callback(
+        &mut self,
+        nft_account_id: AccountId,
+        token_id: String,
+        account_id: AccountId,
+        msg: Option<String>,
+    ) -> bool {
+        let is_approved = match env::promise_result(0) {
+            PromiseResult::Successful(result) => {
+                serde_json::de::from_slice::<bool>(&result).unwrap_or(false)
+            }
+            _ => false,
+        };
+        if is_approved {
+            self.internal_nft_record_approval(nft_account_id, token_id, account_id, msg);
+        }
+        is_approved
+    }
+
+    /// CALLBACK for any outgoing nft_transfer or nft_transfer_call
+    /// Confirms the pending token left the treasury, otherwise puts it back in holdings
+    #[private]
+    pub fn nft_transfer_callback(
+        &mut self,
+        nft_account_id: AccountId,
+        token_id: String,
+        receiver_id: AccountId,
+        msg: Option<String>,
+    ) -> bool {
+        // nft_transfer_call resolves to whether the receiver kept the token
+        let transferred = match env::promise_result(0) {
+            PromiseResult::Successful(result) => {
+                msg.is_none() || serde_json::de::from_slice::<bool>(&result).unwrap_or(true)
+            }
+            _ => false,
+        };
+
+        let mut pending = self.nft_pending.get(&nft_account_id).unwrap_or_default();
+        if let Some(index) = pending.iter().position(|x| x == &token_id) {
+            pending.remove(index);
+        }
+        if pending.is_empty() {
+            self.nft_pending.remove(&nft_account_id);
+        } else {
+            self.nft_pending.insert(&nft_account_id, &pending);
+        }
+
+        if transferred {
+            // Approvals do not survive a transfer
+            self.internal_nft_clear_approvals(&nft_account_id, &token_id, None);
+            self.nft_metadata
+                .remove(&nft_metadata_key(&nft_account_id, &token_id));
+            self.nft_history.push(&NonFungibleTokenRecord {
+                kind: NonFungibleTokenRecordKind::Transfer,
+                nft_contract_id: nft_account_id,
+                token_id,
+                account_id: receiver_id,
+                previous_owner_id: None,
+                msg,
+                block_timestamp: U64::from(env::block_timestamp()),
+            });
+            return true;
+        }
+
+        // Transfer failed or the token was returned, put it back
+        let mut tokens = self.nft_holdings.get(&nft_account_id).unwrap_or_default();
+        if !tokens.contains(&token_id) {
+            tokens.push(token_id.clone());
+        }
+        self.nft_holdings.insert(&nft_account_id, &tokens);
+        self.internal_nft_sync_keep(&nft_account_id, &token_id);
+        events::NftTransferFailure {
+            nft_contract_id: nft_account_id,
+            token_id,
+            receiver_id,
+        }
+        .emit();
+        false
+    }
+}
+
+impl Contract {
+    fn internal_nft_approval_callback(
+        &self,
+        nft_account_id: AccountId,
+        token_id: String,
+        account_id: Option<AccountId>,
+        msg: Option<String>,
+        approved: bool,
+    ) -> Promise {
+        // Approvals with msg may need to check nft_is_approved
+        let gas = if approved && msg.is_some() {
+            GAS_NFT_APPROVAL_CALLBACK + GAS_NFT_IS_APPROVED + GAS_NFT_APPROVAL_CALLBACK
+        } else {
+            GAS_NFT_APPROVAL_CALLBACK
+        };
+        Promise::new(env::current_account_id()).function_call(
+            "nft_approval_callback".to_string(),
             json!({
-                "receiver_id": to_account_id,
-                "token_id": to_token_id,
+                "nft_account_id": nft_account_id,
+                "token_id": token_id,
+                "account_id": account_id,
+                "msg": msg,
+                "approved": approved,
             })
             .to_string()
-            .as_bytes(),
-            ONE_YOCTO,
-            GAS_NFT_TRANSFER,
+            .into_bytes(),
+            NO_DEPOSIT,
+            gas,
+        )
+    }
+
+    /// Records an approval confirmed by the NFT contract, replacing any previous one for the same account
+    fn internal_nft_record_approval(
+        &mut self,
+        nft_account_id: AccountId,
+        token_id: String,
+        account_id: AccountId,
+        msg: Option<String>,
+    ) {
+        let mut approvals = self.nft_approvals.get(&nft_account_id).unwrap_or_default();
+        approvals.retain(|a| !(a.token_id == token_id && a.account_id == account_id));
+        approvals.push(NonFungibleTokenApproval {
+            token_id: token_id.clone(),
+            account_id: account_id.clone(),
+            msg,
+            block_timestamp: U64::from(env::block_timestamp()),
+        });
+        self.nft_approvals.insert(&nft_account_id, &approvals);
+
+        events::NftApprovalUpdate {
+            nft_contract_id: nft_account_id,
+            token_id,
+            account_id: Some(account_id),
+            approved: true,
+        }
+        .emit();
+    }
+
+    /// Keeps a sync in progress from dropping a token added to holdings, its page may be synced already
+    pub(crate) fn internal_nft_sync_keep(&mut self, nft_account_id: &AccountId, token_id: &str) {
+        if let Some(mut synced) = self.nft_syncing.get(nft_account_id) {
+            if !synced.iter().any(|id| id == token_id) {
+                synced.push(token_id.to_string());
+                self.nft_syncing.insert(nft_account_id, &synced);
+            }
+        }
+    }
+
+    /// Drops recorded approvals for a token, only for `account_id` if specified
+    pub(crate) fn internal_nft_clear_approvals(
+        &mut self,
+        nft_account_id: &AccountId,
+        token_id: &str,
+        account_id: Option<&AccountId>,
+    ) {
+        let mut approvals = match self.nft_approvals.get(nft_account_id) {
+            Some(approvals) => approvals,
+            None => return,
+        };
+        approvals.retain(|a| {
+            a.token_id != token_id || account_id.map_or(false, |id| &a.account_id != id)
+        });
+        if approvals.is_empty() {
+            self.nft_approvals.remove(nft_account_id);
+        } else {
+            self.nft_approvals.insert(nft_account_id, &approvals);
+        }
+    }
+
+    /// Moves the token from holdings into pending, then transfers it & confirms via callback
+    pub(crate) fn internal_nft_transfer(
+        &mut self,
+        nft_account_id: AccountId,
+        token_id: String,
+        receiver_id: AccountId,
+        msg: Option<String>,
+    ) -> Promise {
+        // Check if treasury holds the nft
+        let mut tokens = self
+            .nft_holdings
+            .get(&nft_account_id)
+            .expect("NFT Account not found");
+        let index = tokens
+            .iter()
+            .position(|x| x == &token_id)
+            .expect("NFT Token ID not found");
+        tokens.remove(index);
+        self.nft_holdings.insert(&nft_account_id, &tokens);
+
+        let mut pending = self.nft_pending.get(&nft_account_id).unwrap_or_default();
+        pending.push(token_id.clone());
+        self.nft_pending.insert(&nft_account_id, &pending);
+
+        events::NftTransfer {
+            nft_contract_id: nft_account_id.clone(),
+            token_id: token_id.clone(),
+            receiver_id: receiver_id.clone(),
+        }
+        .emit();
+
+        let transfer = if let Some(msg) = msg.clone() {
+            Promise::new(nft_account_id.clone()).function_call(
+                "nft_transfer_call".to_string(),
+                json!({
+                    "receiver_id": receiver_id,
+                    "token_id": token_id,
+                    "msg": msg,
+                })
+                .to_string()
+                .into_bytes(),
+                ONE_YOCTO,
+                GAS_NFT_TRANSFER_CALL,
+            )
+        } else {
+            Promise::new(nft_account_id.clone()).function_call(
+                "nft_transfer".to_string(),
+                json!({
+                    "receiver_id": receiver_id,
+                    "token_id": token_id,
+                })
+                .to_string()
+                .into_bytes(),
+                ONE_YOCTO,
+                GAS_NFT_TRANSFER,
+            )
+        };
+
+        transfer.then(
+            Promise::new(env::current_account_id()).function_call(
+                "nft_transfer_callback".to_string(),
+                json!({
+                    "nft_account_id": nft_account_id,
+                    "token_id": token_id,
+                    "receiver_id": receiver_id,
+                    "msg": msg,
+                })
+                .to_string()
+                .into_bytes(),
+                NO_DEPOSIT,
+                GAS_NFT_TRANSFER_CALLBACK,
+            ),
+        )
+    }
+}
+
+#[near_bindgen]
+impl NonFungibleTokenReceiver for Contract {
+    /// Automatically track incoming NFTs, called by the NFT contract upon `nft_transfer_call`
+    /// NOTE: Tokens from contracts on the blocklist get returned
+    /// NOTE: If the allowlist has any contracts, tokens from all other contracts get returned too
+    ///
+    /// ```bash
+    /// near call image.testnet nft_transfer_call '{"receiver_id": "treasury.testnet", "token_id": "1", "msg": "donation"}' --accountId user.testnet --depositYocto 1 --gas 100000000000000
+    /// ```
+    fn nft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_id: AccountId,
+        token_id: String,
+        msg: String,
+    ) -> PromiseOrValue<bool> {
+        let nft_account_id = env::predecessor_account_id();
+        let allowed = self.nft_allowlist.is_empty() || self.nft_allowlist.contains(&nft_account_id);
+        let rejected = !allowed || self.nft_blocklist.contains(&nft_account_id);
+
+        events::NftDeposit {
+            nft_contract_id: nft_account_id.clone(),
+            token_id: token_id.clone(),
+            sender_id: sender_id.clone(),
+            rejected,
+        }
+        .emit();
+        if rejected {
+            return PromiseOrValue::Value(true);
+        }
+
+        // Add to holdings, then keep record of who sent it
+        let mut tokens = self.nft_holdings.get(&nft_account_id).unwrap_or_default();
+        if !tokens.contains(&token_id) {
+            tokens.push(token_id.clone());
+        }
+        self.nft_holdings.insert(&nft_account_id, &tokens);
+        self.internal_nft_sync_keep(&nft_account_id, &token_id);
+        self.nft_history.push(&NonFungibleTokenRecord {
+            kind: NonFungibleTokenRecordKind::Deposit,
+            nft_contract_id: nft_account_id,
+            token_id,
+            account_id: sender_id,
+            previous_owner_id: Some(previous_owner_id),
+            msg: if msg.is_empty() {
+                None
+            } else {
+                Some(utils::truncate_string(msg, NFT_HISTORY_MSG_MAX_LEN))
+            },
+            block_timestamp: U64::from(env::block_timestamp()),
+        });
+
+        PromiseOrValue::Value(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    fn deposit(
+        contract: &mut Contract,
+        nft_account_id: AccountId,
+        token_id: &str,
+        msg: &str,
+    ) -> bool {
+        testing_env!(get_context(nft_account_id).build());
+        match contract.nft_on_transfer(
+            accounts(2),
+            accounts(3),
+            token_id.to_string(),
+            msg.to_string(),
+        ) {
+            PromiseOrValue::Value(returned) => returned,
+            PromiseOrValue::Promise(_) => panic!("Expected a value"),
+        }
+    }
+
+    fn set_owner_deposit() {
+        testing_env!(get_context(accounts(1)).attached_deposit(1).build());
+    }
+
+    fn hold(contract: &mut Contract, nft_account_id: &AccountId, token_ids: &[&str]) {
+        let tokens: Vec<String> = token_ids.iter().map(|id| id.to_string()).collect();
+        contract.nft_holdings.insert(nft_account_id, &tokens);
+    }
+
+    fn transfer(contract: &mut Contract, msg: Option<&str>, result: PromiseResult) -> bool {
+        hold(contract, &accounts(4), &["1", "2"]);
+        testing_env!(get_context(accounts(1)).build());
+        match msg {
+            Some(msg) => contract.nft_transfer_call(
+                accounts(4),
+                "1".to_string(),
+                accounts(2),
+                msg.to_string(),
+            ),
+            None => contract.nft_transfer(accounts(4), "1".to_string(), accounts(2)),
+        };
+        assert_eq!(
+            contract.nft_holdings.get(&accounts(4)),
+            Some(vec!["2".to_string()])
         );
+        assert_eq!(contract.get_nft_pending(accounts(4)), vec!["1".to_string()]);
+        assert_eq!(get_events("nft_transfer")[0]["token_id"], "1");
 
-        env::promise_return(p);
+        set_callback_context(&mut get_context(accounts(0)), vec![result]);
+        contract.nft_transfer_callback(
+            accounts(4),
+            "1".to_string(),
+            accounts(2),
+            msg.map(|msg| msg.to_string()),
+        )
+    }
+
+    #[test]
+    fn test_nft_transfer_confirmed() {
+        let mut contract = setup_contract();
+        assert!(transfer(
+            &mut contract,
+            None,
+            PromiseResult::Successful(vec![])
+        ));
+        assert_eq!(
+            contract.nft_holdings.get(&accounts(4)),
+            Some(vec!["2".to_string()])
+        );
+        assert!(contract.get_nft_pending(accounts(4)).is_empty());
+        let history = contract.get_nft_history(None, None);
+        assert!(matches!(
+            history[0].kind,
+            NonFungibleTokenRecordKind::Transfer
+        ));
+        assert_eq!(history[0].account_id, accounts(2));
+    }
+
+    #[test]
+    fn test_nft_transfer_failed() {
+        let mut contract = setup_contract();
+        assert!(!transfer(&mut contract, None, PromiseResult::Failed));
+        assert_eq!(
+            contract.nft_holdings.get(&accounts(4)),
+            Some(vec!["2".to_string(), "1".to_string()])
+        );
+        assert!(contract.get_nft_pending(accounts(4)).is_empty());
+        assert!(contract.get_nft_history(None, None).is_empty());
+        let events = get_events("nft_transfer_failure");
+        assert_eq!(events[0]["token_id"], "1");
+        assert_eq!(events[0]["receiver_id"], accounts(2).to_string());
+    }
+
+    #[test]
+    fn test_nft_transfer_call_kept() {
+        let mut contract = setup_contract();
+        assert!(transfer(&mut contract, Some("list"), json_result(&true)));
+        assert_eq!(
+            contract.nft_holdings.get(&accounts(4)),
+            Some(vec!["2".to_string()])
+        );
+        let history = contract.get_nft_history(None, None);
+        assert_eq!(history[0].msg, Some("list".to_string()));
+    }
+
+    #[test]
+    fn test_nft_transfer_call_returned() {
+        let mut contract = setup_contract();
+        assert!(!transfer(&mut contract, Some("list"), json_result(&false)));
+        assert_eq!(
+            contract.nft_holdings.get(&accounts(4)),
+            Some(vec!["2".to_string(), "1".to_string()])
+        );
+        assert!(contract.get_nft_pending(accounts(4)).is_empty());
+        assert_eq!(get_events("nft_transfer_failure").len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "NFT Token ID not found")]
+    fn test_nft_transfer_not_held() {
+        let mut contract = setup_contract();
+        hold(&mut contract, &accounts(4), &["2"]);
+        contract.nft_transfer(accounts(4), "1".to_string(), accounts(2));
+    }
+
+    fn approval_callback(
+        contract: &mut Contract,
+        account_id: Option<AccountId>,
+        msg: Option<&str>,
+        approved: bool,
+        result: PromiseResult,
+    ) -> PromiseOrValue<bool> {
+        set_callback_context(&mut get_context(accounts(0)), vec![result]);
+        contract.nft_approval_callback(
+            accounts(4),
+            "1".to_string(),
+            account_id,
+            msg.map(|msg| msg.to_string()),
+            approved,
+        )
+    }
+
+    fn approved_accounts(contract: &Contract) -> Vec<AccountId> {
+        contract
+            .get_nft_approvals(accounts(4))
+            .into_iter()
+            .map(|approval| approval.account_id)
+            .collect()
+    }
+
+    #[test]
+    fn test_nft_approvals() {
+        let mut contract = setup_contract();
+        hold(&mut contract, &accounts(4), &["1"]);
+        testing_env!(get_context(accounts(1)).build());
+        contract.nft_approve_for(accounts(4), "1".to_string(), accounts(2), None);
+        // Nothing is recorded until the NFT contract confirms
+        assert!(approved_accounts(&contract).is_empty());
+
+        for account_id in [accounts(2), accounts(3), accounts(2)] {
+            let result = PromiseResult::Successful(vec![]);
+            assert!(matches!(
+                approval_callback(&mut contract, Some(account_id), None, true, result),
+                PromiseOrValue::Value(true)
+            ));
+        }
+        assert_eq!(approved_accounts(&contract), vec![accounts(3), accounts(2)]);
+        assert_eq!(get_events("nft_approval_update")[0]["approved"], true);
+
+        let result = PromiseResult::Successful(vec![]);
+        approval_callback(&mut contract, Some(accounts(3)), None, false, result);
+        assert_eq!(approved_accounts(&contract), vec![accounts(2)]);
+        assert_eq!(get_events("nft_approval_update")[0]["approved"], false);
+
+        // Approvals do not survive a transfer
+        assert!(transfer(
+            &mut contract,
+            None,
+            PromiseResult::Successful(vec![])
+        ));
+        assert!(approved_accounts(&contract).is_empty());
+    }
+
+    #[test]
+    fn test_nft_approval_failed() {
+        let mut contract = setup_contract();
+        assert!(matches!(
+            approval_callback(
+                &mut contract,
+                Some(accounts(2)),
+                None,
+                true,
+                PromiseResult::Failed
+            ),
+            PromiseOrValue::Value(false)
+        ));
+        assert!(approved_accounts(&contract).is_empty());
+
+        // A failing nft_on_approve still leaves the approval on the NFT contract, so it gets checked
+        let result = PromiseResult::Failed;
+        assert!(matches!(
+            approval_callback(&mut contract, Some(accounts(2)), Some("list"), true, result),
+            PromiseOrValue::Promise(_)
+        ));
+        set_callback_context(&mut get_context(accounts(0)), vec![json_result(&true)]);
+        assert!(contract.nft_is_approved_callback(
+            accounts(4),
+            "1".to_string(),
+            accounts(2),
+            Some("list".to_string())
+        ));
+        assert_eq!(approved_accounts(&contract), vec![accounts(2)]);
+
+        set_callback_context(&mut get_context(accounts(0)), vec![json_result(&false)]);
+        assert!(!contract.nft_is_approved_callback(
+            accounts(4),
+            "1".to_string(),
+            accounts(3),
+            None
+        ));
+        assert_eq!(approved_accounts(&contract), vec![accounts(2)]);
+    }
+
+    #[test]
+    fn test_nft_revoke_all() {
+        let mut contract = setup_contract();
+        for account_id in [accounts(2), accounts(3)] {
+            let result = PromiseResult::Successful(vec![]);
+            approval_callback(&mut contract, Some(account_id), None, true, result);
+        }
+        let result = PromiseResult::Successful(vec![]);
+        approval_callback(&mut contract, None, None, false, result);
+        assert!(approved_accounts(&contract).is_empty());
+        assert!(contract.nft_approvals.get(&accounts(4)).is_none());
+    }
+
+    fn token(token_id: &str, owner_id: AccountId) -> serde_json::Value {
+        json!({
+            "token_id": token_id,
+            "owner_id": owner_id,
+            "metadata": {
+                "title": format!("Token {}", token_id),
+                "media": null,
+            },
+            "approved_account_ids": {},
+        })
+    }
+
+    fn sync(
+        contract: &mut Contract,
+        tokens: Vec<serde_json::Value>,
+        from_index: u128,
+        limit: Option<u64>,
+    ) -> u64 {
+        set_callback_context(&mut get_context(accounts(0)), vec![json_result(&tokens)]);
+        contract.sync_nft_holdings_callback(accounts(4), U128::from(from_index), limit)
+    }
+
+    fn holdings(contract: &Contract) -> Vec<String> {
+        contract.nft_holdings.get(&accounts(4)).unwrap_or_default()
+    }
+
+    #[test]
+    fn test_sync_nft_holdings() {
+        let mut contract = setup_contract();
+        hold(&mut contract, &accounts(4), &["1", "2", "3"]);
+        contract
+            .nft_pending
+            .insert(&accounts(4), &vec!["5".to_string()]);
+        let tokens = vec![
+            token("1", accounts(0)),
+            token("3", accounts(0)),
+            token("4", accounts(0)),
+            token("5", accounts(0)),
+            token("6", accounts(2)),
+        ];
+
+        // Tokens in flight or owned by others are skipped, tokens no longer owned are dropped
+        assert_eq!(sync(&mut contract, tokens, 0, None), 5);
+        assert_eq!(holdings(&contract), vec!["1", "3", "4"]);
+        let tokens = contract.nft_tokens(accounts(4));
+        assert_eq!(tokens[0].title, Some("Token 1".to_string()));
+        assert_eq!(tokens[2].title, Some("Token 4".to_string()));
+        assert!(contract.nft_syncing.get(&accounts(4)).is_none());
+    }
+
+    #[test]
+    fn test_sync_nft_holdings_pages() {
+        let mut contract = setup_contract();
+        hold(&mut contract, &accounts(4), &["1", "2", "3"]);
+
+        // Full page, nothing gets dropped until the last one
+        let tokens = vec![token("1", accounts(0)), token("2", accounts(0))];
+        assert_eq!(sync(&mut contract, tokens, 0, Some(2)), 2);
+        assert_eq!(holdings(&contract), vec!["1", "2", "3"]);
+
+        // Deposited meanwhile, its page may be synced already
+        testing_env!(get_context(accounts(4)).build());
+        contract.nft_on_transfer(accounts(2), accounts(2), "7".to_string(), String::new());
+
+        let tokens = vec![token("4", accounts(0))];
+        assert_eq!(sync(&mut contract, tokens, 2, Some(2)), 1);
+        assert_eq!(holdings(&contract), vec!["1", "2", "7", "4"]);
+        assert!(contract.nft_syncing.get(&accounts(4)).is_none());
+    }
+
+    #[test]
+    fn test_sync_nft_holdings_failed() {
+        let mut contract = setup_contract();
+        hold(&mut contract, &accounts(4), &["1"]);
+        set_callback_context(&mut get_context(accounts(0)), vec![PromiseResult::Failed]);
+        assert_eq!(
+            contract.sync_nft_holdings_callback(accounts(4), U128::from(0), None),
+            0
+        );
+        assert_eq!(holdings(&contract), vec!["1"]);
+    }
+
+    #[test]
+    fn test_nft_on_transfer_accepts_any_contract() {
+        let mut contract = setup_contract();
+        assert!(!deposit(&mut contract, accounts(4), "1", "donation"));
+        assert!(!deposit(&mut contract, accounts(4), "1", ""));
+        assert_eq!(
+            contract.nft_holdings.get(&accounts(4)),
+            Some(vec!["1".to_string()])
+        );
+
+        let history = contract.get_nft_history(None, None);
+        assert_eq!(history.len(), 2);
+        assert!(matches!(
+            history[0].kind,
+            NonFungibleTokenRecordKind::Deposit
+        ));
+        assert_eq!(history[0].account_id, accounts(2));
+        assert_eq!(history[0].previous_owner_id, Some(accounts(3)));
+        assert_eq!(history[0].msg, Some("donation".to_string()));
+        assert_eq!(history[1].msg, None);
+        assert_eq!(get_events("nft_deposit")[0]["rejected"], false);
+    }
+
+    #[test]
+    fn test_nft_on_transfer_blocklist() {
+        let mut contract = setup_contract();
+        set_owner_deposit();
+        contract.add_nft_blocklist(accounts(4));
+
+        assert!(deposit(&mut contract, accounts(4), "1", ""));
+        assert_eq!(contract.nft_holdings.get(&accounts(4)), None);
+        assert!(contract.get_nft_history(None, None).is_empty());
+        assert_eq!(get_events("nft_deposit")[0]["rejected"], true);
+    }
+
+    #[test]
+    fn test_nft_on_transfer_allowlist() {
+        let mut contract = setup_contract();
+        set_owner_deposit();
+        contract.add_nft_allowlist(accounts(4));
+        contract.add_nft_allowlist(accounts(5));
+        contract.add_nft_blocklist(accounts(5));
+
+        // Only allowlisted contracts once there are any, the blocklist still wins
+        assert!(deposit(&mut contract, accounts(2), "1", ""));
+        assert!(deposit(&mut contract, accounts(5), "1", ""));
+        assert!(!deposit(&mut contract, accounts(4), "1", ""));
+        assert_eq!(contract.get_nft_list(), vec![accounts(4)]);
+
+        set_owner_deposit();
+        contract.remove_nft_allowlist(accounts(4));
+        contract.remove_nft_allowlist(accounts(5));
+        assert!(!deposit(&mut contract, accounts(2), "1", ""));
+    }
+
+    #[test]
+    fn test_nft_on_transfer_truncates_msg() {
+        let mut contract = setup_contract();
+        deposit(&mut contract, accounts(4), "1", &"a".repeat(300));
+        let history = contract.get_nft_history(None, None);
+        assert_eq!(
+            history[0].msg.as_ref().map(|msg| msg.len()),
+            Some(NFT_HISTORY_MSG_MAX_LEN)
+        );
     }
 }
@@ -10,6 +10,14 @@ impl Contract {
         );
     }
 
+    pub(crate) fn assert_owner_or_croncat(&self) {
+        assert!(
+            env::predecessor_account_id() == self.owner_id
+                || Some(env::predecessor_account_id()) == self.croncat_id,
+            "Not an approved caller"
+        );
+    }
+
     /// Changes core configurations
     /// Should only be updated by owner -- in best case DAO based :)
     pub fn update_settings(
@@ -17,8 +25,27 @@ impl Contract {
         owner_id: Option<AccountId>,
         croncat_id: Option<AccountId>,
         stake_threshold: Option<StakeThreshold>,
+        stake_refresh_period: Option<U64>,
+        stake_epoch_length: Option<U64>,
+        max_liquid_unstake_fee_bps: Option<U64>,
+        ft_balances_refresh_period: Option<U64>,
+        ft_storage_budget: Option<U128>,
+        wrap_id: Option<AccountId>,
+        wrap_near_float: Option<U128>,
     ) {
         self.assert_owner();
+        let event = events::SettingsUpdate {
+            owner_id: owner_id.clone(),
+            croncat_id: croncat_id.clone(),
+            stake_threshold: stake_threshold.clone(),
+            stake_refresh_period,
+            stake_epoch_length,
+            max_liquid_unstake_fee_bps,
+            ft_balances_refresh_period,
+            ft_storage_budget,
+            wrap_id: wrap_id.clone(),
+            wrap_near_float,
+        };
 
         // BE CAREFUL!
         if let Some(owner_id) = owner_id {
@@ -32,6 +59,41 @@ impl Contract {
         if let Some(stake_threshold) = stake_threshold {
             self.stake_threshold = stake_threshold;
         }
+        if let Some(stake_refresh_period) = stake_refresh_period {
+            self.stake_refresh_period = stake_refresh_period.0;
+        }
+        if let Some(stake_epoch_length) = stake_epoch_length {
+            assert_ne!(stake_epoch_length.0, 0, "Epoch length must be above 0");
+            self.stake_epoch_length = stake_epoch_length.0;
+        }
+        if let Some(max_liquid_unstake_fee_bps) = max_liquid_unstake_fee_bps {
+            assert!(
+                u128::from(max_liquid_unstake_fee_bps.0) <= staking::BPS_DENOMINATOR,
+                "Fee cannot be above 10000 bps"
+            );
+            self.max_liquid_unstake_fee_bps = max_liquid_unstake_fee_bps.0;
+        }
+
+        // Token Settings
+        if let Some(ft_balances_refresh_period) = ft_balances_refresh_period {
+            self.ft_balances_refresh_period = ft_balances_refresh_period.0;
+        }
+        if let Some(ft_storage_budget) = ft_storage_budget {
+            self.ft_storage_budget = ft_storage_budget.0;
+        }
+        if let Some(wrap_id) = wrap_id {
+            self.wrap_id = Some(wrap_id);
+        }
+        // Setting a float of 0 turns off the wNEAR top up
+        if let Some(wrap_near_float) = wrap_near_float {
+            self.wrap_near_float = if wrap_near_float.0 > 0 {
+                Some(wrap_near_float.0)
+            } else {
+                None
+            };
+        }
+
+        event.emit();
     }
 
     /// Manage payable account
@@ -49,6 +111,12 @@ impl Contract {
         // Insert ONLY if there isn't a record of this already
         assert!(account.is_none(), "Payable account exists already");
         self.approved_accounts_payable.insert(&account_id);
+
+        events::PayableAccountUpdate {
+            account_id,
+            approved: true,
+        }
+        .emit();
     }
 
     /// Remove a payable account
@@ -66,6 +134,12 @@ impl Contract {
         // Insert ONLY if there isn't a record of this already
         assert!(account.is_none(), "Payable account exists already");
         self.approved_accounts_payable.remove(&account_id);
+
+        events::PayableAccountUpdate {
+            account_id,
+            approved: false,
+        }
+        .emit();
     }
 
     /// Transfer funds away from treasury
@@ -86,6 +160,14 @@ impl Contract {
             );
         }
 
+        events::FtTransfer {
+            token_id: None,
+            receiver_id: receiver_id.clone(),
+            amount,
+            memo: None,
+        }
+        .emit();
+
         Promise::new(receiver_id).transfer(amount.0)
     }
 }
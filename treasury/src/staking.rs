@@ -1,43 +1,324 @@
 use crate::*;
 
-use near_sdk::BlockHeight;
+use near_sdk::{BlockHeight, PromiseIndex};
 
 /// Amount of blocks needed before withdraw is available
 pub const GAS_STAKE_DEPOSIT_AND_STAKE: Gas = Gas(70_000_000_000_000);
+pub const GAS_STAKE_DEPOSIT_AND_STAKE_CALLBACK: Gas = Gas(5_000_000_000_000);
 pub const GAS_STAKE_UNSTAKE: Gas = Gas(40_000_000_000_000);
 pub const GAS_STAKE_WITHDRAW_ALL: Gas = Gas(40_000_000_000_000);
+pub const GAS_STAKE_WITHDRAW_CALLBACK: Gas = Gas(10_000_000_000_000);
 pub const GAS_STAKE_GET_STAKE_BALANCE: Gas = Gas(10_000_000_000_000);
 pub const GAS_STAKE_GET_STAKE_BALANCE_CALLBACK: Gas = Gas(10_000_000_000_000);
+pub const GAS_STAKE_PING: Gas = Gas(15_000_000_000_000);
+pub const GAS_STAKE_REFRESH_CALLBACK: Gas = Gas(10_000_000_000_000);
+/// Gas per pool of a delegations refresh, the ping, balance lookup & its share of the callback
+pub const GAS_STAKE_REFRESH_POOL: Gas =
+    Gas(GAS_STAKE_PING.0 + GAS_STAKE_GET_STAKE_BALANCE.0 + GAS_STAKE_REFRESH_CALLBACK.0);
 pub const GAS_STAKE_LIQUID_UNSTAKE_VIEW: Gas = Gas(10_000_000_000_000);
 pub const GAS_STAKE_LIQUID_UNSTAKE_FEE_VIEW: Gas = Gas(10_000_000_000_000);
-pub const GAS_STAKE_LIQUID_UNSTAKE_CALLBACK: Gas = Gas(80_000_000_000_000);
-pub const GAS_STAKE_LIQUID_UNSTAKE_CALLBACK_FINAL: Gas = Gas(40_000_000_000_000);
+pub const GAS_STAKE_LIQUID_UNSTAKE_CALLBACK: Gas = Gas(100_000_000_000_000);
+pub const GAS_STAKE_LIQUID_UNSTAKE_CALLBACK_FINAL: Gas = Gas(70_000_000_000_000);
+pub const GAS_STAKE_LIQUID_UNSTAKE_CALLBACK_QUOTE: Gas = Gas(45_000_000_000_000);
 pub const GAS_STAKE_LIQUID_UNSTAKE_POOL_CALL: Gas = Gas(30_000_000_000_000);
+pub const GAS_STAKE_LIQUID_UNSTAKE_POOL_CALLBACK: Gas = Gas(5_000_000_000_000);
 pub const GAS_YIELD_HARVEST: Gas = Gas(120_000_000_000_000);
 pub const GAS_CRONCAT_CREATE_TASK: Gas = Gas(30_000_000_000_000);
+pub const GAS_CRONCAT_CREATE_TASK_CALLBACK: Gas = Gas(5_000_000_000_000);
+pub const GAS_CRONCAT_REMOVE_TASK: Gas = Gas(10_000_000_000_000);
+pub const GAS_CRONCAT_REFILL_BALANCE: Gas = Gas(10_000_000_000_000);
+/// Cancels the new task if its tranches were moved to a later unlock while it was being created
+pub const GAS_CRONCAT_WITHDRAW_TASK_CALLBACK: Gas =
+    Gas(GAS_CRONCAT_CREATE_TASK_CALLBACK.0 + GAS_CRONCAT_REMOVE_TASK.0);
+/// Covers rescheduling the withdraw of a shortfall
+pub const GAS_STAKE_WITHDRAW_RECONCILE_CALLBACK: Gas = Gas(GAS_STAKE_WITHDRAW_CALLBACK.0
+    + GAS_CRONCAT_CREATE_TASK.0
+    + GAS_CRONCAT_WITHDRAW_TASK_CALLBACK.0);
+/// Withdraw & its callbacks, with a single croncat task to cancel
+pub const GAS_STAKE_WITHDRAW: Gas = Gas(GAS_STAKE_WITHDRAW_ALL.0
+    + GAS_STAKE_WITHDRAW_CALLBACK.0
+    + GAS_CRONCAT_REMOVE_TASK.0
+    + GAS_STAKE_GET_STAKE_BALANCE.0
+    + GAS_STAKE_WITHDRAW_RECONCILE_CALLBACK.0);
+pub const GAS_STAKE_PLAN_STEP: Gas = Gas(5_000_000_000_000);
 
 pub const CRONCAT_CREATE_TASK_FEE: Balance = 17500000000000000000000;
 
+/// Amount of balance snapshots kept per pool, oldest get dropped first
+pub const MAX_STAKE_SNAPSHOTS: usize = 30;
+pub const YEAR_NANOS: u128 = 365 * 24 * 60 * 60 * 1_000_000_000;
+/// APY precision, 10_000 basis points is 100%
+pub const BPS_DENOMINATOR: u128 = 10_000;
+/// Default time before delegation balances are due for a refresh, 12 hours in nanos
+pub const STAKE_REFRESH_PERIOD: u64 = 12 * 60 * 60 * 1_000_000_000;
+/// Most pools refreshed per `refresh_delegations` call by the croncat trigger, ~35 Tgas each
+pub const STAKE_REFRESH_PAGE_LIMIT: u64 = 6;
+/// Default maximum fee for liquid unstaking, 3% in basis points
+pub const MAX_LIQUID_UNSTAKE_FEE_BPS: u64 = 300;
+/// Epochs an unstaked balance is locked for, before withdraw is available
+pub const UNSTAKE_LOCK_EPOCHS: u64 = 4;
+/// Default epoch length estimate, 15 hours in nanos
+/// NOTE: Mainnet epochs are ~14 hours, rounding up keeps withdraws from being scheduled before the unlock
+pub const STAKE_EPOCH_LENGTH: u64 = 15 * 60 * 60 * 1_000_000_000;
+
+/// Single unstake awaiting its unlock, a pool can have several in flight
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UnstakeTranche {
+    pub amount: U128,
+    /// Epoch when withdraw becomes available
+    pub unlock_epoch: u64,
+    pub block_timestamp: U64,
+    /// Croncat task scheduled to withdraw this tranche, if croncat is configured
+    pub task_hash: Option<Base64VecU8>,
+}
+
+/// Upcoming unlock of an unstake tranche
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UnstakeUnlock {
+    pub pool_account_id: AccountId,
+    pub amount: U128,
+    pub unlock_epoch: u64,
+    pub ready: bool,
+}
+
+/// Liquid staking protocol of a pool, describing how its shares get valued & instantly unstaked
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum LiquidStakingProvider {
+    /// REF: https://github.com/Narwallets/meta-pool
+    MetaPool,
+    /// LiNEAR-style pools, where shares are an FT & get sold with `instant_unstake`
+    Linear,
+}
+
+impl LiquidStakingProvider {
+    /// Default method to instantly unstake shares for NEAR
+    pub fn unstake_function(&self) -> &str {
+        match self {
+            LiquidStakingProvider::MetaPool => "liquid_unstake",
+            LiquidStakingProvider::Linear => "instant_unstake",
+        }
+    }
+
+    /// View returning the shares held by an account
+    pub fn balance_view(&self) -> &str {
+        match self {
+            LiquidStakingProvider::MetaPool => "get_account_info",
+            LiquidStakingProvider::Linear => "get_account_details",
+        }
+    }
+
+    pub fn balance_args(&self, account_id: &AccountId) -> String {
+        json!({
+            "account_id": account_id,
+        })
+        .to_string()
+    }
+
+    /// Shares held & their NEAR value before fees, from the balance view response
+    pub fn parse_balance(&self, result: &[u8]) -> (Balance, Balance) {
+        match self {
+            LiquidStakingProvider::MetaPool => {
+                let pool_balance: external::MetaPoolBalance = serde_json::de::from_slice(result)
+                    .expect("Could not get balance from stake pool");
+                (pool_balance.st_near.0, pool_balance.valued_st_near.0)
+            }
+            LiquidStakingProvider::Linear => {
+                let account: external::LinearAccountDetails = serde_json::de::from_slice(result)
+                    .expect("Could not get balance from stake pool");
+                (account.stake_shares.0, account.staked_balance.0)
+            }
+        }
+    }
+
+    /// View returning the NEAR received for selling an amount of shares, after fees
+    pub fn quote_view(&self) -> &str {
+        match self {
+            LiquidStakingProvider::MetaPool => "get_near_amount_sell_stnear",
+            LiquidStakingProvider::Linear => "get_instant_unstake_amount",
+        }
+    }
+
+    pub fn quote_args(&self, shares: U128) -> String {
+        match self {
+            LiquidStakingProvider::MetaPool => json!({
+                "stnear_to_sell": shares,
+            }),
+            LiquidStakingProvider::Linear => json!({
+                "stake_shares_in": shares,
+            }),
+        }
+        .to_string()
+    }
+
+    /// REF: https://github.com/linear-protocol/LiNEAR/blob/main/contracts/linear/src/liquidity_pool.rs
+    pub fn unstake_args(&self, shares: U128, min_expected_near: U128) -> String {
+        match self {
+            LiquidStakingProvider::MetaPool => json!({
+                "st_near_to_burn": shares,
+                "min_expected_near": min_expected_near,
+            }),
+            LiquidStakingProvider::Linear => json!({
+                "stake_shares_in": shares,
+                "min_amount_out": min_expected_near,
+            }),
+        }
+        .to_string()
+    }
+}
+
+/// Pool balance at a point in time, for computing yield
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakeSnapshot {
+    pub block_timestamp: u64,
+    /// Staked & unstaked balance held by the pool
+    pub balance: Balance,
+    /// Principal deposited & not yet withdrawn at the time
+    pub principal: Balance,
+    /// Rewards earned so far, realized or not
+    pub rewards: Balance,
+}
+
 /// Stake Buckets keep track of staked amounts per-pool
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, PanicOnDefault)]
 #[serde(crate = "near_sdk::serde")]
 pub struct StakeDelegation {
-    /// The starting balance, to compute future gains
+    /// The first staked balance, kept for reference
     pub init_balance: Balance,
-    /// non-realtime balance, updated by CALLs, useful in some scenarios
+    /// non-realtime staked balance, updated by CALLs, useful in some scenarios
+    /// NOTE: Unstaking balances are tracked as tranches in stake_pending_delegations
     pub balance: Balance,
     /// To keep track of how long at stake
     pub start_block: BlockHeight,
-    /// To keep track of the epoch when withdraw is available from unstake
-    pub withdraw_epoch: Option<u64>,
-    /// For computing how much is available for withdraw upon ready
-    pub withdraw_balance: Option<Balance>,
     /// Some providers have diff implementations
     pub withdraw_function: String,
     /// For enabling short term staking
     pub liquid_unstake_function: Option<String>,
+    /// Protocol used for liquid unstaking, set whenever liquid_unstake_function is
+    pub liquid_staking_provider: Option<LiquidStakingProvider>,
     /// For enabling yield from harvesting solutions (EX: Metapool $META)
     pub yield_function: Option<String>,
+    /// Token received by the yield function, turns on the harvest pipeline
+    pub harvest_token_id: Option<AccountId>,
+    /// Optional swap of the harvested token, into wNEAR for restaking
+    pub harvest_route: Option<harvest::HarvestRoute>,
+    /// Croncat task running the harvest pipeline on a cadence
+    pub harvest_task_hash: Option<Base64VecU8>,
+    /// Target share of total stake, relative to the weights of other pools
+    pub weight: u64,
+    /// All NEAR ever staked into this pool
+    pub total_deposited: Balance,
+    /// All NEAR ever withdrawn or liquid unstaked from this pool
+    pub total_withdrawn: Balance,
+    /// Deposited NEAR not yet withdrawn, withdraws count against principal before rewards
+    pub principal: Balance,
+    /// Rewards that have been withdrawn
+    pub rewards_realized: Balance,
+    /// Recent balances, updated by get_staked_balance
+    pub snapshots: Vec<StakeSnapshot>,
+    /// Unstaked balance held by the pool, as of the last refresh
+    pub unstaked_balance: Balance,
+    /// Whether the pool reported the unstaked balance as withdrawable, as of the last refresh
+    pub can_withdraw: bool,
+    /// Block timestamp of the last balance refresh, 0 if never
+    pub last_refreshed: u64,
+}
+
+impl StakeDelegation {
+    pub(crate) fn record_deposit(&mut self, amount: Balance) {
+        self.total_deposited = self.total_deposited.saturating_add(amount);
+        self.principal = self.principal.saturating_add(amount);
+    }
+
+    /// Undo a deposit the pool did not accept
+    pub(crate) fn revert_deposit(&mut self, amount: Balance) {
+        self.balance = self.balance.saturating_sub(amount);
+        self.total_deposited = self.total_deposited.saturating_sub(amount);
+        self.principal = self.principal.saturating_sub(amount);
+    }
+
+    /// Returns the part of the amount that came out of principal
+    pub(crate) fn record_withdraw(&mut self, amount: Balance) -> Balance {
+        let from_principal = u128::min(amount, self.principal);
+        self.principal -= from_principal;
+        self.rewards_realized = self
+            .rewards_realized
+            .saturating_add(amount - from_principal);
+        self.total_withdrawn = self.total_withdrawn.saturating_add(amount);
+        from_principal
+    }
+
+    /// Undo a withdraw the pool did not pay out
+    pub(crate) fn revert_withdraw(&mut self, amount: Balance, from_principal: Balance) {
+        self.principal = self.principal.saturating_add(from_principal);
+        self.rewards_realized = self
+            .rewards_realized
+            .saturating_sub(amount.saturating_sub(from_principal));
+        self.total_withdrawn = self.total_withdrawn.saturating_sub(amount);
+    }
+
+    /// Rewards earned so far, given the full balance held by the pool
+    pub(crate) fn get_rewards(&self, pool_balance: Balance) -> Balance {
+        pool_balance
+            .saturating_add(self.total_withdrawn)
+            .saturating_sub(self.total_deposited)
+    }
+
+    pub(crate) fn record_snapshot(&mut self, pool_balance: Balance) {
+        self.snapshots.push(StakeSnapshot {
+            block_timestamp: env::block_timestamp(),
+            balance: pool_balance,
+            principal: self.principal,
+            rewards: self.get_rewards(pool_balance),
+        });
+        if self.snapshots.len() > MAX_STAKE_SNAPSHOTS {
+            self.snapshots.remove(0);
+        }
+    }
+
+    /// Annualized yield in basis points, from rewards earned between the oldest & latest snapshot
+    pub(crate) fn get_apy_bps(&self) -> Option<u64> {
+        let first = self.snapshots.first()?;
+        let last = self.snapshots.last()?;
+        let elapsed = u128::from(last.block_timestamp.saturating_sub(first.block_timestamp));
+        let principal = (first.principal + last.principal) / 2;
+        if elapsed == 0 || principal == 0 {
+            return None;
+        }
+        let earned = last.rewards.saturating_sub(first.rewards);
+        Some(
+            (U256::from(earned) * U256::from(BPS_DENOMINATOR) * U256::from(YEAR_NANOS)
+                / (U256::from(principal) * U256::from(elapsed)))
+            .as_u64(),
+        )
+    }
+}
+
+/// Rewards & yield of a single pool
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakeRewards {
+    pub pool_account_id: Option<AccountId>,
+    pub total_deposited: U128,
+    pub total_withdrawn: U128,
+    pub principal: U128,
+    /// Latest known balance held by the pool
+    pub balance: U128,
+    pub rewards_realized: U128,
+    pub rewards_unrealized: U128,
+    /// Annualized yield, 10_000 is 100%. None until 2 snapshots exist
+    pub apy_bps: Option<u64>,
+}
+
+/// Rewards & yield for each pool, and the whole treasury
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakingRewardsReport {
+    pub pools: Vec<StakeRewards>,
+    pub total: StakeRewards,
 }
 
 /// Stake Buckets keep track of staked amounts per-pool
@@ -52,7 +333,11 @@ pub struct StakeDelegationHumanFriendly {
     pub withdraw_balance: Option<U128>,
     pub withdraw_function: String,
     pub liquid_unstake_function: Option<String>,
+    pub liquid_staking_provider: Option<LiquidStakingProvider>,
     pub yield_function: Option<String>,
+    pub harvest_token_id: Option<AccountId>,
+    pub harvest_route: Option<harvest::HarvestRoute>,
+    pub weight: u64,
 }
 
 /// Stake Buckets keep track of staked amounts per-pool
@@ -69,6 +354,64 @@ pub struct StakeThreshold {
     pub eval_cadence: String,   // OR cron cadence
 }
 
+/// Kind of staking step taken against a pool
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum StakePlanAction {
+    Stake,
+    Unstake,
+    LiquidUnstake,
+}
+
+impl StakePlanAction {
+    /// Gas reserved to take this step, including the cross-contract calls it makes
+    pub fn required_gas(&self) -> Gas {
+        match self {
+            StakePlanAction::Stake => {
+                GAS_STAKE_PLAN_STEP
+                    + GAS_STAKE_DEPOSIT_AND_STAKE
+                    + GAS_STAKE_DEPOSIT_AND_STAKE_CALLBACK
+            }
+            StakePlanAction::Unstake => {
+                GAS_STAKE_PLAN_STEP
+                    + GAS_STAKE_UNSTAKE
+                    + GAS_CRONCAT_REMOVE_TASK
+                    + GAS_CRONCAT_CREATE_TASK
+                    + GAS_CRONCAT_WITHDRAW_TASK_CALLBACK
+            }
+            StakePlanAction::LiquidUnstake => {
+                GAS_STAKE_PLAN_STEP
+                    + GAS_STAKE_LIQUID_UNSTAKE_VIEW
+                    + GAS_STAKE_LIQUID_UNSTAKE_CALLBACK
+            }
+        }
+    }
+}
+
+/// Single step of a staking plan against one pool
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakePlanStep {
+    pub pool_account_id: AccountId,
+    pub action: StakePlanAction,
+    pub amount: U128,
+    /// Why this step is needed, for humans reviewing the plan
+    pub reason: String,
+}
+
+/// Liquid balance versus its threshold, and the steps that bring it back in range
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RebalancePlan {
+    pub liquid_actual: U128,
+    pub liquid_ideal: U128,
+    pub liquid_deviation: U128,
+    pub liquid_extreme_deviation: U128,
+    /// Already unstaking, counted towards liquid as it becomes available soon
+    pub unstaking: U128,
+    pub steps: Vec<StakePlanStep>,
+}
+
 impl Default for StakeThreshold {
     fn default() -> Self {
         StakeThreshold {
@@ -91,16 +434,24 @@ impl Contract {
     ///
     /// ```bash
     /// near call treasury.testnet add_staking_pool '{"pool_account_id": "steak.factory.testnet"}' --accountId treasury.testnet
+    ///
+    /// OR, for a liquid staking pool:
+    ///
+    /// near call treasury.testnet add_staking_pool '{"pool_account_id": "linear-protocol.testnet", "liquid_staking_provider": "linear"}' --accountId treasury.testnet
     /// ```
     pub fn add_staking_pool(
         &mut self,
         pool_account_id: AccountId,
-        // If a pool has liquid staking abilities, you can provide here. Examples: MetaPool: liquid_unstake
+        // If a pool has liquid staking abilities, you can provide here. Examples: MetaPool: liquid_unstake, LiNEAR: instant_unstake
         liquid_unstake_function: Option<String>,
+        // Protocol of a liquid staking pool, defaults to MetaPool if only liquid_unstake_function is provided
+        liquid_staking_provider: Option<LiquidStakingProvider>,
         // If a pool has harvesting abilities, you can provide here. Examples: MetaPool: harvest_meta, CheddarFarm: withdraw_crop
         yield_function: Option<String>,
         // IF the withdraw function is different than standard
         withdraw_function: Option<String>,
+        // Target share of total stake relative to other pools, default 1 (even split)
+        weight: Option<u64>,
     ) {
         self.assert_owner();
         let current_pool = self.stake_delegations.get(&pool_account_id);
@@ -108,21 +459,67 @@ impl Contract {
         // Insert ONLY if there isn't a record of this pool already
         // NOTE: Only managing the stake_delegations, as stake_pending_delegations is used for active balance movements
         assert!(current_pool.is_none(), "Stake pool exists already");
+        let liquid_staking_provider = match (&liquid_unstake_function, liquid_staking_provider) {
+            (_, Some(provider)) => Some(provider),
+            (Some(_), None) => Some(LiquidStakingProvider::MetaPool),
+            (None, None) => None,
+        };
+        let liquid_unstake_function = liquid_unstake_function.or_else(|| {
+            liquid_staking_provider
+                .as_ref()
+                .map(|provider| provider.unstake_function().to_string())
+        });
         self.stake_delegations.insert(
             &pool_account_id,
             &StakeDelegation {
                 init_balance: 0,
                 balance: 0,
                 start_block: 0, // 0 indicates that the staking has not started yet
-                withdraw_epoch: None,
-                withdraw_balance: None,
                 withdraw_function: withdraw_function.unwrap_or("withdraw_all".to_string()),
                 liquid_unstake_function,
+                liquid_staking_provider,
                 yield_function,
+                harvest_token_id: None,
+                harvest_route: None,
+                harvest_task_hash: None,
+                weight: weight.unwrap_or(1),
+                total_deposited: 0,
+                total_withdrawn: 0,
+                principal: 0,
+                rewards_realized: 0,
+                snapshots: Vec::new(),
+                unstaked_balance: 0,
+                can_withdraw: false,
+                last_refreshed: 0,
             },
         );
     }
 
+    /// Change the target share of total stake for a pool
+    /// NOTE: A weight of 0 stops new stake going to the pool, and unstakes from it first
+    /// NOTE: At least one pool must keep a weight above 0
+    ///
+    /// ```bash
+    /// near call treasury.testnet set_staking_pool_weight '{"pool_account_id": "steak.factory.testnet", "weight": 2}' --accountId treasury.testnet
+    /// ```
+    pub fn set_staking_pool_weight(&mut self, pool_account_id: AccountId, weight: u64) {
+        self.assert_owner();
+        let mut delegation = self
+            .stake_delegations
+            .get(&pool_account_id)
+            .expect("Stake pool doesnt exist");
+        if weight == 0 {
+            assert!(
+                self.stake_delegations
+                    .iter()
+                    .any(|(id, d)| id != pool_account_id && d.weight > 0),
+                "At least one pool needs a weight"
+            );
+        }
+        delegation.weight = weight;
+        self.stake_delegations.insert(&pool_account_id, &delegation);
+    }
+
     /// Remove a pool, if all balances have been withdrawn
     ///
     /// ```bash
@@ -136,46 +533,94 @@ impl Contract {
         // NOTE: Only managing the stake_delegations, as stake_pending_delegations is used for active balance movements
         assert!(current_pool.is_some(), "Stake pool doesnt exist");
         assert_eq!(current_pool.unwrap().balance, 0, "Stake pool has a balance");
+        assert!(
+            self.stake_pending_delegations
+                .get(&pool_account_id)
+                .is_none(),
+            "Stake pool has an unstaking balance"
+        );
         self.stake_delegations.remove(&pool_account_id);
     }
 
-    /// Returns all staking delegations
+    /// Check if any pool has an unstake tranche ready to withdraw
+    /// Returns the `withdraw` arguments for the first pool that is ready
     ///
     /// ```bash
     /// near view treasury.testnet has_delegation_to_withdraw
     /// ```
     pub fn has_delegation_to_withdraw(&self) -> external::CroncatTriggerResponse {
-        let mut ret: Vec<AccountId> = Vec::new();
-        let mut has_withdraw = false;
-
-        // Return all data within range
-        let keys = self.stake_pending_delegations.keys_as_vector();
-        for pool_account_id in keys.iter() {
-            if let Some(delegation) = self.stake_pending_delegations.get(&pool_account_id) {
-                // Check if any of the pending delegations have a withdraw epoch older than THIS epoch
-                if delegation.withdraw_epoch.unwrap_or(env::epoch_height()) < env::epoch_height()
-                    && delegation.withdraw_balance.unwrap_or(0) > 0
-                {
-                    has_withdraw = true;
-                }
+        let ready = self
+            .stake_pending_delegations
+            .iter()
+            .find(|(_, tranches)| {
+                tranches
+                    .iter()
+                    .any(|t| t.unlock_epoch <= env::epoch_height() && t.amount.0 > 0)
+            })
+            .map(|(pool_account_id, _)| pool_account_id);
 
-                ret.push(pool_account_id);
-            }
-        }
         (
-            has_withdraw,
-            Base64VecU8::from(serde_json::ser::to_vec(&ret).expect("Could not serialize")),
+            ready.is_some(),
+            Base64VecU8::from(
+                json!({
+                    "pool_account_id": ready,
+                })
+                .to_string()
+                .into_bytes(),
+            ),
         )
     }
 
+    /// Upcoming unlocks of unstaked balances, soonest first
+    ///
+    /// ```bash
+    /// near view treasury.testnet get_unstake_unlocks '{"pool_account_id": "steak.factory.testnet"}'
+    /// ```
+    pub fn get_unstake_unlocks(&self, pool_account_id: Option<AccountId>) -> Vec<UnstakeUnlock> {
+        let mut unlocks: Vec<UnstakeUnlock> = Vec::new();
+        for (id, tranches) in self.stake_pending_delegations.iter() {
+            if pool_account_id.is_some() && pool_account_id.as_ref() != Some(&id) {
+                continue;
+            }
+            for tranche in tranches {
+                unlocks.push(UnstakeUnlock {
+                    pool_account_id: id.clone(),
+                    amount: tranche.amount,
+                    unlock_epoch: tranche.unlock_epoch,
+                    ready: tranche.unlock_epoch <= env::epoch_height(),
+                });
+            }
+        }
+        unlocks.sort_by_key(|u| u.unlock_epoch);
+        unlocks
+    }
+
     /// Check staking threshold to find if an auto_stake rebalance should occur
+    /// Returns the rebalance plan as arguments, see `get_stake_rebalance_plan`
     ///
     /// ```bash
     /// near view treasury.testnet needs_stake_rebalance --accountId manager_v1.croncat.testnet
     /// ```
     pub fn needs_stake_rebalance(&self) -> external::CroncatTriggerResponse {
+        let plan = self.get_stake_rebalance_plan();
+        (
+            !plan.steps.is_empty(),
+            Base64VecU8::from(serde_json::ser::to_vec(&plan).expect("Could not serialize")),
+        )
+    }
+
+    /// Compute which pools to stake into, unstake from or liquid unstake from, to bring liquid NEAR back to threshold
+    /// Logic:
+    /// - Above ideal + deviation: Stake the excess liquid, spread by how far each pool is below its weight
+    /// - Below ideal - deviation: Unstake the shortfall, from the pools most above their weight
+    /// - Below ideal - extreme deviation: Same as above, but liquid unstake from pools that support it
+    ///
+    /// ```bash
+    /// near view treasury.testnet get_stake_rebalance_plan
+    /// ```
+    pub fn get_stake_rebalance_plan(&self) -> RebalancePlan {
         let threshold = &self.stake_threshold;
-        let current_balance = env::account_balance();
+        let liquid_actual = env::account_balance();
         let mut staked_balance: Balance = 0;
         let mut unstaking_balance: Balance = 0;
 
@@ -184,20 +629,20 @@ impl Contract {
             staked_balance = staked_balance.saturating_add(stake.balance);
         }
         // get total unstaking balance
-        for (_, unstake) in self.stake_pending_delegations.iter() {
-            unstaking_balance =
-                unstaking_balance.saturating_add(unstake.withdraw_balance.unwrap_or(0));
+        for (_, tranches) in self.stake_pending_delegations.iter() {
+            for tranche in tranches {
+                unstaking_balance = unstaking_balance.saturating_add(tranche.amount.0);
+            }
         }
 
         // update total balance, so we can check thresholds
-        // TODO: Check if taking unstaking balance into consideration is a bad idea realistically
-        let total_balance: Balance =
-            current_balance.saturating_add(staked_balance.saturating_add(unstaking_balance));
+        let total_balance: Balance = liquid_actual
+            .saturating_add(staked_balance)
+            .saturating_add(unstaking_balance);
 
         // Compute threshold values
         let liquid_ideal: u128 =
             utils::calc_percent(threshold.liquid, threshold.denominator, total_balance);
-        let liquid_actual: u128 = utils::calc_percent(1, threshold.denominator, current_balance);
         let liquid_deviation: u128 =
             utils::calc_percent(threshold.deviation, threshold.denominator, total_balance);
         let liquid_extreme_deviation: u128 = utils::calc_percent(
@@ -205,122 +650,118 @@ impl Contract {
             threshold.denominator,
             total_balance,
         );
+        let liquid_upcoming = liquid_actual.saturating_add(unstaking_balance);
+        let mut steps: Vec<StakePlanStep> = Vec::new();
 
-        // TODO: Needs to check both sides of the threshold (above & below ideal)
-        // Check if liquid balance is above threshold deviation
-        if liquid_actual > liquid_ideal.saturating_add(liquid_deviation) {
-            return (
-                true,
-                Base64VecU8::from(
-                    serde_json::ser::to_vec(&vec![
-                        U128::from(liquid_actual),
-                        U128::from(liquid_ideal),
-                        U128::from(liquid_deviation),
-                        U128::from(liquid_extreme_deviation),
-                    ])
-                    .expect("Could not serialize"),
-                ),
+        // Liquid balance is above threshold deviation, stake the excess
+        if liquid_actual > liquid_ideal.saturating_add(liquid_deviation)
+            && !self.stake_delegations.is_empty()
+        {
+            let stake_amount = u128::min(
+                liquid_actual - liquid_ideal,
+                liquid_actual.saturating_sub(MIN_BALANCE_FOR_STORAGE),
             );
+            if stake_amount >= STAKE_BALANCE_MIN {
+                for (pool_account_id, amount) in self.allocate_stake(stake_amount) {
+                    steps.push(StakePlanStep {
+                        pool_account_id,
+                        action: StakePlanAction::Stake,
+                        amount: U128::from(amount),
+                        reason: "Liquid above ideal, pool below its weighted target".to_string(),
+                    });
+                }
+            }
         }
 
-        // Check if liquid balance is below threshold deviation
-        if (liquid_actual < liquid_ideal.saturating_sub(liquid_deviation))
-            || (liquid_actual < liquid_ideal.saturating_sub(liquid_extreme_deviation))
-        {
-            return (
-                true,
-                Base64VecU8::from(
-                    serde_json::ser::to_vec(&vec![
-                        U128::from(liquid_actual),
-                        U128::from(liquid_ideal),
-                        U128::from(liquid_deviation),
-                        U128::from(liquid_extreme_deviation),
-                    ])
-                    .expect("Could not serialize"),
-                ),
-            );
+        // Liquid balance is below threshold deviation, unstake the shortfall not already unstaking
+        if liquid_actual < liquid_ideal.saturating_sub(liquid_deviation) {
+            let extreme = liquid_actual < liquid_ideal.saturating_sub(liquid_extreme_deviation);
+            // Liquid unstaking is immediate, so pending unstakes do not cover the shortfall
+            let unstake_amount = if extreme {
+                liquid_ideal - liquid_actual
+            } else {
+                liquid_ideal.saturating_sub(liquid_upcoming)
+            };
+            for (pool_account_id, amount) in self.allocate_unstake(unstake_amount) {
+                let delegation = self
+                    .stake_delegations
+                    .get(&pool_account_id)
+                    .expect("No delegation found for pool");
+                let (action, reason) = if extreme && delegation.liquid_unstake_function.is_some() {
+                    (
+                        StakePlanAction::LiquidUnstake,
+                        "Liquid below extreme deviation, pool above its weighted target",
+                    )
+                } else if extreme {
+                    (
+                        StakePlanAction::Unstake,
+                        "Liquid below extreme deviation, pool above its weighted target but has no liquid unstake",
+                    )
+                } else {
+                    (
+                        StakePlanAction::Unstake,
+                        "Liquid below ideal, pool above its weighted target",
+                    )
+                };
+                steps.push(StakePlanStep {
+                    pool_account_id,
+                    action,
+                    amount: U128::from(amount),
+                    reason: reason.to_string(),
+                });
+            }
         }
 
-        return (
-            false,
-            Base64VecU8::from(serde_json::ser::to_vec(&vec![U128::from(liquid_actual), U128::from(liquid_ideal), U128::from(liquid_deviation), U128::from(liquid_extreme_deviation)]).expect("Could not serialize"))
-        );
+        RebalancePlan {
+            liquid_actual: U128::from(liquid_actual),
+            liquid_ideal: U128::from(liquid_ideal),
+            liquid_deviation: U128::from(liquid_deviation),
+            liquid_extreme_deviation: U128::from(liquid_extreme_deviation),
+            unstaking: U128::from(unstaking_balance),
+            steps,
+        }
     }
 
-    /// Check staking threshold for eval
-    /// Logic:
-    /// - Checks if current balance is above defined threshold (Example if account total balance is 100 near, liquid 40 with a setting of staking 80%, go ahead and stake)
-    /// - If threshold is out of proportion, trigger one of the following:
-    ///     - Stake: If above threshold
-    ///     - UnStake: If below threshold
-    ///     - Liquid UnStake: If below extreme threshold
+    /// Carry out the rebalance plan, see `get_stake_rebalance_plan`
+    /// Takes steps in plan order, for as long as there is gas left to do so.
+    /// Steps not reached get planned again on the next call.
     ///
     /// ```bash
-    /// near call treasury.testnet auto_stake --accountId manager_v1.croncat.testnet
+    /// near call treasury.testnet auto_stake --accountId manager_v1.croncat.testnet --gas 300000000000000
     /// ```
     pub fn auto_stake(&mut self) {
         // Check if approved caller
-        assert!(
-            env::predecessor_account_id() == self.owner_id
-                || env::predecessor_account_id() == self.croncat_id.clone().unwrap(),
-            "Not an approved caller"
-        );
-        let (needs_rebalance, liquid_base64): external::CroncatTriggerResponse =
-            self.needs_stake_rebalance();
-        if !needs_rebalance {
+        self.assert_owner_or_croncat();
+        let plan = self.get_stake_rebalance_plan();
+        if plan.steps.is_empty() {
             return;
         }
-        let (liquid_actual, liquid_ideal, liquid_deviation, liquid_extreme_deviation): (
-            U128,
-            U128,
-            U128,
-            U128,
-        ) = serde_json::de::from_slice(&liquid_base64.try_to_vec().expect("Base64 to Vec fail"))
-            .expect("Could not deserialize");
-
-        // Get the staking pool(s) to do things
-        // NOTE: For simplicity, just going to get 1 pool
-        let pool_id = self
-            .stake_delegations
-            .keys()
-            .next()
-            .expect("No pool id found");
-
-        // Check if liquid balance is above threshold deviation
-        if liquid_actual.0 > liquid_ideal.0.saturating_add(liquid_deviation.0) {
-            // Time to restake some amount
-            self.deposit_and_stake(
-                pool_id.clone(),
-                Some(U128::from(liquid_ideal.0.saturating_sub(liquid_actual.0))),
-            );
-        }
 
-        // Check if liquid balance is below threshold deviation
-        if liquid_actual.0 < liquid_ideal.0.saturating_sub(liquid_deviation.0) {
-            // Time to unstake some amount
-            if liquid_actual.0 < liquid_ideal.0.saturating_sub(liquid_extreme_deviation.0) {
-                let unstake_amount = Some(U128::from(
-                    liquid_extreme_deviation.0.saturating_sub(liquid_actual.0),
-                ));
-                let pool = self
-                    .stake_delegations
-                    .get(&pool_id.clone())
-                    .expect("No delegation found for pool");
-                // If pool supports liquid unstaking, otherwise go to regular
-                if pool.liquid_unstake_function.is_some() {
-                    self.liquid_unstake(pool_id, unstake_amount);
-                } else {
-                    self.unstake(pool_id, unstake_amount);
+        let mut steps: Vec<StakePlanStep> = Vec::new();
+        for step in plan.steps.into_iter() {
+            // Leave enough room for this step & the remaining execution
+            let gas_left = env::prepaid_gas().0.saturating_sub(env::used_gas().0);
+            if gas_left < step.action.required_gas().0 {
+                break;
+            }
+
+            let pool_account_id = step.pool_account_id.clone();
+            match step.action {
+                StakePlanAction::Stake => {
+                    self.internal_deposit_and_stake(pool_account_id, step.amount.0);
+                }
+                StakePlanAction::Unstake => {
+                    self.internal_unstake(pool_account_id, Some(step.amount));
+                }
+                StakePlanAction::LiquidUnstake => {
+                    self.internal_liquid_unstake(pool_account_id, Some(step.amount));
                 }
-            } else {
-                self.unstake(
-                    pool_id,
-                    Some(U128::from(
-                        liquid_extreme_deviation.0.saturating_sub(liquid_actual.0),
-                    )),
-                );
             }
+            steps.push(step);
         }
+
+        assert!(!steps.is_empty(), "Not enough gas attached");
+        events::StakePlan { steps }.emit();
     }
 
     /// Send NEAR to a staking pool and stake.
@@ -359,36 +800,33 @@ impl Contract {
             }
         }
 
-        // Stop if somehow we made it this far and have nothing to stake... RUDE
-        assert_ne!(stake_amount, 0, "Nothing to stake");
-        log!("Amount to stake {:?}", &stake_amount);
-
-        let delegation = pool_delegation.unwrap();
-        let updated_delegation = StakeDelegation {
-            init_balance: stake_amount,
-            balance: stake_amount,
-            start_block: env::block_height(),
-            withdraw_epoch: None,
-            withdraw_balance: None,
-            withdraw_function: delegation.withdraw_function,
-            liquid_unstake_function: delegation.liquid_unstake_function,
-            yield_function: delegation.yield_function,
-        };
-
-        // Update our local balance values, so we can keep track if we're using the pool (but not caring about interest earned)
-        self.stake_delegations
-            .insert(&pool_account_id, &updated_delegation);
+        let p = self.internal_deposit_and_stake(pool_account_id, stake_amount);
+        env::promise_return(p);
+    }
 
-        // Lastly, make the cross-contract call to DO the staking :D
-        let p = env::promise_create(
-            pool_account_id,
-            "deposit_and_stake",
-            json!({}).to_string().as_bytes(),
-            stake_amount,
-            GAS_STAKE_DEPOSIT_AND_STAKE,
+    /// CALLBACK for deposit_and_stake
+    /// Reverts the deposit bookkeeping if the pool did not take the NEAR, returns whether it did
+    #[private]
+    pub fn callback_deposit_and_stake(&mut self, pool_account_id: AccountId, amount: U128) -> bool {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "Expected 1 promise result."
         );
+        if is_promise_success() {
+            return true;
+        }
 
-        env::promise_return(p);
+        if let Some(mut delegation) = self.stake_delegations.get(&pool_account_id) {
+            delegation.revert_deposit(amount.0);
+            // The failed deposit was the first, staking has not started yet
+            if delegation.total_deposited == 0 {
+                delegation.init_balance = 0;
+                delegation.start_block = 0;
+            }
+            self.stake_delegations.insert(&pool_account_id, &delegation);
+        }
+        false
     }
 
     /// Get the staked balance from a pool for THIS account
@@ -459,24 +897,11 @@ impl Contract {
                 // Attempt to parse the returned balance amount
                 let pool_balance: external::PoolBalance = serde_json::de::from_slice(&result)
                     .expect("Could not get balance from stake delegation");
-                let mut delegation = self
-                    .stake_delegations
-                    .get(&pool_account_id)
-                    .expect("No delegation found");
-
-                // Update internal balances
-                delegation.balance = pool_balance.staked_balance.0;
-                delegation.withdraw_balance = Some(pool_balance.unstaked_balance.0);
-
-                // If its known, immediately make withdraw available, otherwise compute when withdraw is available
-                if pool_balance.can_withdraw {
-                    delegation.withdraw_epoch = Some(env::epoch_height() + 4);
-                }
+                let delegation =
+                    self.internal_update_delegation_balance(&pool_account_id, &pool_balance);
 
-                // Update the balances of pool
+                // Update the balances of pool, unstake tranches are left as is
                 self.stake_delegations.insert(&pool_account_id, &delegation);
-                self.stake_pending_delegations
-                    .insert(&pool_account_id, &delegation);
                 (
                     pool_balance.staked_balance.0,
                     pool_balance.unstaked_balance.0,
@@ -487,167 +912,337 @@ impl Contract {
         }
     }
 
-    /// Unstake from a pool, works in metapool and traditional validator pools
-    /// NOTE: Unstaking here will schedule the automatic withdrawal in future epoch
+    /// Refresh the balances of every delegation in the page
+    /// Pings each pool so rewards are up to date, fans out `get_account`, then stores all results in a single callback
+    /// NOTE: Pools without `ping` (EX: MetaPool) still get their balance fetched
+    /// NOTE: The page is cut down to the pools the attached gas covers, ~35 Tgas each
+    /// NOTE: Each call moves the refresh on to the next page, the refresh is done once the last page is stored
     ///
     /// ```bash
-    /// near call treasury.testnet unstake '{"pool_account_id": "steak.factory.testnet", "amount": "100000000000000000000000000"}' --accountId treasury.testnet
-    ///
-    /// OR, to unstake ALL:
-    ///
-    /// near call treasury.testnet unstake '{"pool_account_id": "steak.factory.testnet"}' --accountId treasury.testnet
+    /// near call treasury.testnet refresh_delegations '{"from_index": "0", "limit": "10"}' --accountId treasury.testnet --gas 300000000000000
     /// ```
-    pub fn unstake(&mut self, pool_account_id: AccountId, amount: Option<U128>) {
-        self.assert_owner();
-        let pool_delegation = self.stake_delegations.get(&pool_account_id);
-        assert!(pool_delegation.is_some(), "Stake delegation doesnt exist");
-        let mut unstake_function = "unstake_all";
+    pub fn refresh_delegations(&mut self, from_index: Option<U64>, limit: Option<U64>) {
+        let mut start = 0;
+        let mut end = 10;
 
-        // Stop if somehow we made it this far and have nothing to unstake... RUDE
-        if amount.is_some() {
-            assert_ne!(amount.unwrap().0, 0, "Nothing to unstake");
-            unstake_function = "unstake";
+        if let Some(from_index) = from_index {
+            start = from_index.0;
         }
+        if let Some(limit) = limit {
+            end = start + limit.0;
+        }
+        end = u64::min(end, self.stake_delegations.len());
+
+        // Leave enough room for every pool in the page
+        let gas_left = env::prepaid_gas().0.saturating_sub(env::used_gas().0);
+        let max_pools = gas_left.saturating_sub(GAS_BASE_FEE.0) / GAS_STAKE_REFRESH_POOL.0;
+        assert!(max_pools > 0, "Not enough gas attached");
+        end = u64::min(end, start.saturating_add(max_pools));
+
+        // Get all pools within range
+        let keys = self.stake_delegations.keys_as_vector();
+        let pool_account_ids: Vec<AccountId> = (start..end).filter_map(|i| keys.get(i)).collect();
+        assert!(!pool_account_ids.is_empty(), "No delegations to refresh");
+
+        let promises: Vec<_> = pool_account_ids
+            .iter()
+            .map(|pool_account_id| {
+                let ping = env::promise_create(
+                    pool_account_id.clone(),
+                    "ping",
+                    json!({}).to_string().as_bytes(),
+                    NO_DEPOSIT,
+                    GAS_STAKE_PING,
+                );
+                env::promise_then(
+                    ping,
+                    pool_account_id.clone(),
+                    "get_account",
+                    json!({
+                        "account_id": env::current_account_id(),
+                    })
+                    .to_string()
+                    .as_bytes(),
+                    NO_DEPOSIT,
+                    GAS_STAKE_GET_STAKE_BALANCE,
+                )
+            })
+            .collect();
 
-        // Update our local balance values, so we know whats in process of long-form unstaking
-        let mut delegation = pool_delegation.unwrap();
-        let withdraw_balance = amount.unwrap_or(U128::from(0)).0;
-        delegation.withdraw_epoch = Some(env::epoch_height() + 4);
-        delegation.withdraw_balance = Some(withdraw_balance);
-        self.stake_pending_delegations
-            .insert(&pool_account_id, &delegation);
-
-        // Lastly, make the cross-contract call to DO the unstaking :D
-        let p = env::promise_create(
-            pool_account_id.clone(),
-            &unstake_function,
+        let p = env::promise_then(
+            env::promise_and(&promises),
+            env::current_account_id(),
+            "callback_refresh_delegations",
             json!({
-                "amount": amount,
+                "pool_account_ids": pool_account_ids,
+                "next_index": U64::from(end),
             })
             .to_string()
             .as_bytes(),
             NO_DEPOSIT,
-            GAS_STAKE_UNSTAKE,
+            Gas(GAS_STAKE_REFRESH_CALLBACK.0 * pool_account_ids.len() as u64),
         );
 
-        // Add withdraw scheduler, if croncat is configured
-        // NOTE: Could also add this as a timeout action!
-        if self.croncat_id.is_some() {
-            external::croncat::create_task(
-                env::current_account_id().to_string(),
-                "withdraw".to_string(),
-                // TODO: what cadence is needed here? (this sets it to every friday at minute 0), ideally can set a block height start
-                "* * * * * Fri".to_string(),
-                Some(false),
-                Some(U128::from(NO_DEPOSIT)),
-                Some(u64::from(GAS_STAKE_WITHDRAW_ALL + GAS_CRONCAT_CREATE_TASK)), // 70 Tgas
-                Some(Base64VecU8::from(
-                    json!({
-                        "pool_account_id": pool_account_id,
-                    })
-                    .to_string()
-                    .as_bytes()
-                    .to_vec(),
-                )),
-                self.croncat_id.clone().unwrap(),
-                CRONCAT_CREATE_TASK_FEE,
-                GAS_CRONCAT_CREATE_TASK,
-            );
+        env::promise_return(p);
+    }
+
+    /// CALLBACK for refresh_delegations
+    /// Results come back in the same order as `pool_account_ids`, failed lookups keep their stored balance
+    #[private]
+    pub fn callback_refresh_delegations(
+        &mut self,
+        pool_account_ids: Vec<AccountId>,
+        next_index: U64,
+    ) {
+        assert_eq!(
+            env::promise_results_count(),
+            pool_account_ids.len() as u64,
+            "Expected 1 promise result per pool."
+        );
+
+        for (i, pool_account_id) in pool_account_ids.iter().enumerate() {
+            if let PromiseResult::Successful(result) = env::promise_result(i as u64) {
+                if let Ok(pool_balance) =
+                    serde_json::de::from_slice::<external::PoolBalance>(&result)
+                {
+                    let delegation =
+                        self.internal_update_delegation_balance(pool_account_id, &pool_balance);
+                    self.stake_delegations.insert(pool_account_id, &delegation);
+                }
+            }
         }
 
-        env::promise_return(p);
+        self.internal_stake_refresh_advance(next_index.0);
     }
 
-    /// Withdraw unstaked balance from a pool, works in metapool and traditional validator pools
+    /// Check if any delegation balance is due for a refresh, based on the refresh period
+    /// Stays due until every page is refreshed, returns the `refresh_delegations` arguments of the next page
     ///
     /// ```bash
-    /// near call treasury.testnet withdraw '{"pool_account_id": "steak.factory.testnet"}' --accountId treasury.testnet
+    /// near view treasury.testnet needs_delegations_refresh
     /// ```
-    pub fn withdraw(&mut self, pool_account_id: AccountId) {
-        self.assert_owner();
-        let pending_pool_delegation = self
-            .stake_pending_delegations
-            .get(&pool_account_id)
-            .expect("Withdraw delegation doesnt exist");
-        let mut pool_delegation = self
-            .stake_delegations
-            .get(&pool_account_id)
-            .expect("Stake delegation doesnt exist");
-
-        // Clear the pending amount, update main pool amount
-        // NOTE: would be great to do this on a callback, but seems withdraw functions dont provide how much was withdrawn on response
-        // NOTE: Could try getting current account balance and balance after callback, however it doesnt work if this is used for FT staking
-        pool_delegation.balance = pool_delegation
-            .balance
-            .saturating_sub(pending_pool_delegation.withdraw_balance.unwrap_or(0));
-        log!("pool_delegation.balance {:?}", pool_delegation.balance);
-        self.stake_pending_delegations.remove(&pool_account_id);
-        self.stake_delegations
-            .insert(&pool_account_id, &pool_delegation);
+    pub fn needs_delegations_refresh(&self) -> external::CroncatTriggerResponse {
+        let due = self.stake_refresh_index > 0
+            || self.stake_delegations.values().any(|delegation| {
+                env::block_timestamp()
+                    >= delegation
+                        .last_refreshed
+                        .saturating_add(self.stake_refresh_period)
+            });
 
-        // Lastly, make the cross-contract call to DO the withdraw :D
-        let p = env::promise_create(
-            pool_account_id,
-            &pool_delegation.withdraw_function,
-            json!({}).to_string().as_bytes(),
-            NO_DEPOSIT,
-            GAS_STAKE_WITHDRAW_ALL,
-        );
+        (
+            due,
+            Base64VecU8::from(
+                json!({
+                    "from_index": U64::from(self.stake_refresh_index),
+                    "limit": U64::from(STAKE_REFRESH_PAGE_LIMIT),
+                })
+                .to_string()
+                .into_bytes(),
+            ),
+        )
+    }
 
+    /// Unstake from a pool, works in metapool and traditional validator pools
+    /// NOTE: Unstaking here will schedule the automatic withdrawal in future epoch
+    ///
+    /// ```bash
+    /// near call treasury.testnet unstake '{"pool_account_id": "steak.factory.testnet", "amount": "100000000000000000000000000"}' --accountId treasury.testnet
+    ///
+    /// OR, to unstake ALL:
+    ///
+    /// near call treasury.testnet unstake '{"pool_account_id": "steak.factory.testnet"}' --accountId treasury.testnet
+    /// ```
+    pub fn unstake(&mut self, pool_account_id: AccountId, amount: Option<U128>) {
+        self.assert_owner();
+        let p = self.internal_unstake(pool_account_id, amount);
         env::promise_return(p);
     }
 
-    /// Unstake any liquid staked near tokens for NEAR. Useful for situations that require immediate access to NEAR.
-    /// NOTE: This is a 3 part process:
-    ///       1. Get the staking balance
-    ///       2. Get the amount available to withdraw
-    ///       3. Make the actual liquid_unstaking
-    /// NOTE: The amount is the liquid staked asset, not NEAR
+    /// Withdraw unstaked balance from a pool, works in metapool and traditional validator pools
+    /// Claims every unstake tranche that has matured, others stay queued
+    /// NOTE: Croncat calls this once the estimated unlock time passes, if still locked it gets rescheduled
     ///
     /// ```bash
-    /// near call treasury.testnet liquid_unstake '{"pool_account_id": "steak.factory.testnet", "amount": "100000000000000000000000000"}' --accountId treasury.testnet
+    /// near call treasury.testnet withdraw '{"pool_account_id": "steak.factory.testnet"}' --accountId treasury.testnet
     /// ```
-    pub fn liquid_unstake(&mut self, pool_account_id: AccountId, amount: Option<U128>) {
-        self.assert_owner();
-        let delegated_stake = self.stake_delegations.get(&pool_account_id);
-        assert!(delegated_stake.is_some(), "Delegation doesnt exist");
-        let delegation = delegated_stake.unwrap();
-        assert!(
-            delegation.liquid_unstake_function.is_some(),
-            "Liquid unstake unsupported for this pool"
+    pub fn withdraw(&mut self, pool_account_id: AccountId) {
+        self.assert_owner_or_croncat();
+        match self.internal_withdraw(pool_account_id.clone()) {
+            Some(p) => env::promise_return(p),
+            None => {
+                assert_ne!(
+                    env::predecessor_account_id(),
+                    self.owner_id,
+                    "No unstaked balance ready to withdraw"
+                );
+                // Epochs ran longer than estimated, try again for the next tranche to unlock
+                let unlock_epoch = self
+                    .stake_pending_delegations
+                    .get(&pool_account_id)
+                    .and_then(|tranches| tranches.iter().map(|t| t.unlock_epoch).min())
+                    .expect("Withdraw delegation doesnt exist");
+                self.internal_schedule_withdraw(pool_account_id, unlock_epoch);
+            }
+        }
+    }
+
+    /// CALLBACK for withdraw
+    /// Pools dont return the amount withdrawn, so a successful withdraw is followed by `get_account` to reconcile it
+    /// Logic:
+    /// - If the withdraw failed, the claimed tranches go back into the unstake queue
+    /// - Otherwise cancels the croncat tasks of the claimed tranches, so they dont fire again
+    /// NOTE: A one-off task that already fired is gone from croncat, its removal simply fails
+    #[private]
+    pub fn callback_withdraw(
+        &mut self,
+        pool_account_id: AccountId,
+        expected: U128,
+        unlock_epoch: u64,
+        task_hashes: Vec<Base64VecU8>,
+    ) {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "Expected 1 promise result."
         );
+        if !is_promise_success() {
+            self.internal_reconcile_withdraw(pool_account_id, expected, 0, unlock_epoch);
+            return;
+        }
+
+        if let Some(croncat_id) = self.croncat_id.clone() {
+            for task_hash in task_hashes {
+                external::croncat::remove_task(
+                    task_hash,
+                    croncat_id.clone(),
+                    NO_DEPOSIT,
+                    GAS_CRONCAT_REMOVE_TASK,
+                );
+            }
+        }
 
-        // First check if there are any staked balances
         let p1 = env::promise_create(
             pool_account_id.clone(),
-            "get_account_info",
+            "get_account",
             json!({
                 "account_id": env::current_account_id(),
             })
             .to_string()
             .as_bytes(),
             NO_DEPOSIT,
-            GAS_STAKE_LIQUID_UNSTAKE_VIEW,
+            GAS_STAKE_GET_STAKE_BALANCE,
         );
-
         let p2 = env::promise_then(
             p1,
             env::current_account_id(),
-            "callback_liquid_unstake",
+            "callback_withdraw_reconcile",
             json!({
                 "pool_account_id": pool_account_id,
-                "amount": amount,
+                "expected": expected,
+                "unlock_epoch": unlock_epoch,
             })
             .to_string()
             .as_bytes(),
             NO_DEPOSIT,
-            GAS_STAKE_LIQUID_UNSTAKE_CALLBACK,
+            GAS_STAKE_WITHDRAW_RECONCILE_CALLBACK,
         );
-
         env::promise_return(p2);
     }
 
-    /// 2. CALLBACK for get_account_info - which returns the amount of near staked
+    /// CALLBACK for the `get_account` following a withdraw
+    /// Logic:
+    /// - Any unstaked balance the pool holds beyond the tranches still queued was not paid out
+    /// - Records the rest as received against the pool totals
+    /// - The shortfall goes back into the unstake queue & gets a withdraw scheduled, so it can be withdrawn again
+    /// NOTE: If the pool balance can't be read, the withdraw is taken as paid out in full
+    #[private]
+    pub fn callback_withdraw_reconcile(
+        &mut self,
+        pool_account_id: AccountId,
+        expected: U128,
+        unlock_epoch: u64,
+    ) {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "Expected 1 promise result."
+        );
+        let mut received = expected.0;
+        if let PromiseResult::Successful(result) = env::promise_result(0) {
+            if let Ok(pool_balance) = serde_json::de::from_slice::<external::PoolBalance>(&result) {
+                if self.stake_delegations.get(&pool_account_id).is_some() {
+                    let delegation =
+                        self.internal_update_delegation_balance(&pool_account_id, &pool_balance);
+                    self.stake_delegations.insert(&pool_account_id, &delegation);
+                }
+                let held = pool_balance
+                    .unstaked_balance
+                    .0
+                    .saturating_sub(self.get_unstaking_balance(&pool_account_id));
+                received = expected.0.saturating_sub(held);
+            }
+        }
+
+        self.internal_reconcile_withdraw(pool_account_id, expected, received, unlock_epoch);
+    }
+
+    /// CALLBACK for the croncat create_task of a scheduled withdraw
+    /// Keeps the task hash on the tranches it withdraws, so the task can be cancelled later
+    /// NOTE: If the tranches moved to a later unlock in the meantime, the task is cancelled instead
+    #[private]
+    pub fn callback_create_withdraw_task(&mut self, pool_account_id: AccountId, unlock_epoch: u64) {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "Expected 1 promise result."
+        );
+
+        if let PromiseResult::Successful(result) = env::promise_result(0) {
+            let task_hash: Base64VecU8 =
+                serde_json::de::from_slice(&result).expect("Could not get task hash");
+            let mut tranches = self
+                .stake_pending_delegations
+                .get(&pool_account_id)
+                .unwrap_or_default();
+            let mut kept = false;
+            for tranche in tranches.iter_mut() {
+                if tranche.unlock_epoch == unlock_epoch {
+                    tranche.task_hash = Some(task_hash.clone());
+                    kept = true;
+                }
+            }
+            if kept {
+                self.stake_pending_delegations
+                    .insert(&pool_account_id, &tranches);
+            } else if let Some(croncat_id) = self.croncat_id.clone() {
+                external::croncat::remove_task(
+                    task_hash,
+                    croncat_id,
+                    NO_DEPOSIT,
+                    GAS_CRONCAT_REMOVE_TASK,
+                );
+            }
+        }
+    }
+
+    /// Unstake any liquid staked near tokens for NEAR. Useful for situations that require immediate access to NEAR.
+    /// NOTE: This is a 3 part process:
+    ///       1. Get the staking balance
+    ///       2. Get the amount available to withdraw, again for just the shares needed if an amount is given
+    ///       3. Make the actual liquid_unstaking
+    /// NOTE: The amount is in NEAR, only the shares needed to receive it after fees get sold
+    ///
+    /// ```bash
+    /// near call treasury.testnet liquid_unstake '{"pool_account_id": "steak.factory.testnet", "amount": "100000000000000000000000000"}' --accountId treasury.testnet
+    /// ```
+    pub fn liquid_unstake(&mut self, pool_account_id: AccountId, amount: Option<U128>) {
+        self.assert_owner();
+        let p = self.internal_liquid_unstake(pool_account_id, amount);
+        env::promise_return(p);
+    }
+
+    /// 2. CALLBACK for the provider balance view - which returns the amount of shares held
     #[private]
     pub fn callback_liquid_unstake(&mut self, pool_account_id: AccountId, amount: Option<U128>) {
         is_promise_success();
@@ -658,28 +1253,25 @@ impl Contract {
                 unreachable!()
             }
             PromiseResult::Successful(result) => {
+                let provider = self
+                    .stake_delegations
+                    .get(&pool_account_id)
+                    .and_then(|delegation| delegation.liquid_staking_provider)
+                    .expect("Liquid unstake unsupported for this pool");
+
                 // Attempt to parse the returned account balances
-                let pool_balance: external::MetaPoolBalance = serde_json::de::from_slice(&result)
-                    .expect("Could not get balance from stake pool");
-                log!("pool_balance {:?}", pool_balance);
+                let (shares, value) = provider.parse_balance(&result);
 
                 // Double check values before going forward
-                assert!(pool_balance.st_near.0 > 0, "No st_near balance");
-                assert!(
-                    pool_balance.valued_st_near.0 > 0,
-                    "No valued_st_near balance"
-                );
-                let st_near: U128 = pool_balance.st_near;
+                assert!(shares > 0, "No liquid staked balance");
+                assert!(value > 0, "No liquid staked value");
+                let shares = U128::from(shares);
 
                 // First check if there are any staked balances
                 let p1 = env::promise_create(
                     pool_account_id.clone(),
-                    "get_near_amount_sell_stnear",
-                    json!({
-                        "stnear_to_sell": st_near,
-                    })
-                    .to_string()
-                    .as_bytes(),
+                    provider.quote_view(),
+                    provider.quote_args(shares).as_bytes(),
                     NO_DEPOSIT,
                     GAS_STAKE_LIQUID_UNSTAKE_FEE_VIEW,
                 );
@@ -691,7 +1283,8 @@ impl Contract {
                     json!({
                         "pool_account_id": pool_account_id,
                         "amount": amount,
-                        "st_near": st_near,
+                        "shares": shares,
+                        "value": U128::from(value),
                     })
                     .to_string()
                     .as_bytes(),
@@ -707,13 +1300,19 @@ impl Contract {
         }
     }
 
-    /// 3. CALLBACK for get_near_amount_sell_stnear - which returns the amount of near that can be unstaked
+    /// 3. CALLBACK for the provider quote view - which returns the NEAR received for selling all shares, after fees
+    /// Logic:
+    /// - If no amount, sells all shares for the quoted NEAR
+    /// - If amount, solves for the shares needed to receive at least that amount after fees, and quotes those
+    /// - If the amount needs all shares, sells all shares for the quoted NEAR
+    /// NOTE: Aborts if the fee on the quote of the shares sold is above the max_liquid_unstake_fee_bps setting
     #[private]
     pub fn callback_liquid_unstake_final(
         &mut self,
         pool_account_id: AccountId,
         amount: Option<U128>,
-        st_near: U128,
+        shares: U128,
+        value: U128,
     ) {
         is_promise_success();
 
@@ -724,52 +1323,90 @@ impl Contract {
             }
             PromiseResult::Successful(result) => {
                 // Attempt to parse the returned available balance
-                let mut pool_min_expected_near: U128 = serde_json::de::from_slice(&result)
+                let quote: U128 = serde_json::de::from_slice(&result)
                     .expect("Could not get amount from stake pool");
 
                 // Double check values before going forward
-                assert!(pool_min_expected_near.0 > 0, "No st_near to unstake");
-                let mut st_near_to_burn = st_near;
-
-                // If no amount specified, simply unstake all st_near
-                // IF amount, compare to use the maximum unstakable that matches user amounts
-                // TODO: This is always returning with less NEAR than expected because of fees being taken out, need to add fee to amount so ratio can include fee
-                if amount.is_some() {
-                    // Get amount ratio, then desired st_near from ratio
-                    // limit to maximum st_near balance
-                    let denominator = 100;
-                    let desired_amount = u128::from(amount.unwrap().0).saturating_mul(denominator);
-                    let ratio = desired_amount.div_euclid(pool_min_expected_near.0);
-                    let st_unstake_amount =
-                        u128::from(st_near.0.saturating_mul(ratio)).div_euclid(denominator);
-                    if st_near.0 > st_unstake_amount {
-                        st_near_to_burn = U128::from(st_unstake_amount);
-                        pool_min_expected_near = U128::from(
-                            u128::from(pool_min_expected_near.0.saturating_mul(ratio))
-                                .div_euclid(denominator),
+                assert!(quote.0 > 0, "No shares to unstake");
+
+                // Solve for the shares to burn, rounding up so the NEAR received covers the amount
+                if let Some(amount) = amount {
+                    assert_ne!(amount.0, 0, "Nothing to unstake");
+                    let shares_needed = utils::mul_div_ceil(amount.0, shares.0, quote.0);
+                    if shares_needed < shares.0 {
+                        // NEAR value of the needed shares, before fees
+                        let value_needed = utils::mul_div_ceil(shares_needed, value.0, shares.0);
+                        let provider = self
+                            .stake_delegations
+                            .get(&pool_account_id)
+                            .and_then(|delegation| delegation.liquid_staking_provider)
+                            .expect("Liquid unstake unsupported for this pool");
+                        let p1 = env::promise_create(
+                            pool_account_id.clone(),
+                            provider.quote_view(),
+                            provider.quote_args(U128::from(shares_needed)).as_bytes(),
+                            NO_DEPOSIT,
+                            GAS_STAKE_LIQUID_UNSTAKE_FEE_VIEW,
                         );
+                        let p2 = env::promise_then(
+                            p1,
+                            env::current_account_id(),
+                            "callback_liquid_unstake_quote",
+                            json!({
+                                "pool_account_id": pool_account_id,
+                                "amount": amount,
+                                "shares": U128::from(shares_needed),
+                                "value": U128::from(value_needed),
+                            })
+                            .to_string()
+                            .as_bytes(),
+                            NO_DEPOSIT,
+                            GAS_STAKE_LIQUID_UNSTAKE_CALLBACK_QUOTE,
+                        );
+                        env::promise_return(p2);
+                        return;
                     }
                 }
 
-                // We have some balances, attempt to unstake
-                let delegation = self
-                    .stake_delegations
-                    .get(&pool_account_id)
-                    .expect("Delegation doesnt exist");
-                let p1 = env::promise_create(
-                    pool_account_id.clone(),
-                    &delegation.liquid_unstake_function.unwrap(),
-                    json!({
-                        "st_near_to_burn": st_near_to_burn,
-                        "min_expected_near": pool_min_expected_near,
-                    })
-                    .to_string()
-                    .as_bytes(),
-                    NO_DEPOSIT,
-                    GAS_STAKE_LIQUID_UNSTAKE_POOL_CALL,
-                );
+                let p =
+                    self.internal_liquid_unstake_sell(pool_account_id, shares, value, quote, quote);
+                env::promise_return(p);
+            }
+            PromiseResult::Failed => {
+                // Fail me not, please
+            }
+        }
+    }
+
+    /// 4. CALLBACK for the provider quote view - which returns the NEAR received for selling the shares needed, after fees
+    /// Sells the shares, expecting at least the amount, or the quote if the rate dropped below it
+    #[private]
+    pub fn callback_liquid_unstake_quote(
+        &mut self,
+        pool_account_id: AccountId,
+        amount: U128,
+        shares: U128,
+        value: U128,
+    ) {
+        is_promise_success();
 
-                env::promise_return(p1);
+        match env::promise_result(0) {
+            PromiseResult::NotReady => {
+                unreachable!()
+            }
+            PromiseResult::Successful(result) => {
+                let quote: U128 = serde_json::de::from_slice(&result)
+                    .expect("Could not get amount from stake pool");
+                assert!(quote.0 > 0, "No shares to unstake");
+                let min_expected_near = U128::from(u128::min(amount.0, quote.0));
+                let p = self.internal_liquid_unstake_sell(
+                    pool_account_id,
+                    shares,
+                    value,
+                    quote,
+                    min_expected_near,
+                );
+                env::promise_return(p);
             }
             PromiseResult::Failed => {
                 // Fail me not, please
@@ -777,13 +1414,103 @@ impl Contract {
         }
     }
 
+    /// 5. CALLBACK for the provider unstake method
+    /// Reverts the staked balance & withdraw bookkeeping if the pool did not pay out, returns whether it did
+    #[private]
+    pub fn callback_liquid_unstake_pool(
+        &mut self,
+        pool_account_id: AccountId,
+        value_burned: U128,
+        amount: U128,
+        from_principal: U128,
+    ) -> bool {
+        assert_eq!(
+            env::promise_results_count(),
+            1,
+            "Expected 1 promise result."
+        );
+        if is_promise_success() {
+            return true;
+        }
+
+        if let Some(mut delegation) = self.stake_delegations.get(&pool_account_id) {
+            delegation.balance = delegation.balance.saturating_add(value_burned.0);
+            delegation.revert_withdraw(amount.0, from_principal.0);
+            self.stake_delegations.insert(&pool_account_id, &delegation);
+        }
+        false
+    }
+
+    /// Rewards & annualized yield per pool, and for the whole treasury
+    /// NOTE: Balances & yield are as of the latest get_staked_balance for each pool
+    ///
+    /// ```bash
+    /// near view treasury.testnet get_staking_rewards
+    /// ```
+    pub fn get_staking_rewards(&self) -> StakingRewardsReport {
+        let mut pools: Vec<StakeRewards> = Vec::new();
+        let mut total = StakeRewards {
+            pool_account_id: None,
+            total_deposited: U128::from(0),
+            total_withdrawn: U128::from(0),
+            principal: U128::from(0),
+            balance: U128::from(0),
+            rewards_realized: U128::from(0),
+            rewards_unrealized: U128::from(0),
+            apy_bps: None,
+        };
+        // Principal weighted, for the treasury wide yield
+        let mut apy_weighted = U256::from(0);
+        let mut apy_principal: Balance = 0;
+
+        for (pool_account_id, delegation) in self.stake_delegations.iter() {
+            let balance = delegation
+                .snapshots
+                .last()
+                .map(|s| s.balance)
+                .unwrap_or(delegation.balance + self.get_unstaking_balance(&pool_account_id));
+            let rewards_unrealized = balance.saturating_sub(delegation.principal);
+            let apy_bps = delegation.get_apy_bps();
+            if let Some(apy_bps) = apy_bps {
+                apy_weighted += U256::from(apy_bps) * U256::from(delegation.principal);
+                apy_principal = apy_principal.saturating_add(delegation.principal);
+            }
+
+            total.total_deposited.0 += delegation.total_deposited;
+            total.total_withdrawn.0 += delegation.total_withdrawn;
+            total.principal.0 += delegation.principal;
+            total.balance.0 += balance;
+            total.rewards_realized.0 += delegation.rewards_realized;
+            total.rewards_unrealized.0 += rewards_unrealized;
+
+            pools.push(StakeRewards {
+                pool_account_id: Some(pool_account_id),
+                total_deposited: U128::from(delegation.total_deposited),
+                total_withdrawn: U128::from(delegation.total_withdrawn),
+                principal: U128::from(delegation.principal),
+                balance: U128::from(balance),
+                rewards_realized: U128::from(delegation.rewards_realized),
+                rewards_unrealized: U128::from(rewards_unrealized),
+                apy_bps,
+            });
+        }
+
+        if apy_principal > 0 {
+            total.apy_bps = Some((apy_weighted / U256::from(apy_principal)).as_u64());
+        }
+
+        StakingRewardsReport { pools, total }
+    }
+
     /// Execute a yield harvest for staking pools that support it.
+    /// If a harvest token is configured (see `set_harvest_config`), runs the full pipeline:
+    /// harvest, refresh the token balance, swap to wNEAR if routed, unwrap, then restake into the pool
     ///
     /// ```bash
-    /// near call treasury.testnet yield_harvest '{"pool_account_id": "steak.factory.testnet"}' --accountId treasury.testnet
+    /// near call treasury.testnet yield_harvest '{"pool_account_id": "steak.factory.testnet"}' --accountId treasury.testnet --gas 300000000000000
     /// ```
     pub fn yield_harvest(&mut self, pool_account_id: AccountId) {
-        self.assert_owner();
+        self.assert_owner_or_croncat();
         let delegated_stake = self.stake_delegations.get(&pool_account_id);
         assert!(delegated_stake.is_some(), "Delegation doesnt exist");
         let delegation = delegated_stake.unwrap();
@@ -792,6 +1519,21 @@ impl Contract {
             "Yield unsupported for this pool"
         );
 
+        events::StakeUpdate {
+            pool_account_id: pool_account_id.clone(),
+            amount: None,
+        }
+        .emit_harvest();
+
+        if let Some(token_id) = delegation.harvest_token_id {
+            self.internal_harvest(
+                pool_account_id,
+                delegation.yield_function.unwrap(),
+                token_id,
+            );
+            return;
+        }
+
         // Make a yield harvest call, including yocto since most include FT that needs txns with priveledges
         let p = env::promise_create(
             pool_account_id,
@@ -804,3 +1546,1648 @@ impl Contract {
         env::promise_return(p);
     }
 }
+
+impl Contract {
+    /// Stakes into a pool, without checking the caller
+    pub(crate) fn internal_deposit_and_stake(
+        &mut self,
+        pool_account_id: AccountId,
+        stake_amount: Balance,
+    ) -> PromiseIndex {
+        // Stop if somehow we made it this far and have nothing to stake... RUDE
+        assert_ne!(stake_amount, 0, "Nothing to stake");
+        events::StakeUpdate {
+            pool_account_id: pool_account_id.clone(),
+            amount: Some(U128::from(stake_amount)),
+        }
+        .emit_stake();
+
+        let mut delegation = self
+            .stake_delegations
+            .get(&pool_account_id)
+            .expect("Stake delegation doesnt exist");
+        if delegation.start_block == 0 {
+            delegation.init_balance = stake_amount;
+            delegation.start_block = env::block_height();
+        }
+        delegation.balance = delegation.balance.saturating_add(stake_amount);
+        delegation.record_deposit(stake_amount);
+
+        // Update our local balance values, principal is kept apart so rewards can be computed
+        self.stake_delegations.insert(&pool_account_id, &delegation);
+
+        // Lastly, make the cross-contract call to DO the staking :D
+        let p = env::promise_create(
+            pool_account_id.clone(),
+            "deposit_and_stake",
+            json!({}).to_string().as_bytes(),
+            stake_amount,
+            GAS_STAKE_DEPOSIT_AND_STAKE,
+        );
+
+        // Revert the bookkeeping above if the pool does not take the NEAR
+        env::promise_then(
+            p,
+            env::current_account_id(),
+            "callback_deposit_and_stake",
+            json!({
+                "pool_account_id": pool_account_id,
+                "amount": U128::from(stake_amount),
+            })
+            .to_string()
+            .as_bytes(),
+            NO_DEPOSIT,
+            GAS_STAKE_DEPOSIT_AND_STAKE_CALLBACK,
+        )
+    }
+
+    /// Unstakes from a pool & schedules the withdraw, without checking the caller
+    pub(crate) fn internal_unstake(
+        &mut self,
+        pool_account_id: AccountId,
+        amount: Option<U128>,
+    ) -> PromiseIndex {
+        let pool_delegation = self.stake_delegations.get(&pool_account_id);
+        assert!(pool_delegation.is_some(), "Stake delegation doesnt exist");
+        let mut delegation = pool_delegation.unwrap();
+        let mut unstake_function = "unstake_all";
+
+        // Stop if somehow we made it this far and have nothing to unstake... RUDE
+        if amount.is_some() {
+            assert_ne!(amount.unwrap().0, 0, "Nothing to unstake");
+            unstake_function = "unstake";
+        }
+
+        // Update our local balance values, queueing a tranche so we know whats in process of long-form unstaking
+        let withdraw_balance = amount.map(|a| a.0).unwrap_or(delegation.balance);
+        delegation.balance = delegation.balance.saturating_sub(withdraw_balance);
+        self.stake_delegations.insert(&pool_account_id, &delegation);
+        let mut tranches = self
+            .stake_pending_delegations
+            .get(&pool_account_id)
+            .unwrap_or_default();
+        let unlock_epoch = env::epoch_height() + UNSTAKE_LOCK_EPOCHS;
+        // Tranches unlocking in the same epoch share a single scheduled withdraw
+        let scheduled = tranches.iter().any(|t| t.unlock_epoch == unlock_epoch);
+        let task_hash = tranches
+            .iter()
+            .find(|t| t.unlock_epoch == unlock_epoch)
+            .and_then(|t| t.task_hash.clone());
+
+        // Pools lock the whole unstaked balance again on every unstake, so earlier tranches now unlock with this one
+        let mut stale_task_hashes: Vec<Base64VecU8> = Vec::new();
+        for tranche in tranches.iter_mut() {
+            if tranche.unlock_epoch == unlock_epoch {
+                continue;
+            }
+            if let Some(stale) = tranche.task_hash.take() {
+                if !stale_task_hashes.contains(&stale) {
+                    stale_task_hashes.push(stale);
+                }
+            }
+            tranche.unlock_epoch = unlock_epoch;
+            tranche.task_hash = task_hash.clone();
+        }
+        tranches.push(UnstakeTranche {
+            amount: U128::from(withdraw_balance),
+            unlock_epoch,
+            block_timestamp: U64::from(env::block_timestamp()),
+            task_hash,
+        });
+        self.stake_pending_delegations
+            .insert(&pool_account_id, &tranches);
+
+        // Earlier withdraws would fire before the new unlock, timeout actions skip on their own
+        if let Some(croncat_id) = self.croncat_id.clone() {
+            for task_hash in stale_task_hashes {
+                external::croncat::remove_task(
+                    task_hash,
+                    croncat_id.clone(),
+                    NO_DEPOSIT,
+                    GAS_CRONCAT_REMOVE_TASK,
+                );
+            }
+        }
+        events::StakeUpdate {
+            pool_account_id: pool_account_id.clone(),
+            amount,
+        }
+        .emit_unstake();
+
+        // Lastly, make the cross-contract call to DO the unstaking :D
+        let p = env::promise_create(
+            pool_account_id.clone(),
+            &unstake_function,
+            json!({
+                "amount": amount,
+            })
+            .to_string()
+            .as_bytes(),
+            NO_DEPOSIT,
+            GAS_STAKE_UNSTAKE,
+        );
+
+        // Add withdraw scheduler, for when the unstaked balance unlocks
+        if !scheduled {
+            self.internal_schedule_withdraw(pool_account_id, unlock_epoch);
+        }
+
+        p
+    }
+
+    /// Records the NEAR received from a withdraw, any shortfall goes back into the unstake queue
+    pub(crate) fn internal_reconcile_withdraw(
+        &mut self,
+        pool_account_id: AccountId,
+        expected: U128,
+        received: Balance,
+        unlock_epoch: u64,
+    ) {
+        if let Some(mut delegation) = self.stake_delegations.get(&pool_account_id) {
+            delegation.record_withdraw(received);
+            self.stake_delegations.insert(&pool_account_id, &delegation);
+        }
+        if received == expected.0 {
+            return;
+        }
+
+        let restored = expected.0.saturating_sub(received);
+        if restored > 0 {
+            self.internal_restore_unstake_tranche(pool_account_id.clone(), restored, unlock_epoch);
+        }
+        events::StakeWithdrawReconcile {
+            pool_account_id,
+            expected,
+            received: U128::from(received),
+            restored: U128::from(restored),
+        }
+        .emit();
+    }
+
+    /// Puts an amount the pool did not pay out back in the unstake queue, and schedules its withdraw
+    /// NOTE: Joins the unlock of any tranches still queued, as the pool locks the whole unstaked balance together
+    pub(crate) fn internal_restore_unstake_tranche(
+        &mut self,
+        pool_account_id: AccountId,
+        amount: Balance,
+        unlock_epoch: u64,
+    ) {
+        let mut tranches = self
+            .stake_pending_delegations
+            .get(&pool_account_id)
+            .unwrap_or_default();
+        let queued_epoch = tranches.iter().map(|t| t.unlock_epoch).max();
+        let unlock_epoch = u64::max(unlock_epoch, queued_epoch.unwrap_or_default());
+        let task_hash = tranches
+            .iter()
+            .find(|t| t.unlock_epoch == unlock_epoch)
+            .and_then(|t| t.task_hash.clone());
+        tranches.insert(
+            0,
+            UnstakeTranche {
+                amount: U128::from(amount),
+                unlock_epoch,
+                block_timestamp: U64::from(env::block_timestamp()),
+                task_hash,
+            },
+        );
+        self.stake_pending_delegations
+            .insert(&pool_account_id, &tranches);
+
+        // Queued tranches at this unlock have a withdraw scheduled already
+        if queued_epoch != Some(unlock_epoch) {
+            self.internal_schedule_withdraw(pool_account_id, unlock_epoch);
+        }
+    }
+
+    /// Estimated timestamp (in nanos) an epoch starts at, based on the epoch length setting
+    pub(crate) fn get_epoch_timestamp_estimate(&self, epoch: u64) -> u64 {
+        let epochs_left = epoch.saturating_sub(env::epoch_height());
+        env::block_timestamp().saturating_add(epochs_left.saturating_mul(self.stake_epoch_length))
+    }
+
+    /// Schedules a withdraw for the estimated time an epoch starts
+    /// Uses a one-off croncat task if croncat is configured, otherwise a timeout action
+    pub(crate) fn internal_schedule_withdraw(
+        &mut self,
+        pool_account_id: AccountId,
+        unlock_epoch: u64,
+    ) {
+        // If the estimate has passed already, the pool was still locked, so wait at least one more epoch
+        let unlock_ts = u64::max(
+            self.get_epoch_timestamp_estimate(unlock_epoch),
+            env::block_timestamp().saturating_add(self.stake_epoch_length),
+        );
+
+        if let Some(croncat_id) = self.croncat_id.clone() {
+            external::croncat::create_task(
+                env::current_account_id().to_string(),
+                "withdraw".to_string(),
+                utils::timestamp_to_cron(unlock_ts),
+                Some(false),
+                Some(U128::from(NO_DEPOSIT)),
+                // Covers the withdraw, cancelling tasks & rescheduling if still locked
+                Some(u64::from(GAS_BASE_FEE + GAS_STAKE_WITHDRAW)),
+                Some(Base64VecU8::from(
+                    json!({
+                        "pool_account_id": pool_account_id,
+                    })
+                    .to_string()
+                    .as_bytes()
+                    .to_vec(),
+                )),
+                croncat_id,
+                CRONCAT_CREATE_TASK_FEE,
+                GAS_CRONCAT_CREATE_TASK,
+            )
+            .then(
+                Promise::new(env::current_account_id()).function_call(
+                    "callback_create_withdraw_task".to_string(),
+                    json!({
+                        "pool_account_id": pool_account_id,
+                        "unlock_epoch": unlock_epoch,
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NO_DEPOSIT,
+                    GAS_CRONCAT_WITHDRAW_TASK_CALLBACK,
+                ),
+            );
+        } else {
+            self.internal_insert_timeout_action(Action::new_timeout(
+                u128::from(unlock_ts),
+                actions::ActionType::StakeWithdraw {
+                    pool_account_id,
+                    unlock_epoch,
+                },
+            ));
+        }
+    }
+
+    /// Withdraws every matured unstake tranche of a pool, without checking the caller
+    /// Returns None if nothing has matured yet
+    pub(crate) fn internal_withdraw(&mut self, pool_account_id: AccountId) -> Option<PromiseIndex> {
+        let tranches = self
+            .stake_pending_delegations
+            .get(&pool_account_id)
+            .expect("Withdraw delegation doesnt exist");
+        let pool_delegation = self
+            .stake_delegations
+            .get(&pool_account_id)
+            .expect("Stake delegation doesnt exist");
+
+        // Split the matured tranches from those still locked
+        let (matured, locked): (Vec<UnstakeTranche>, Vec<UnstakeTranche>) = tranches
+            .into_iter()
+            .partition(|t| t.unlock_epoch <= env::epoch_height());
+        let withdraw_balance: Balance = matured.iter().map(|t| t.amount.0).sum();
+        if withdraw_balance == 0 {
+            return None;
+        }
+
+        // Clear the matured tranches, pool totals get updated on callback with the amount received
+        if locked.is_empty() {
+            self.stake_pending_delegations.remove(&pool_account_id);
+        } else {
+            self.stake_pending_delegations
+                .insert(&pool_account_id, &locked);
+        }
+        events::StakeUpdate {
+            pool_account_id: pool_account_id.clone(),
+            amount: Some(U128::from(withdraw_balance)),
+        }
+        .emit_withdraw();
+
+        // Lastly, make the cross-contract call to DO the withdraw :D
+        // Only withdraw the matured amount if some tranches are still locked
+        let p = if locked.is_empty() {
+            env::promise_create(
+                pool_account_id.clone(),
+                &pool_delegation.withdraw_function,
+                json!({}).to_string().as_bytes(),
+                NO_DEPOSIT,
+                GAS_STAKE_WITHDRAW_ALL,
+            )
+        } else {
+            env::promise_create(
+                pool_account_id.clone(),
+                "withdraw",
+                json!({
+                    "amount": U128::from(withdraw_balance),
+                })
+                .to_string()
+                .as_bytes(),
+                NO_DEPOSIT,
+                GAS_STAKE_WITHDRAW_ALL,
+            )
+        };
+
+        // Shortfalls get restored as a matured tranche, using the earliest unlock claimed
+        let unlock_epoch = matured
+            .iter()
+            .map(|t| t.unlock_epoch)
+            .min()
+            .unwrap_or_default();
+
+        // Tranches unlocking in the same epoch share a task
+        let mut task_hashes: Vec<Base64VecU8> = Vec::new();
+        for task_hash in matured.into_iter().filter_map(|t| t.task_hash) {
+            if !task_hashes.contains(&task_hash) {
+                task_hashes.push(task_hash);
+            }
+        }
+        let callback_gas = GAS_STAKE_WITHDRAW_CALLBACK.0
+            + GAS_CRONCAT_REMOVE_TASK.0 * task_hashes.len() as u64
+            + GAS_STAKE_GET_STAKE_BALANCE.0
+            + GAS_STAKE_WITHDRAW_RECONCILE_CALLBACK.0;
+
+        Some(env::promise_then(
+            p,
+            env::current_account_id(),
+            "callback_withdraw",
+            json!({
+                "pool_account_id": pool_account_id,
+                "expected": U128::from(withdraw_balance),
+                "unlock_epoch": unlock_epoch,
+                "task_hashes": task_hashes,
+            })
+            .to_string()
+            .as_bytes(),
+            NO_DEPOSIT,
+            Gas(callback_gas),
+        ))
+    }
+
+    /// Starts the liquid unstake process, without checking the caller
+    pub(crate) fn internal_liquid_unstake(
+        &mut self,
+        pool_account_id: AccountId,
+        amount: Option<U128>,
+    ) -> PromiseIndex {
+        let delegated_stake = self.stake_delegations.get(&pool_account_id);
+        assert!(delegated_stake.is_some(), "Delegation doesnt exist");
+        let delegation = delegated_stake.unwrap();
+        assert!(
+            delegation.liquid_unstake_function.is_some(),
+            "Liquid unstake unsupported for this pool"
+        );
+        let provider = delegation
+            .liquid_staking_provider
+            .expect("Liquid unstake unsupported for this pool");
+
+        // First check if there are any staked balances
+        let p1 = env::promise_create(
+            pool_account_id.clone(),
+            provider.balance_view(),
+            provider.balance_args(&env::current_account_id()).as_bytes(),
+            NO_DEPOSIT,
+            GAS_STAKE_LIQUID_UNSTAKE_VIEW,
+        );
+
+        env::promise_then(
+            p1,
+            env::current_account_id(),
+            "callback_liquid_unstake",
+            json!({
+                "pool_account_id": pool_account_id,
+                "amount": amount,
+            })
+            .to_string()
+            .as_bytes(),
+            NO_DEPOSIT,
+            GAS_STAKE_LIQUID_UNSTAKE_CALLBACK,
+        )
+    }
+
+    /// Sells liquid staked shares for NEAR, if the fee on their quote is within the max_liquid_unstake_fee_bps setting
+    /// NOTE: Liquid unstaking is immediate, so the expected NEAR counts as withdrawn, the fee comes out of the staked value
+    /// NOTE: All of this gets reverted on callback, if the pool does not pay out
+    pub(crate) fn internal_liquid_unstake_sell(
+        &mut self,
+        pool_account_id: AccountId,
+        shares: U128,
+        value: U128,
+        quote: U128,
+        min_expected_near: U128,
+    ) -> PromiseIndex {
+        let fee_bps =
+            utils::mul_div_ceil(value.0.saturating_sub(quote.0), BPS_DENOMINATOR, value.0);
+        assert!(
+            fee_bps <= u128::from(self.max_liquid_unstake_fee_bps),
+            "Liquid unstake fee of {} bps is above the maximum of {} bps",
+            fee_bps,
+            self.max_liquid_unstake_fee_bps
+        );
+
+        let mut delegation = self
+            .stake_delegations
+            .get(&pool_account_id)
+            .expect("Delegation doesnt exist");
+        delegation.balance = delegation.balance.saturating_sub(value.0);
+        let from_principal = delegation.record_withdraw(min_expected_near.0);
+        self.stake_delegations.insert(&pool_account_id, &delegation);
+        events::StakeUpdate {
+            pool_account_id: pool_account_id.clone(),
+            amount: Some(min_expected_near),
+        }
+        .emit_liquid_unstake();
+        let provider = delegation
+            .liquid_staking_provider
+            .expect("Liquid unstake unsupported for this pool");
+        let p1 = env::promise_create(
+            pool_account_id.clone(),
+            &delegation.liquid_unstake_function.unwrap(),
+            provider.unstake_args(shares, min_expected_near).as_bytes(),
+            NO_DEPOSIT,
+            GAS_STAKE_LIQUID_UNSTAKE_POOL_CALL,
+        );
+        env::promise_then(
+            p1,
+            env::current_account_id(),
+            "callback_liquid_unstake_pool",
+            json!({
+                "pool_account_id": pool_account_id,
+                "value_burned": value,
+                "amount": min_expected_near,
+                "from_principal": U128::from(from_principal),
+            })
+            .to_string()
+            .as_bytes(),
+            NO_DEPOSIT,
+            GAS_STAKE_LIQUID_UNSTAKE_POOL_CALLBACK,
+        )
+    }
+
+    /// Applies the balances reported by a pool's `get_account`, returning the updated delegation for storing
+    pub(crate) fn internal_update_delegation_balance(
+        &mut self,
+        pool_account_id: &AccountId,
+        pool_balance: &external::PoolBalance,
+    ) -> StakeDelegation {
+        let mut delegation = self
+            .stake_delegations
+            .get(pool_account_id)
+            .expect("No delegation found");
+        delegation.balance = pool_balance.staked_balance.0;
+        delegation.unstaked_balance = pool_balance.unstaked_balance.0;
+        delegation.can_withdraw = pool_balance.can_withdraw;
+        delegation.last_refreshed = env::block_timestamp();
+        delegation.record_snapshot(
+            pool_balance
+                .staked_balance
+                .0
+                .saturating_add(pool_balance.unstaked_balance.0),
+        );
+        delegation
+    }
+
+    /// Move the delegations refresh on to the next page, or finish it after the last page
+    pub(crate) fn internal_stake_refresh_advance(&mut self, next_index: u64) {
+        if next_index >= self.stake_delegations.len() {
+            self.stake_refresh_index = 0;
+        } else {
+            self.stake_refresh_index = next_index;
+        }
+    }
+
+    /// Sum of the unstake tranches of a pool
+    pub(crate) fn get_unstaking_balance(&self, pool_account_id: &AccountId) -> Balance {
+        self.stake_pending_delegations
+            .get(pool_account_id)
+            .unwrap_or_default()
+            .iter()
+            .map(|t| t.amount.0)
+            .sum()
+    }
+
+    /// Staked balance & weight per pool, not including balances currently unstaking
+    fn get_pool_stakes(&self) -> Vec<(AccountId, u64, Balance)> {
+        self.stake_delegations
+            .iter()
+            .map(|(pool_account_id, delegation)| {
+                (pool_account_id, delegation.weight, delegation.balance)
+            })
+            .collect()
+    }
+
+    /// Weighted target stake of a pool, given the total stake across all pools
+    fn get_pool_target(weight: u64, total_weight: u64, total_staked: Balance) -> Balance {
+        if total_weight == 0 {
+            return 0;
+        }
+        utils::calc_percent(weight, total_weight, total_staked)
+    }
+
+    /// Spreads new stake across pools, in proportion to how far each is below its weighted target
+    /// NOTE: Returns no allocations if no pool has a weight
+    pub(crate) fn allocate_stake(&self, amount: Balance) -> Vec<(AccountId, Balance)> {
+        let pools = self.get_pool_stakes();
+        let total_weight: u64 = pools.iter().map(|(_, weight, _)| weight).sum();
+        if total_weight == 0 {
+            return Vec::new();
+        }
+        let total_staked: Balance = pools.iter().map(|(_, _, staked)| staked).sum();
+        let target_total = total_staked.saturating_add(amount);
+
+        let deficits: Vec<Balance> = pools
+            .iter()
+            .map(|(_, weight, staked)| {
+                Self::get_pool_target(*weight, total_weight, target_total).saturating_sub(*staked)
+            })
+            .collect();
+        let total_deficit: Balance = deficits.iter().sum();
+        if total_deficit == 0 {
+            return Vec::new();
+        }
+
+        let mut allocations: Vec<(AccountId, Balance)> = Vec::new();
+        let mut allocated: Balance = 0;
+        for (i, (pool_account_id, _, _)) in pools.iter().enumerate() {
+            let share = (U256::from(amount) * U256::from(deficits[i]) / U256::from(total_deficit))
+                .as_u128();
+            allocated += share;
+            allocations.push((pool_account_id.clone(), share));
+        }
+
+        // Rounding leftovers go to the pool furthest below its target
+        if let Some(i) = (0..deficits.len()).max_by_key(|i| deficits[*i]) {
+            allocations[i].1 += amount.saturating_sub(allocated);
+        }
+
+        allocations.into_iter().filter(|(_, a)| *a > 0).collect()
+    }
+
+    /// Takes unstakes from the pools most above their weighted target first
+    pub(crate) fn allocate_unstake(&self, amount: Balance) -> Vec<(AccountId, Balance)> {
+        let pools = self.get_pool_stakes();
+        let total_weight: u64 = pools.iter().map(|(_, weight, _)| weight).sum();
+        let total_staked: Balance = pools.iter().map(|(_, _, staked)| staked).sum();
+        let target_total = total_staked.saturating_sub(amount);
+
+        // (pool, excess above target, staked)
+        let mut excesses: Vec<(AccountId, Balance, Balance)> = pools
+            .into_iter()
+            .map(|(pool_account_id, weight, staked)| {
+                let target = Self::get_pool_target(weight, total_weight, target_total);
+                (pool_account_id, staked.saturating_sub(target), staked)
+            })
+            .collect();
+        excesses.sort_by(|a, b| b.1.cmp(&a.1));
+
+        let mut remaining = u128::min(amount, total_staked);
+        let mut amounts: Vec<Balance> = vec![0; excesses.len()];
+        for (i, (_, excess, _)) in excesses.iter().enumerate() {
+            let take = u128::min(*excess, remaining);
+            amounts[i] = take;
+            remaining -= take;
+        }
+
+        // Rounding leftovers come from whichever pools still have stake
+        for (i, (_, _, staked)) in excesses.iter().enumerate() {
+            let take = u128::min(staked.saturating_sub(amounts[i]), remaining);
+            amounts[i] += take;
+            remaining -= take;
+        }
+
+        excesses
+            .into_iter()
+            .zip(amounts)
+            .filter(|(_, a)| *a > 0)
+            .map(|((pool_account_id, _, _), a)| (pool_account_id, a))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    fn set_epoch(epoch_height: u64) {
+        testing_env!(get_context(accounts(1)).epoch_height(epoch_height).build());
+    }
+
+    fn setup(epoch_height: u64) -> Contract {
+        let contract = setup_contract();
+        set_epoch(epoch_height);
+        contract
+    }
+
+    fn add_pool(
+        contract: &mut Contract,
+        pool_account_id: AccountId,
+        weight: u64,
+        balance: Balance,
+    ) {
+        contract.add_staking_pool(
+            pool_account_id.clone(),
+            None,
+            None,
+            None,
+            None,
+            Some(weight),
+        );
+        let mut delegation = contract.stake_delegations.get(&pool_account_id).unwrap();
+        delegation.balance = balance;
+        contract
+            .stake_delegations
+            .insert(&pool_account_id, &delegation);
+    }
+
+    fn set_balances(liquid: Balance, prepaid_gas: u64) {
+        testing_env!(get_context(accounts(1))
+            .account_balance(liquid)
+            .prepaid_gas(Gas(prepaid_gas))
+            .build());
+    }
+
+    fn add_tranche(contract: &mut Contract, pool_account_id: AccountId, amount: Balance) {
+        contract.stake_pending_delegations.insert(
+            &pool_account_id,
+            &vec![UnstakeTranche {
+                amount: U128::from(amount),
+                unlock_epoch: 1 + UNSTAKE_LOCK_EPOCHS,
+                block_timestamp: U64::from(BLOCK_START_TS),
+                task_hash: None,
+            }],
+        );
+    }
+
+    fn get_plan_steps(plan: &RebalancePlan) -> Vec<(AccountId, serde_json::Value, u128)> {
+        plan.steps
+            .iter()
+            .map(|step| {
+                (
+                    step.pool_account_id.clone(),
+                    serde_json::to_value(&step.action).unwrap(),
+                    step.amount.0,
+                )
+            })
+            .collect()
+    }
+
+    fn pool_balance_result(staked: Balance, unstaked: Balance) -> PromiseResult {
+        json_result(&json!({
+            "account_id": accounts(0),
+            "staked_balance": U128::from(staked),
+            "unstaked_balance": U128::from(unstaked),
+            "can_withdraw": false,
+        }))
+    }
+
+    fn add_liquid_pool(contract: &mut Contract, pool_account_id: AccountId, balance: Balance) {
+        contract.add_staking_pool(
+            pool_account_id.clone(),
+            None,
+            Some(LiquidStakingProvider::MetaPool),
+            None,
+            None,
+            None,
+        );
+        contract.internal_deposit_and_stake(pool_account_id, balance);
+    }
+
+    fn get_tranche_epochs(contract: &Contract, pool_account_id: &AccountId) -> Vec<(u128, u64)> {
+        contract
+            .stake_pending_delegations
+            .get(pool_account_id)
+            .unwrap_or_default()
+            .iter()
+            .map(|t| (t.amount.0, t.unlock_epoch))
+            .collect()
+    }
+
+    fn get_scheduled_withdraws(contract: &Contract) -> usize {
+        contract
+            .timeout_actions
+            .iter()
+            .map(|(_, actions)| actions.len())
+            .sum()
+    }
+
+    #[test]
+    fn test_allocate_stake_fills_deficits() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 1, 0);
+        add_pool(&mut contract, accounts(3), 1, 10 * ONE_NEAR);
+        assert_eq!(
+            contract.allocate_stake(10 * ONE_NEAR),
+            vec![(accounts(2), 10 * ONE_NEAR)]
+        );
+    }
+
+    #[test]
+    fn test_allocate_stake_by_weight() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 2, 0);
+        add_pool(&mut contract, accounts(3), 1, 0);
+        assert_eq!(
+            contract.allocate_stake(30 * ONE_NEAR + 1),
+            vec![
+                (accounts(2), 20 * ONE_NEAR + 1),
+                (accounts(3), 10 * ONE_NEAR)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_allocate_stake_without_weights() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 0, 0);
+        assert!(contract.allocate_stake(10 * ONE_NEAR).is_empty());
+    }
+
+    #[test]
+    fn test_allocate_unstake_takes_excess_first() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 1, 10 * ONE_NEAR);
+        add_pool(&mut contract, accounts(3), 1, 30 * ONE_NEAR);
+        assert_eq!(
+            contract.allocate_unstake(20 * ONE_NEAR),
+            vec![(accounts(3), 20 * ONE_NEAR)]
+        );
+        assert_eq!(
+            contract.allocate_unstake(30 * ONE_NEAR),
+            vec![(accounts(3), 25 * ONE_NEAR), (accounts(2), 5 * ONE_NEAR)]
+        );
+    }
+
+    #[test]
+    fn test_allocate_unstake_from_unweighted_pools() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 1, 10 * ONE_NEAR);
+        add_pool(&mut contract, accounts(3), 0, 5 * ONE_NEAR);
+        assert_eq!(
+            contract.allocate_unstake(6 * ONE_NEAR),
+            vec![(accounts(3), 5 * ONE_NEAR), (accounts(2), ONE_NEAR)]
+        );
+
+        // Never more than the pools hold
+        let unstakes = contract.allocate_unstake(100 * ONE_NEAR);
+        assert_eq!(
+            unstakes.iter().map(|(_, a)| a).sum::<Balance>(),
+            15 * ONE_NEAR
+        );
+    }
+
+    #[test]
+    fn test_set_staking_pool_weight() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 1, 0);
+        add_pool(&mut contract, accounts(3), 1, 0);
+        contract.set_staking_pool_weight(accounts(2), 0);
+        contract.set_staking_pool_weight(accounts(3), 3);
+        assert_eq!(
+            contract.stake_delegations.get(&accounts(2)).unwrap().weight,
+            0
+        );
+        assert_eq!(
+            contract.stake_delegations.get(&accounts(3)).unwrap().weight,
+            3
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "At least one pool needs a weight")]
+    fn test_set_staking_pool_weight_keeps_one() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 0, 0);
+        add_pool(&mut contract, accounts(3), 1, 0);
+        contract.set_staking_pool_weight(accounts(3), 0);
+    }
+
+    #[test]
+    fn test_rebalance_plan_stakes_excess() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 1, 0);
+        add_pool(&mut contract, accounts(3), 1, 0);
+        set_balances(100 * ONE_NEAR, 300_000_000_000_000);
+
+        let plan = contract.get_stake_rebalance_plan();
+        assert_eq!(plan.liquid_ideal.0, 30 * ONE_NEAR);
+        assert_eq!(plan.liquid_deviation.0, 5 * ONE_NEAR);
+        assert_eq!(
+            get_plan_steps(&plan),
+            vec![
+                (accounts(2), json!("stake"), 35 * ONE_NEAR),
+                (accounts(3), json!("stake"), 35 * ONE_NEAR)
+            ]
+        );
+        assert!(contract.needs_stake_rebalance().0);
+
+        // Within deviation, nothing to do
+        set_balances(33 * ONE_NEAR, 300_000_000_000_000);
+        let mut delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        delegation.balance = 67 * ONE_NEAR;
+        contract.stake_delegations.insert(&accounts(2), &delegation);
+        assert!(contract.get_stake_rebalance_plan().steps.is_empty());
+        assert!(!contract.needs_stake_rebalance().0);
+    }
+
+    #[test]
+    fn test_rebalance_plan_unstakes_shortfall() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 1, 80 * ONE_NEAR);
+        set_balances(20 * ONE_NEAR, 300_000_000_000_000);
+        assert_eq!(
+            get_plan_steps(&contract.get_stake_rebalance_plan()),
+            vec![(accounts(2), json!("unstake"), 10 * ONE_NEAR)]
+        );
+
+        // Pending unstakes already cover the shortfall
+        let mut delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        delegation.balance = 70 * ONE_NEAR;
+        contract.stake_delegations.insert(&accounts(2), &delegation);
+        add_tranche(&mut contract, accounts(2), 10 * ONE_NEAR);
+        let plan = contract.get_stake_rebalance_plan();
+        assert_eq!(plan.unstaking.0, 10 * ONE_NEAR);
+        assert!(plan.steps.is_empty());
+    }
+
+    #[test]
+    fn test_rebalance_plan_extreme_liquid_unstakes() {
+        let mut contract = setup(1);
+        contract.add_staking_pool(
+            accounts(2),
+            Some("liquid_unstake".to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+        add_pool(&mut contract, accounts(3), 1, 0);
+        let mut delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        delegation.balance = 90 * ONE_NEAR;
+        contract.stake_delegations.insert(&accounts(2), &delegation);
+        add_tranche(&mut contract, accounts(2), 10 * ONE_NEAR);
+        set_balances(10 * ONE_NEAR, 300_000_000_000_000);
+
+        // Liquid unstaking is immediate, so the pending unstake is not counted against the shortfall
+        assert_eq!(
+            get_plan_steps(&contract.get_stake_rebalance_plan()),
+            vec![(accounts(2), json!("liquid_unstake"), 23 * ONE_NEAR)]
+        );
+    }
+
+    #[test]
+    fn test_auto_stake_limited_by_gas() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 1, 0);
+        add_pool(&mut contract, accounts(3), 1, 0);
+        set_balances(100 * ONE_NEAR, 100_000_000_000_000);
+        contract.auto_stake();
+
+        // Only one stake fits, the other is planned again on the next call
+        let events = get_events("stake_plan");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["steps"].as_array().unwrap().len(), 1);
+        assert_eq!(events[0]["steps"][0]["action"], "stake");
+        let staked: Balance = contract
+            .stake_delegations
+            .values()
+            .map(|delegation| delegation.balance)
+            .sum();
+        assert_eq!(staked, 35 * ONE_NEAR);
+
+        set_balances(100 * ONE_NEAR, 300_000_000_000_000);
+        contract.auto_stake();
+        assert_eq!(
+            get_events("stake_plan")[0]["steps"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gas attached")]
+    fn test_auto_stake_not_enough_gas() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 1, 0);
+        set_balances(100 * ONE_NEAR, 50_000_000_000_000);
+        contract.auto_stake();
+    }
+
+    #[test]
+    fn test_withdraw_accounting() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 1, 10 * ONE_NEAR);
+        let mut delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        delegation.record_deposit(10 * ONE_NEAR);
+
+        // Withdraws count against principal first, the rest are realized rewards
+        let from_principal = delegation.record_withdraw(12 * ONE_NEAR);
+        assert_eq!(from_principal, 10 * ONE_NEAR);
+        assert_eq!(delegation.principal, 0);
+        assert_eq!(delegation.rewards_realized, 2 * ONE_NEAR);
+        assert_eq!(delegation.get_rewards(0), 2 * ONE_NEAR);
+
+        delegation.revert_withdraw(12 * ONE_NEAR, from_principal);
+        assert_eq!(delegation.principal, 10 * ONE_NEAR);
+        assert_eq!(delegation.rewards_realized, 0);
+        assert_eq!(delegation.total_withdrawn, 0);
+
+        delegation.revert_deposit(10 * ONE_NEAR);
+        assert_eq!(delegation.balance, 0);
+        assert_eq!(delegation.principal, 0);
+        assert_eq!(delegation.total_deposited, 0);
+    }
+
+    #[test]
+    fn test_deposit_and_stake_callback() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 1, 0);
+        contract.internal_deposit_and_stake(accounts(2), 10 * ONE_NEAR);
+        let mut context = get_context(accounts(0));
+        set_callback_context(&mut context, vec![PromiseResult::Successful(vec![])]);
+        assert!(contract.callback_deposit_and_stake(accounts(2), U128::from(10 * ONE_NEAR)));
+        let delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        assert_eq!(delegation.balance, 10 * ONE_NEAR);
+        assert_eq!(delegation.principal, 10 * ONE_NEAR);
+        assert_eq!(delegation.start_block, BLOCK_START_BLOCK);
+
+        // A later deposit the pool did not take only reverts itself
+        contract.internal_deposit_and_stake(accounts(2), 5 * ONE_NEAR);
+        set_callback_context(&mut context, vec![PromiseResult::Failed]);
+        assert!(!contract.callback_deposit_and_stake(accounts(2), U128::from(5 * ONE_NEAR)));
+        let delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        assert_eq!(delegation.balance, 10 * ONE_NEAR);
+        assert_eq!(delegation.principal, 10 * ONE_NEAR);
+        assert_eq!(delegation.total_deposited, 10 * ONE_NEAR);
+        assert_eq!(delegation.start_block, BLOCK_START_BLOCK);
+    }
+
+    #[test]
+    fn test_deposit_and_stake_callback_first_deposit_failed() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 1, 0);
+        contract.internal_deposit_and_stake(accounts(2), 10 * ONE_NEAR);
+        set_callback_context(&mut get_context(accounts(0)), vec![PromiseResult::Failed]);
+        assert!(!contract.callback_deposit_and_stake(accounts(2), U128::from(10 * ONE_NEAR)));
+
+        // Staking never started
+        let delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        assert_eq!(delegation.balance, 0);
+        assert_eq!(delegation.principal, 0);
+        assert_eq!(delegation.init_balance, 0);
+        assert_eq!(delegation.start_block, 0);
+    }
+
+    #[test]
+    fn test_liquid_unstake_pool_callback() {
+        let mut contract = setup(1);
+        contract.add_staking_pool(
+            accounts(2),
+            Some("liquid_unstake".to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+        contract.internal_deposit_and_stake(accounts(2), 10 * ONE_NEAR);
+        let amount = U128::from(4 * ONE_NEAR);
+        let mut context = get_context(accounts(0));
+
+        contract.internal_liquid_unstake_sell(accounts(2), amount, amount, amount, amount);
+        set_callback_context(&mut context, vec![PromiseResult::Successful(vec![])]);
+        assert!(contract.callback_liquid_unstake_pool(accounts(2), amount, amount, amount));
+        let delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        assert_eq!(delegation.balance, 6 * ONE_NEAR);
+        assert_eq!(delegation.principal, 6 * ONE_NEAR);
+        assert_eq!(delegation.total_withdrawn, 4 * ONE_NEAR);
+
+        // Pool did not pay out, the stake is back
+        contract.internal_liquid_unstake_sell(accounts(2), amount, amount, amount, amount);
+        set_callback_context(&mut context, vec![PromiseResult::Failed]);
+        assert!(!contract.callback_liquid_unstake_pool(accounts(2), amount, amount, amount));
+        let delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        assert_eq!(delegation.balance, 6 * ONE_NEAR);
+        assert_eq!(delegation.principal, 6 * ONE_NEAR);
+        assert_eq!(delegation.total_withdrawn, 4 * ONE_NEAR);
+    }
+
+    #[test]
+    fn test_staking_rewards_apy() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 1, 0);
+        contract.internal_deposit_and_stake(accounts(2), 10 * ONE_NEAR);
+        let mut context = get_context(accounts(0));
+        set_callback_context(&mut context, vec![pool_balance_result(10 * ONE_NEAR, 0)]);
+        contract.callback_get_staked_balance(accounts(2));
+        assert_eq!(contract.get_staking_rewards().total.apy_bps, None);
+
+        // Half a year later, 5% more
+        context.block_timestamp(BLOCK_START_TS + YEAR_NANOS as u64 / 2);
+        set_callback_context(
+            &mut context,
+            vec![pool_balance_result(10 * ONE_NEAR + ONE_NEAR / 2, 0)],
+        );
+        contract.callback_get_staked_balance(accounts(2));
+        let report = contract.get_staking_rewards();
+        assert_eq!(report.pools.len(), 1);
+        assert_eq!(report.pools[0].apy_bps, Some(1_000));
+        assert_eq!(report.pools[0].principal.0, 10 * ONE_NEAR);
+        assert_eq!(report.pools[0].rewards_unrealized.0, ONE_NEAR / 2);
+        assert_eq!(report.total.apy_bps, Some(1_000));
+        assert_eq!(report.total.balance.0, 10 * ONE_NEAR + ONE_NEAR / 2);
+
+        // Failed lookups leave the balances as they were
+        set_callback_context(&mut context, vec![PromiseResult::Failed]);
+        assert_eq!(
+            contract.callback_get_staked_balance(accounts(2)),
+            (0, 0, false)
+        );
+        assert_eq!(
+            contract
+                .stake_delegations
+                .get(&accounts(2))
+                .unwrap()
+                .snapshots
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_refresh_delegations_pages() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 1, 0);
+        add_pool(&mut contract, accounts(3), 1, 0);
+        add_pool(&mut contract, accounts(4), 1, 0);
+        let (due, args) = contract.needs_delegations_refresh();
+        assert!(due);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&args.0).unwrap(),
+            json!({ "from_index": "0", "limit": STAKE_REFRESH_PAGE_LIMIT.to_string() })
+        );
+
+        // First page, the refresh carries on from the next pool
+        let mut context = get_context(accounts(0));
+        set_callback_context(
+            &mut context,
+            vec![
+                pool_balance_result(10 * ONE_NEAR, 0),
+                pool_balance_result(20 * ONE_NEAR, ONE_NEAR),
+            ],
+        );
+        contract.callback_refresh_delegations(vec![accounts(2), accounts(3)], U64::from(2));
+        let delegation = contract.stake_delegations.get(&accounts(3)).unwrap();
+        assert_eq!(delegation.balance, 20 * ONE_NEAR);
+        assert_eq!(delegation.unstaked_balance, ONE_NEAR);
+        assert_eq!(delegation.last_refreshed, BLOCK_START_TS);
+        let (due, args) = contract.needs_delegations_refresh();
+        assert!(due);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&args.0).unwrap()["from_index"],
+            "2"
+        );
+
+        // Failed lookups keep their stored balance, and stay due
+        set_callback_context(&mut context, vec![PromiseResult::Failed]);
+        contract.callback_refresh_delegations(vec![accounts(4)], U64::from(3));
+        assert_eq!(contract.stake_refresh_index, 0);
+        assert_eq!(
+            contract
+                .stake_delegations
+                .get(&accounts(4))
+                .unwrap()
+                .last_refreshed,
+            0
+        );
+        assert!(contract.needs_delegations_refresh().0);
+
+        // Last page stored, nothing due until the refresh period passes
+        set_callback_context(&mut context, vec![pool_balance_result(5 * ONE_NEAR, 0)]);
+        contract.callback_refresh_delegations(vec![accounts(4)], U64::from(3));
+        assert!(!contract.needs_delegations_refresh().0);
+
+        // An unfinished refresh stays due, even with fresh balances
+        contract.internal_stake_refresh_advance(1);
+        assert!(contract.needs_delegations_refresh().0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough gas attached")]
+    fn test_refresh_delegations_not_enough_gas() {
+        let mut contract = setup(1);
+        add_pool(&mut contract, accounts(2), 1, 0);
+        set_balances(100 * ONE_NEAR, 30_000_000_000_000);
+        contract.refresh_delegations(None, None);
+    }
+
+    #[test]
+    fn test_unstake_moves_earlier_tranches() {
+        let mut contract = setup(10);
+        add_pool(&mut contract, accounts(2), 1, 10 * ONE_NEAR);
+        contract.internal_unstake(accounts(2), Some(U128::from(5 * ONE_NEAR)));
+        assert_eq!(
+            get_tranche_epochs(&contract, &accounts(2)),
+            vec![(5 * ONE_NEAR, 10 + UNSTAKE_LOCK_EPOCHS)]
+        );
+
+        set_epoch(12);
+        contract.internal_unstake(accounts(2), Some(U128::from(3 * ONE_NEAR)));
+        assert_eq!(
+            get_tranche_epochs(&contract, &accounts(2)),
+            vec![
+                (5 * ONE_NEAR, 12 + UNSTAKE_LOCK_EPOCHS),
+                (3 * ONE_NEAR, 12 + UNSTAKE_LOCK_EPOCHS)
+            ]
+        );
+        assert_eq!(contract.get_unstaking_balance(&accounts(2)), 8 * ONE_NEAR);
+        assert_eq!(
+            contract
+                .stake_delegations
+                .get(&accounts(2))
+                .unwrap()
+                .balance,
+            2 * ONE_NEAR
+        );
+
+        // Same epoch unstakes share the withdraw scheduled already
+        contract.internal_unstake(accounts(2), Some(U128::from(ONE_NEAR)));
+        assert_eq!(get_scheduled_withdraws(&contract), 2);
+    }
+
+    #[test]
+    fn test_restore_tranche_joins_queued_unlock() {
+        let mut contract = setup(20);
+        add_pool(&mut contract, accounts(2), 1, 10 * ONE_NEAR);
+        contract.internal_unstake(accounts(2), Some(U128::from(4 * ONE_NEAR)));
+        let queued_epoch = 20 + UNSTAKE_LOCK_EPOCHS;
+        let scheduled = get_scheduled_withdraws(&contract);
+
+        contract.internal_restore_unstake_tranche(accounts(2), 2 * ONE_NEAR, 21);
+        assert_eq!(
+            get_tranche_epochs(&contract, &accounts(2)),
+            vec![(2 * ONE_NEAR, queued_epoch), (4 * ONE_NEAR, queued_epoch)]
+        );
+        assert_eq!(get_scheduled_withdraws(&contract), scheduled);
+
+        // Nothing queued, the restored tranche gets its own withdraw
+        contract.internal_restore_unstake_tranche(accounts(3), ONE_NEAR, 21);
+        assert_eq!(
+            get_tranche_epochs(&contract, &accounts(3)),
+            vec![(ONE_NEAR, 21)]
+        );
+        assert_eq!(get_scheduled_withdraws(&contract), scheduled + 1);
+    }
+
+    #[test]
+    fn test_withdraw_matured_tranches() {
+        let mut contract = setup(10);
+        add_pool(&mut contract, accounts(2), 1, 10 * ONE_NEAR);
+        contract.internal_unstake(accounts(2), Some(U128::from(4 * ONE_NEAR)));
+        assert!(!contract.has_delegation_to_withdraw().0);
+        let unlocks = contract.get_unstake_unlocks(None);
+        assert_eq!(unlocks.len(), 1);
+        assert_eq!(unlocks[0].unlock_epoch, 10 + UNSTAKE_LOCK_EPOCHS);
+        assert!(!unlocks[0].ready);
+
+        // A later unstake stays queued, once the first one matures
+        set_epoch(10 + UNSTAKE_LOCK_EPOCHS);
+        contract.stake_pending_delegations.insert(
+            &accounts(2),
+            &vec![
+                UnstakeTranche {
+                    amount: U128::from(4 * ONE_NEAR),
+                    unlock_epoch: 10 + UNSTAKE_LOCK_EPOCHS,
+                    block_timestamp: U64::from(BLOCK_START_TS),
+                    task_hash: None,
+                },
+                UnstakeTranche {
+                    amount: U128::from(ONE_NEAR),
+                    unlock_epoch: 12 + UNSTAKE_LOCK_EPOCHS,
+                    block_timestamp: U64::from(BLOCK_START_TS),
+                    task_hash: None,
+                },
+            ],
+        );
+        let (ready, args) = contract.has_delegation_to_withdraw();
+        assert!(ready);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&args.0).unwrap(),
+            json!({ "pool_account_id": accounts(2) })
+        );
+        assert!(contract.get_unstake_unlocks(Some(accounts(2)))[0].ready);
+        assert!(contract.get_unstake_unlocks(Some(accounts(3))).is_empty());
+
+        contract.withdraw(accounts(2));
+        assert_eq!(
+            get_events("withdraw")[0]["amount"],
+            "4000000000000000000000000"
+        );
+        assert_eq!(
+            get_tranche_epochs(&contract, &accounts(2)),
+            vec![(ONE_NEAR, 12 + UNSTAKE_LOCK_EPOCHS)]
+        );
+        assert!(!contract.has_delegation_to_withdraw().0);
+    }
+
+    #[test]
+    fn test_withdraw_callback_failed() {
+        let mut contract = setup(10);
+        add_pool(&mut contract, accounts(2), 1, 10 * ONE_NEAR);
+        contract.internal_unstake(accounts(2), Some(U128::from(4 * ONE_NEAR)));
+        let unlock_epoch = 10 + UNSTAKE_LOCK_EPOCHS;
+        set_epoch(unlock_epoch);
+        contract.withdraw(accounts(2));
+        assert!(get_tranche_epochs(&contract, &accounts(2)).is_empty());
+
+        // The claimed tranche goes back into the unstake queue
+        let mut context = get_context(accounts(0));
+        context.epoch_height(unlock_epoch);
+        set_callback_context(&mut context, vec![PromiseResult::Failed]);
+        contract.callback_withdraw(accounts(2), U128::from(4 * ONE_NEAR), unlock_epoch, vec![]);
+        assert_eq!(
+            get_tranche_epochs(&contract, &accounts(2)),
+            vec![(4 * ONE_NEAR, unlock_epoch)]
+        );
+        assert_eq!(
+            contract
+                .stake_delegations
+                .get(&accounts(2))
+                .unwrap()
+                .total_withdrawn,
+            0
+        );
+        assert!(contract.has_delegation_to_withdraw().0);
+    }
+
+    #[test]
+    #[should_panic(expected = "No unstaked balance ready to withdraw")]
+    fn test_withdraw_not_ready() {
+        let mut contract = setup(10);
+        add_pool(&mut contract, accounts(2), 1, 10 * ONE_NEAR);
+        contract.internal_unstake(accounts(2), Some(U128::from(4 * ONE_NEAR)));
+        contract.withdraw(accounts(2));
+    }
+
+    #[test]
+    fn test_schedule_withdraw_at_unlock_estimate() {
+        let mut contract = setup(10);
+        contract.internal_schedule_withdraw(accounts(2), 14);
+        let timeouts = contract.timeout_actions.to_vec();
+        assert_eq!(timeouts.len(), 1);
+        assert_eq!(
+            timeouts[0].0,
+            u128::from(BLOCK_START_TS + 4 * STAKE_EPOCH_LENGTH)
+        );
+        let payload =
+            serde_json::to_value(&timeouts[0].1[0]).unwrap()["payload"]["StakeWithdraw"].clone();
+        assert_eq!(payload["pool_account_id"], accounts(2).to_string());
+        assert_eq!(payload["unlock_epoch"], 14);
+
+        // Estimate has passed already, so waits for the next epoch
+        contract.internal_schedule_withdraw(accounts(2), 8);
+        assert_eq!(
+            contract.timeout_actions.min(),
+            Some(u128::from(BLOCK_START_TS + STAKE_EPOCH_LENGTH))
+        );
+    }
+
+    #[test]
+    fn test_schedule_withdraw_with_croncat() {
+        let mut contract = setup(10);
+        contract.croncat_id = Some(accounts(5));
+        add_pool(&mut contract, accounts(2), 1, 10 * ONE_NEAR);
+        contract.internal_unstake(accounts(2), Some(U128::from(4 * ONE_NEAR)));
+        assert!(contract.timeout_actions.is_empty());
+
+        // The task hash is kept on the tranches it withdraws
+        let unlock_epoch = 10 + UNSTAKE_LOCK_EPOCHS;
+        let task_hash = Base64VecU8::from(vec![1, 2, 3]);
+        set_callback_context(&mut get_context(accounts(0)), vec![json_result(&task_hash)]);
+        contract.callback_create_withdraw_task(accounts(2), unlock_epoch);
+        let tranches = contract
+            .stake_pending_delegations
+            .get(&accounts(2))
+            .unwrap();
+        assert_eq!(tranches[0].task_hash, Some(task_hash));
+
+        // Croncat firing early reschedules, instead of failing
+        testing_env!(get_context(accounts(5))
+            .epoch_height(unlock_epoch - 1)
+            .build());
+        contract.withdraw(accounts(2));
+        assert_eq!(
+            get_tranche_epochs(&contract, &accounts(2)),
+            vec![(4 * ONE_NEAR, unlock_epoch)]
+        );
+    }
+
+    #[test]
+    fn test_reconcile_withdraw_restores_shortfall() {
+        let mut contract = setup(30);
+        add_pool(&mut contract, accounts(2), 1, 10 * ONE_NEAR);
+        let mut delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        delegation.record_deposit(10 * ONE_NEAR);
+        contract.stake_delegations.insert(&accounts(2), &delegation);
+
+        contract.internal_reconcile_withdraw(
+            accounts(2),
+            U128::from(4 * ONE_NEAR),
+            3 * ONE_NEAR,
+            29,
+        );
+        let delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        assert_eq!(delegation.principal, 7 * ONE_NEAR);
+        assert_eq!(delegation.total_withdrawn, 3 * ONE_NEAR);
+        assert_eq!(
+            get_tranche_epochs(&contract, &accounts(2)),
+            vec![(ONE_NEAR, 29)]
+        );
+        let events = get_events("withdraw_reconcile");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["received"], "3000000000000000000000000");
+        assert_eq!(events[0]["restored"], "1000000000000000000000000");
+    }
+
+    #[test]
+    fn test_withdraw_reconcile_callback() {
+        let mut contract = setup(30);
+        add_pool(&mut contract, accounts(2), 1, 10 * ONE_NEAR);
+        add_tranche(&mut contract, accounts(2), 2 * ONE_NEAR);
+        let mut context = get_context(accounts(0));
+        context.epoch_height(30);
+
+        // Pool still holds 1 NEAR beyond the queued tranche, so it was not paid out
+        set_callback_context(
+            &mut context,
+            vec![pool_balance_result(6 * ONE_NEAR, 3 * ONE_NEAR)],
+        );
+        contract.callback_withdraw_reconcile(accounts(2), U128::from(4 * ONE_NEAR), 29);
+        let delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        assert_eq!(delegation.balance, 6 * ONE_NEAR);
+        assert_eq!(delegation.total_withdrawn, 3 * ONE_NEAR);
+        assert_eq!(contract.get_unstaking_balance(&accounts(2)), 3 * ONE_NEAR);
+        assert_eq!(get_events("withdraw_reconcile").len(), 1);
+
+        // Paid out in full, nothing to restore
+        set_callback_context(
+            &mut context,
+            vec![pool_balance_result(6 * ONE_NEAR, 3 * ONE_NEAR)],
+        );
+        contract.callback_withdraw_reconcile(accounts(2), U128::from(2 * ONE_NEAR), 29);
+        let delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        assert_eq!(delegation.total_withdrawn, 5 * ONE_NEAR);
+        assert_eq!(contract.get_unstaking_balance(&accounts(2)), 3 * ONE_NEAR);
+        assert!(get_events("withdraw_reconcile").is_empty());
+
+        // Balance could not be read, taken as paid out in full
+        set_callback_context(&mut context, vec![PromiseResult::Failed]);
+        contract.callback_withdraw_reconcile(accounts(2), U128::from(ONE_NEAR), 29);
+        let delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        assert_eq!(delegation.total_withdrawn, 6 * ONE_NEAR);
+        assert_eq!(contract.get_unstaking_balance(&accounts(2)), 3 * ONE_NEAR);
+    }
+
+    #[test]
+    fn test_liquid_staking_provider_args() {
+        let shares = U128::from(3 * ONE_NEAR);
+        let min_expected_near = U128::from(2 * ONE_NEAR);
+        let metapool = LiquidStakingProvider::MetaPool;
+        assert_eq!(metapool.unstake_function(), "liquid_unstake");
+        assert_eq!(metapool.balance_view(), "get_account_info");
+        assert_eq!(metapool.quote_view(), "get_near_amount_sell_stnear");
+        assert_eq!(
+            metapool.quote_args(shares),
+            json!({ "stnear_to_sell": shares }).to_string()
+        );
+        assert_eq!(
+            metapool.unstake_args(shares, min_expected_near),
+            json!({ "st_near_to_burn": shares, "min_expected_near": min_expected_near })
+                .to_string()
+        );
+
+        let linear = LiquidStakingProvider::Linear;
+        assert_eq!(linear.unstake_function(), "instant_unstake");
+        assert_eq!(linear.balance_view(), "get_account_details");
+        assert_eq!(linear.quote_view(), "get_instant_unstake_amount");
+        assert_eq!(
+            linear.quote_args(shares),
+            json!({ "stake_shares_in": shares }).to_string()
+        );
+        assert_eq!(
+            linear.unstake_args(shares, min_expected_near),
+            json!({ "stake_shares_in": shares, "min_amount_out": min_expected_near }).to_string()
+        );
+        assert_eq!(
+            linear.balance_args(&accounts(0)),
+            json!({ "account_id": accounts(0) }).to_string()
+        );
+    }
+
+    #[test]
+    fn test_liquid_staking_provider_parse_balance() {
+        let metapool_balance = json!({
+            "account_id": accounts(0),
+            "available": "0",
+            "st_near": "3",
+            "valued_st_near": "4",
+            "meta": "0",
+            "realized_meta": "0",
+            "unstaked": "0",
+            "unstaked_requested_unlock_epoch": "0",
+            "unstake_full_epochs_wait_left": 0,
+            "can_withdraw": false,
+            "total": "4",
+            "trip_start": "0",
+            "trip_start_stnear": "0",
+            "trip_accum_stakes": "0",
+            "trip_accum_unstakes": "0",
+            "trip_rewards": "0",
+            "nslp_shares": "0",
+            "nslp_share_value": "0",
+            "nslp_share_bp": 0,
+        });
+        assert_eq!(
+            LiquidStakingProvider::MetaPool.parse_balance(metapool_balance.to_string().as_bytes()),
+            (3, 4)
+        );
+
+        let linear_account = json!({
+            "account_id": accounts(0),
+            "unstaked_balance": "0",
+            "staked_balance": "6",
+            "stake_shares": "5",
+            "can_withdraw": true,
+        });
+        assert_eq!(
+            LiquidStakingProvider::Linear.parse_balance(linear_account.to_string().as_bytes()),
+            (5, 6)
+        );
+    }
+
+    #[test]
+    fn test_add_staking_pool_provider_defaults() {
+        let mut contract = setup(1);
+        contract.add_staking_pool(
+            accounts(2),
+            Some("liquid_unstake".to_string()),
+            None,
+            None,
+            None,
+            None,
+        );
+        contract.add_staking_pool(
+            accounts(3),
+            None,
+            Some(LiquidStakingProvider::Linear),
+            None,
+            None,
+            None,
+        );
+        contract.add_staking_pool(accounts(4), None, None, None, None, None);
+
+        let metapool = contract.stake_delegations.get(&accounts(2)).unwrap();
+        assert!(metapool.liquid_staking_provider == Some(LiquidStakingProvider::MetaPool));
+        let linear = contract.stake_delegations.get(&accounts(3)).unwrap();
+        assert!(linear.liquid_staking_provider == Some(LiquidStakingProvider::Linear));
+        assert_eq!(
+            linear.liquid_unstake_function,
+            Some("instant_unstake".to_string())
+        );
+        let validator = contract.stake_delegations.get(&accounts(4)).unwrap();
+        assert!(validator.liquid_staking_provider.is_none());
+        assert!(validator.liquid_unstake_function.is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "No liquid staked balance")]
+    fn test_liquid_unstake_callback_no_shares() {
+        let mut contract = setup(1);
+        contract.add_staking_pool(
+            accounts(2),
+            None,
+            Some(LiquidStakingProvider::Linear),
+            None,
+            None,
+            None,
+        );
+        let mut context = get_context(accounts(0));
+
+        // Balance view failed, nothing happens
+        set_callback_context(&mut context, vec![PromiseResult::Failed]);
+        contract.callback_liquid_unstake(accounts(2), None);
+
+        set_callback_context(
+            &mut context,
+            vec![json_result(&json!({
+                "account_id": accounts(0),
+                "unstaked_balance": "0",
+                "staked_balance": "0",
+                "stake_shares": "0",
+                "can_withdraw": false,
+            }))],
+        );
+        contract.callback_liquid_unstake(accounts(2), None);
+    }
+
+    #[test]
+    fn test_liquid_unstake_all_shares() {
+        let mut contract = setup(1);
+        add_liquid_pool(&mut contract, accounts(2), 10 * ONE_NEAR);
+        let shares = U128::from(10 * ONE_NEAR);
+        let quote = U128::from(98 * ONE_NEAR / 10);
+
+        // 2% fee, within the default maximum
+        set_callback_context(&mut get_context(accounts(0)), vec![json_result(&quote)]);
+        contract.callback_liquid_unstake_final(accounts(2), None, shares, shares);
+        let delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        assert_eq!(delegation.balance, 0);
+        assert_eq!(delegation.total_withdrawn, quote.0);
+        assert_eq!(
+            get_events("liquid_unstake")[0]["amount"],
+            quote.0.to_string()
+        );
+    }
+
+    #[test]
+    fn test_liquid_unstake_amount_quotes_shares_needed() {
+        let mut contract = setup(1);
+        add_liquid_pool(&mut contract, accounts(2), 10 * ONE_NEAR);
+        let amount = U128::from(49 * ONE_NEAR / 10);
+        let mut context = get_context(accounts(0));
+
+        // Only half the shares are needed, those get quoted before selling
+        set_callback_context(
+            &mut context,
+            vec![json_result(&U128::from(98 * ONE_NEAR / 10))],
+        );
+        contract.callback_liquid_unstake_final(
+            accounts(2),
+            Some(amount),
+            U128::from(10 * ONE_NEAR),
+            U128::from(10 * ONE_NEAR),
+        );
+        assert_eq!(
+            contract
+                .stake_delegations
+                .get(&accounts(2))
+                .unwrap()
+                .balance,
+            10 * ONE_NEAR
+        );
+        assert!(get_events("liquid_unstake").is_empty());
+
+        // Rate dropped since, the lower quote is the minimum expected
+        let quote = U128::from(485 * ONE_NEAR / 100);
+        set_callback_context(&mut context, vec![json_result(&quote)]);
+        contract.callback_liquid_unstake_quote(
+            accounts(2),
+            amount,
+            U128::from(5 * ONE_NEAR),
+            U128::from(5 * ONE_NEAR),
+        );
+        let delegation = contract.stake_delegations.get(&accounts(2)).unwrap();
+        assert_eq!(delegation.balance, 5 * ONE_NEAR);
+        assert_eq!(delegation.total_withdrawn, quote.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Liquid unstake fee of 200 bps is above the maximum of 100 bps")]
+    fn test_liquid_unstake_fee_above_max() {
+        let mut contract = setup(1);
+        add_liquid_pool(&mut contract, accounts(2), 10 * ONE_NEAR);
+        contract.update_settings(
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(U64::from(100)),
+            None,
+            None,
+            None,
+            None,
+        );
+        let shares = U128::from(10 * ONE_NEAR);
+        set_callback_context(
+            &mut get_context(accounts(0)),
+            vec![json_result(&U128::from(98 * ONE_NEAR / 10))],
+        );
+        contract.callback_liquid_unstake_final(accounts(2), None, shares, shares);
+    }
+
+    #[test]
+    #[should_panic(expected = "Fee cannot be above 10000 bps")]
+    fn test_max_liquid_unstake_fee_setting() {
+        let mut contract = setup(1);
+        contract.update_settings(
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(U64::from(10_001)),
+            None,
+            None,
+            None,
+            None,
+        );
+    }
+}
@@ -1,98 +1,363 @@
-use crate::Contract;
-use near_contract_standards::storage_management::{
-    StorageBalance, StorageBalanceBounds, StorageManagement,
-};
-use near_sdk::json_types::{AccountId, U128};
-use near_sdk::{assert_one_yocto, env, log, AccountId, Balance, Promise};
+use crate::*;
+use near_contract_standards::storage_management::StorageManagement;
+
+/// Storage deposit of an account using the treasury, EX: a non-owner proposing actions
+/// TODO: Charge payees & escrow recipients too, once claimable balances & escrow exist in the treasury
+#[derive(BorshDeserialize, BorshSerialize, Default)]
+pub struct StorageAccount {
+    /// Total NEAR deposited for storage
+    pub balance: Balance,
+    /// Bytes used by the records this account created, not including its registration
+    pub used_bytes: StorageUsage,
+}
 
 impl Contract {
+    /// Measures the bytes taken by a single storage account record, used as the registration minimum
+    pub(crate) fn measure_storage_account_usage(&mut self) {
+        let initial_storage_usage = env::storage_usage();
+        let tmp_account_id = AccountId::new_unchecked("a".repeat(64));
+        self.storage_accounts
+            .insert(&tmp_account_id, &StorageAccount::default());
+        self.storage_account_usage = env::storage_usage() - initial_storage_usage;
+        self.storage_accounts.remove(&tmp_account_id);
+    }
+
     fn internal_storage_balance_of(&self, account_id: &AccountId) -> Option<StorageBalance> {
-        if self.agents.contains_key(account_id) {
-            // The "available" balance is always zero because the storage isn't
-            // variable for this contract.
-            Some(StorageBalance {
-                total: self.storage_balance_bounds().min,
-                available: 0.into(),
+        self.storage_accounts
+            .get(account_id)
+            .map(|account| StorageBalance {
+                total: U128::from(account.balance),
+                available: U128::from(self.internal_storage_available(&account)),
             })
-        } else {
-            None
+    }
+
+    fn internal_storage_available(&self, account: &StorageAccount) -> Balance {
+        let locked = Balance::from(self.storage_account_usage + account.used_bytes)
+            * env::storage_byte_cost();
+        account.balance.saturating_sub(locked)
+    }
+
+    /// Charges an account for the bytes its records added, panics if its deposit does not cover them
+    /// NOTE: The owner is exempt, as the treasury pays for its own records
+    pub(crate) fn internal_storage_charge(&mut self, account_id: &AccountId, bytes: StorageUsage) {
+        if account_id == &self.owner_id || bytes == 0 {
+            return;
+        }
+        let mut account = self
+            .storage_accounts
+            .get(account_id)
+            .expect("Account not registered, use storage_deposit");
+        account.used_bytes += bytes;
+        let locked = Balance::from(self.storage_account_usage + account.used_bytes)
+            * env::storage_byte_cost();
+        assert!(
+            account.balance >= locked,
+            "Insufficient storage balance, {} needed",
+            locked
+        );
+        self.storage_accounts.insert(account_id, &account);
+    }
+
+    /// Releases bytes an account was charged for, once its record is gone
+    /// NOTE: Accounts that force unregistered already left the cost with the treasury
+    pub(crate) fn internal_storage_release(&mut self, account_id: &AccountId, bytes: StorageUsage) {
+        if let Some(mut account) = self.storage_accounts.get(account_id) {
+            account.used_bytes = account.used_bytes.saturating_sub(bytes);
+            self.storage_accounts.insert(account_id, &account);
         }
     }
 }
 
+#[near_bindgen]
 impl StorageManagement for Contract {
-    // `registration_only` doesn't affect the implementation here, as there's no need to add additional
-    // storage, so there's only one balance to attach.
-    #[allow(unused_variables)]
+    /// Register an account, or add to its storage balance
+    /// NOTE: With `registration_only`, anything above the minimum is refunded
+    ///
+    /// ```bash
+    /// near call treasury.testnet storage_deposit '{"account_id": "user.testnet"}' --accountId user.testnet --amount 0.1
+    /// ```
+    #[payable]
     fn storage_deposit(
         &mut self,
         account_id: Option<AccountId>,
         registration_only: Option<bool>,
     ) -> StorageBalance {
-        self.register_agent(account_id.clone());
-        let account_id = account_id
-            .map(|a| a.into())
-            .unwrap_or_else(|| env::predecessor_account_id());
+        let amount = env::attached_deposit();
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let registration_only = registration_only.unwrap_or(false);
+
+        if let Some(mut account) = self.storage_accounts.get(&account_id) {
+            if registration_only {
+                // Already registered, refund the full deposit
+                if amount > 0 {
+                    Promise::new(env::predecessor_account_id()).transfer(amount);
+                }
+            } else {
+                account.balance += amount;
+                self.storage_accounts.insert(&account_id, &account);
+            }
+        } else {
+            let min_balance = self.storage_balance_bounds().min.0;
+            assert!(
+                amount >= min_balance,
+                "The attached deposit is less than the minimum storage balance of {}",
+                min_balance
+            );
+            let balance = if registration_only {
+                let refund = amount - min_balance;
+                if refund > 0 {
+                    Promise::new(env::predecessor_account_id()).transfer(refund);
+                }
+                min_balance
+            } else {
+                amount
+            };
+            self.storage_accounts.insert(
+                &account_id,
+                &StorageAccount {
+                    balance,
+                    used_bytes: 0,
+                },
+            );
+        }
+
         self.internal_storage_balance_of(&account_id).unwrap()
     }
 
-    /// While storage_withdraw normally allows the caller to retrieve `available` balance, this
-    /// contract sets storage_balance_bounds.min = storage_balance_bounds.max,
-    /// which means available balance will always be 0. So this implementation:
-    /// * panics if `amount > 0`
-    /// * never transfers Ⓝ to caller
-    /// * returns a `storage_balance` struct if `amount` is 0
+    /// Withdraw any storage balance not locked by the registration or created records
+    ///
+    /// ```bash
+    /// near call treasury.testnet storage_withdraw '{"amount": "10000000000000000000000"}' --accountId user.testnet --depositYocto 1
+    /// ```
+    #[payable]
     fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
         assert_one_yocto();
-        let predecessor = env::predecessor_account_id();
-        if let Some(storage_balance) = self.internal_storage_balance_of(&predecessor) {
-            match amount {
-                Some(amount) if amount.0 > 0 => {
-                    let panic_msg = format!("The amount is greater than the available storage balance. Remember there's a minimum balance needed for an agent's storage. That minimum is {}. To unregister an agent, use the 'unregister_agent' or 'storage_unregister' with the 'force' option.", self.agent_storage_usage);
-                    env::panic(panic_msg.as_bytes());
-                }
-                _ => storage_balance,
-            }
-        } else {
-            env::panic(format!("The account {} is not registered", &predecessor).as_bytes());
+        let account_id = env::predecessor_account_id();
+        let mut account = self.storage_accounts.get(&account_id).unwrap_or_else(|| {
+            env::panic_str(&format!("The account {} is not registered", &account_id))
+        });
+        let available = self.internal_storage_available(&account);
+        let amount = amount.map(|a| a.0).unwrap_or(available);
+        assert!(
+            amount <= available,
+            "The amount is greater than the available storage balance"
+        );
+
+        if amount > 0 {
+            account.balance -= amount;
+            self.storage_accounts.insert(&account_id, &account);
+            Promise::new(account_id.clone()).transfer(amount);
         }
+        self.internal_storage_balance_of(&account_id).unwrap()
     }
 
+    /// Unregister & refund the storage balance
+    /// NOTE: With `force`, records created by the account stay with the treasury & their storage cost is kept
+    ///
+    /// ```bash
+    /// near call treasury.testnet storage_unregister '{"force": false}' --accountId user.testnet --depositYocto 1
+    /// ```
+    #[payable]
     fn storage_unregister(&mut self, force: Option<bool>) -> bool {
         assert_one_yocto();
         let account_id = env::predecessor_account_id();
         let force = force.unwrap_or(false);
-        if let Some(agent) = self.agents.get(&account_id) {
-            let balance = agent.balance.0;
-            if balance == 0 || force {
-                self.remove_agent(account_id.clone());
-
-                // We add 1 to reimburse for the 1 yoctoⓃ used to call this method
-                Promise::new(account_id).transfer(balance + 1);
-                log!(
-                    "Agent has been removed and refunded the storage cost of {}",
-                    balance + 1
-                );
-                true
-            } else {
-                env::panic(b"Can't unregister the agent with the positive balance. Must use the 'force' parameter if desired.")
+        let account = match self.storage_accounts.get(&account_id) {
+            Some(account) => account,
+            None => {
+                env::log_str(&format!("The account {} is not registered", &account_id));
+                return false;
             }
-        } else {
-            log!("The agent {} is not registered", &account_id);
-            false
-        }
+        };
+        assert!(
+            account.used_bytes == 0 || force,
+            "Can't unregister the account with stored records. Must use the 'force' parameter if desired."
+        );
+
+        let kept = Balance::from(account.used_bytes) * env::storage_byte_cost();
+        let refund = account.balance.saturating_sub(kept);
+        self.storage_accounts.remove(&account_id);
+
+        // We add 1 to reimburse for the 1 yoctoⓃ used to call this method
+        Promise::new(account_id).transfer(refund + 1);
+        true
     }
 
+    /// Minimum covers a single account registration, there is no maximum as each record created is paid for
+    ///
+    /// ```bash
+    /// near view treasury.testnet storage_balance_bounds
+    /// ```
     fn storage_balance_bounds(&self) -> StorageBalanceBounds {
-        let required_storage_balance =
-            Balance::from(self.agent_storage_usage) * env::storage_byte_cost();
         StorageBalanceBounds {
-            min: required_storage_balance.into(),
-            max: Some(required_storage_balance.into()),
+            min: U128::from(Balance::from(self.storage_account_usage) * env::storage_byte_cost()),
+            max: None,
         }
     }
 
+    /// ```bash
+    /// near view treasury.testnet storage_balance_of '{"account_id": "user.testnet"}'
+    /// ```
     fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
-        self.internal_storage_balance_of(account_id.as_ref())
+        self.internal_storage_balance_of(&account_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    fn set_caller(predecessor_account_id: AccountId, attached_deposit: Balance) {
+        testing_env!(get_context(predecessor_account_id)
+            .attached_deposit(attached_deposit)
+            .build());
+    }
+
+    fn setup() -> Contract {
+        setup_contract()
+    }
+
+    fn get_min_balance(contract: &Contract) -> Balance {
+        contract.storage_balance_bounds().min.0
+    }
+
+    #[test]
+    fn test_storage_deposit_registration_only() {
+        let mut contract = setup();
+        let min_balance = get_min_balance(&contract);
+        assert!(min_balance > 0);
+
+        set_caller(accounts(2), min_balance * 2);
+        let balance = contract.storage_deposit(None, Some(true));
+        assert_eq!(balance.total.0, min_balance);
+        assert_eq!(balance.available.0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "The attached deposit is less than the minimum storage balance")]
+    fn test_storage_deposit_below_minimum() {
+        let mut contract = setup();
+        let min_balance = get_min_balance(&contract);
+        set_caller(accounts(2), min_balance - 1);
+        contract.storage_deposit(None, None);
+    }
+
+    #[test]
+    fn test_storage_charge_and_release() {
+        let mut contract = setup();
+        let min_balance = get_min_balance(&contract);
+        let byte_cost = env::storage_byte_cost();
+        set_caller(accounts(2), min_balance + 100 * byte_cost);
+        contract.storage_deposit(None, None);
+
+        contract.internal_storage_charge(&accounts(2), 60);
+        let balance = contract.storage_balance_of(accounts(2)).unwrap();
+        assert_eq!(balance.available.0, 40 * byte_cost);
+
+        contract.internal_storage_release(&accounts(2), 60);
+        let balance = contract.storage_balance_of(accounts(2)).unwrap();
+        assert_eq!(balance.available.0, 100 * byte_cost);
+
+        // Releasing more than was charged never underflows
+        contract.internal_storage_release(&accounts(2), 10);
+        assert_eq!(
+            contract
+                .storage_accounts
+                .get(&accounts(2))
+                .unwrap()
+                .used_bytes,
+            0
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient storage balance")]
+    fn test_storage_charge_over_balance() {
+        let mut contract = setup();
+        let min_balance = get_min_balance(&contract);
+        set_caller(accounts(2), min_balance + 10 * env::storage_byte_cost());
+        contract.storage_deposit(None, None);
+        contract.internal_storage_charge(&accounts(2), 11);
+    }
+
+    #[test]
+    #[should_panic(expected = "Account not registered")]
+    fn test_storage_charge_unregistered() {
+        let mut contract = setup();
+        contract.internal_storage_charge(&accounts(2), 1);
+    }
+
+    #[test]
+    fn test_storage_charge_owner_exempt() {
+        let mut contract = setup();
+        contract.internal_storage_charge(&accounts(1), 1_000);
+        assert!(contract.storage_accounts.get(&accounts(1)).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't unregister the account with stored records")]
+    fn test_storage_unregister_with_records() {
+        let mut contract = setup();
+        set_caller(accounts(2), get_min_balance(&contract) + ONE_NEAR);
+        contract.storage_deposit(None, None);
+        contract.internal_storage_charge(&accounts(2), 10);
+        set_caller(accounts(2), 1);
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    fn test_storage_action_proposer() {
+        let mut contract = setup();
+        let payload = serde_json::json!({
+            "Transfer": {
+                "token_id": null,
+                "receiver_id": "charlie",
+                "amount": "1",
+                "msg": null,
+            },
+        });
+        let action: Action = serde_json::from_value(serde_json::json!({
+            "priority": 0,
+            "timeout": (BLOCK_START_TS + 1_000).to_string(),
+            "cadence": null,
+            "payload": payload,
+        }))
+        .unwrap();
+        contract.add_allowed_actions(vec![serde_json::from_value(payload).unwrap()]);
+        let min_balance = get_min_balance(&contract);
+        set_caller(accounts(2), min_balance + ONE_NEAR);
+        contract.storage_deposit(None, None);
+
+        // The proposer pays for the stored action, until it gets removed
+        contract.create_actions(vec![action.clone()]);
+        let used_bytes = contract
+            .storage_accounts
+            .get(&accounts(2))
+            .unwrap()
+            .used_bytes;
+        assert!(used_bytes > 0);
+        let balance = contract.storage_balance_of(accounts(2)).unwrap();
+        assert_eq!(
+            balance.available.0,
+            ONE_NEAR - Balance::from(used_bytes) * env::storage_byte_cost()
+        );
+
+        set_caller(accounts(1), 0);
+        contract.remove_actions(action.clone());
+        let balance = contract.storage_balance_of(accounts(2)).unwrap();
+        assert_eq!(balance.available.0, ONE_NEAR);
+
+        // Executing the action releases its storage too
+        set_caller(accounts(2), 0);
+        contract.create_actions(vec![action]);
+        testing_env!(get_context(accounts(1))
+            .block_timestamp(BLOCK_START_TS + 2_000)
+            .build());
+        contract.call_timeout_actions();
+        let balance = contract.storage_balance_of(accounts(2)).unwrap();
+        assert_eq!(balance.available.0, ONE_NEAR);
     }
 }
@@ -0,0 +1,55 @@
+use crate::*;
+use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+use near_sdk::{testing_env, RuntimeFeesConfig, VMConfig};
+
+pub const BLOCK_START_BLOCK: u64 = 52_201_040;
+pub const BLOCK_START_TS: u64 = 1_624_151_503_447_000_000;
+
+/// Context of a call to the treasury (accounts(0)) from an account
+pub fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+    let mut builder = VMContextBuilder::new();
+    builder
+        .current_account_id(accounts(0))
+        .signer_account_id(predecessor_account_id.clone())
+        .predecessor_account_id(predecessor_account_id)
+        .block_index(BLOCK_START_BLOCK)
+        .block_timestamp(BLOCK_START_TS)
+        .prepaid_gas(Gas(300_000_000_000_000));
+    builder
+}
+
+/// Treasury owned by accounts(1)
+pub fn setup_contract() -> Contract {
+    testing_env!(get_context(accounts(1)).build());
+    Contract::new()
+}
+
+/// Data of every NEP-297 event logged so far with a name
+pub fn get_events(event: &str) -> Vec<serde_json::Value> {
+    get_logs()
+        .iter()
+        .filter_map(|log| log.strip_prefix("EVENT_JSON:"))
+        .map(|log| serde_json::from_str::<serde_json::Value>(log).unwrap())
+        .filter(|log| log["event"] == event)
+        .map(|log| log["data"][0].clone())
+        .collect()
+}
+
+/// Context of a callback, with the results of the promises it was chained on
+pub fn set_callback_context(context: &mut VMContextBuilder, promise_results: Vec<PromiseResult>) {
+    testing_env!(
+        context
+            .predecessor_account_id(accounts(0))
+            .signer_account_id(accounts(1))
+            .build(),
+        VMConfig::test(),
+        RuntimeFeesConfig::test(),
+        Default::default(),
+        promise_results
+    );
+}
+
+/// Successful promise result returning a JSON value
+pub fn json_result<T: Serialize>(value: &T) -> PromiseResult {
+    PromiseResult::Successful(serde_json::to_vec(value).unwrap())
+}
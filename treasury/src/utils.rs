@@ -3,3 +3,228 @@ use crate::*;
 pub fn calc_percent(numerator: u64, denominator: u64, value: u128) -> u128 {
     (U256::from(numerator) * U256::from(value) / U256::from(denominator)).as_u128()
 }
+
+/// Computes value * numerator / denominator, rounding up
+pub fn mul_div_ceil(value: u128, numerator: u128, denominator: u128) -> u128 {
+    let product = U256::from(value) * U256::from(numerator);
+    let denominator = U256::from(denominator);
+    ((product + denominator - U256::from(1)) / denominator).as_u128()
+}
+
+/// Formats a raw token amount into a human readable decimal string, EX: (1500, 3) => "1.5"
+pub fn format_decimals(value: u128, decimals: u8) -> String {
+    let raw = value.to_string();
+    let decimals = decimals as usize;
+    if decimals == 0 {
+        return raw;
+    }
+
+    let padded = format!("{:0>width$}", raw, width = decimals + 1);
+    let (whole, fraction) = padded.split_at(padded.len() - decimals);
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        whole.to_string()
+    } else {
+        format!("{}.{}", whole, fraction)
+    }
+}
+
+/// Converts days since 1970-01-01 into a (year, month, day) civil date
+/// REF: http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = (z - era * 146_097) as u64; // [0, 146096]
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365; // [0, 399]
+    let y = yoe as i64 + era * 400;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100); // [0, 365]
+    let mp = (5 * doy + 2) / 153; // [0, 11]
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32; // [1, 31]
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32; // [1, 12]
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+/// Croncat cadence that fires once, on the first minute at or after a timestamp (in nanos)
+/// EX: 1700000000000000000 => "0 14 22 14 11 * 2023"
+pub fn timestamp_to_cron(timestamp: u64) -> String {
+    let minutes = (timestamp + 59_999_999_999) / 60_000_000_000;
+    let minute_of_day = minutes % (24 * 60);
+    let (year, month, day) = civil_from_days((minutes / (24 * 60)) as i64);
+    format!(
+        "0 {} {} {} {} * {}",
+        minute_of_day % 60,
+        minute_of_day / 60,
+        day,
+        month,
+        year
+    )
+}
+
+/// Append-only history capped at `max_len` records, the oldest get overwritten once full
+/// Each record keeps a stable index, the number of records pushed before it
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct BoundedHistory<T> {
+    records: Vector<T>,
+    max_len: u64,
+    total: u64,
+}
+
+impl<T: BorshSerialize + BorshDeserialize> BoundedHistory<T> {
+    pub fn new<S: IntoStorageKey>(prefix: S, max_len: u64) -> Self {
+        assert!(max_len > 0, "History needs room for a record");
+        Self {
+            records: Vector::new(prefix),
+            max_len,
+            total: 0,
+        }
+    }
+
+    /// Number of records still kept
+    pub fn len(&self) -> u64 {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Index of the oldest record still kept
+    pub fn first_index(&self) -> u64 {
+        self.total - self.records.len()
+    }
+
+    /// Adds a record, overwriting the oldest if full, returns its index
+    pub fn push(&mut self, record: &T) -> u64 {
+        let index = self.total;
+        if self.records.len() < self.max_len {
+            self.records.push(record);
+        } else {
+            self.records.replace(index % self.max_len, record);
+        }
+        self.total += 1;
+        index
+    }
+
+    /// Record at an index, None once it was overwritten
+    pub fn get(&self, index: u64) -> Option<T> {
+        if index < self.first_index() || index >= self.total {
+            return None;
+        }
+        self.records.get(index % self.max_len)
+    }
+
+    /// Update the record at an index, returns false once it was overwritten
+    pub fn replace(&mut self, index: u64, record: &T) -> bool {
+        if index < self.first_index() || index >= self.total {
+            return false;
+        }
+        self.records.replace(index % self.max_len, record);
+        true
+    }
+
+    /// Records kept, oldest first, paged from the oldest kept record
+    pub fn page(&self, from_index: u64, limit: u64) -> Vec<T> {
+        let start = u64::min(from_index, self.len());
+        let end = u64::min(start.saturating_add(limit), self.len());
+        (start..end)
+            .filter_map(|i| self.get(self.first_index() + i))
+            .collect()
+    }
+}
+
+/// Cuts a string down to at most `max_len` bytes, on a char boundary
+pub fn truncate_string(value: String, max_len: usize) -> String {
+    if value.len() <= max_len {
+        return value;
+    }
+    let mut end = max_len;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value[..end].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::VMContextBuilder;
+    use near_sdk::testing_env;
+
+    #[test]
+    fn test_mul_div_ceil() {
+        assert_eq!(mul_div_ceil(10, 1, 3), 4);
+        assert_eq!(mul_div_ceil(9, 1, 3), 3);
+        assert_eq!(mul_div_ceil(0, 5, 7), 0);
+        assert_eq!(mul_div_ceil(u128::MAX, 2, 2), u128::MAX);
+    }
+
+    #[test]
+    fn test_format_decimals() {
+        assert_eq!(format_decimals(1500, 3), "1.5");
+        assert_eq!(format_decimals(1000, 3), "1");
+        assert_eq!(format_decimals(5, 3), "0.005");
+        assert_eq!(format_decimals(0, 24), "0");
+        assert_eq!(format_decimals(1234, 0), "1234");
+        assert_eq!(format_decimals(ONE_NEAR * 25 / 10, 24), "2.5");
+    }
+
+    #[test]
+    fn test_civil_from_days() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(59), (1970, 3, 1));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_675), (2023, 11, 14));
+    }
+
+    #[test]
+    fn test_timestamp_to_cron() {
+        assert_eq!(
+            timestamp_to_cron(1_700_000_000_000_000_000),
+            "0 14 22 14 11 * 2023"
+        );
+        // On the minute stays on the minute, anything after rounds up
+        assert_eq!(
+            timestamp_to_cron(1_699_999_980_000_000_000),
+            "0 13 22 14 11 * 2023"
+        );
+        assert_eq!(
+            timestamp_to_cron(1_699_999_980_000_000_001),
+            "0 14 22 14 11 * 2023"
+        );
+        // Rolls over into the next day & year
+        assert_eq!(
+            timestamp_to_cron(1_704_067_199_000_000_000),
+            "0 0 0 1 1 * 2024"
+        );
+    }
+
+    #[test]
+    fn test_truncate_string() {
+        assert_eq!(truncate_string("hello".to_string(), 10), "hello");
+        assert_eq!(truncate_string("hello".to_string(), 3), "hel");
+        // Never splits a multi-byte char
+        assert_eq!(truncate_string("héllo".to_string(), 2), "h");
+    }
+
+    #[test]
+    fn test_bounded_history() {
+        testing_env!(VMContextBuilder::new().build());
+        let mut history: BoundedHistory<u64> = BoundedHistory::new(b"h".to_vec(), 3);
+        assert!(history.is_empty());
+        for i in 0..5 {
+            assert_eq!(history.push(&(i * 10)), i);
+        }
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.first_index(), 2);
+        assert_eq!(history.get(1), None);
+        assert_eq!(history.get(2), Some(20));
+        assert_eq!(history.get(4), Some(40));
+        assert_eq!(history.get(5), None);
+        assert_eq!(history.page(0, 10), vec![20, 30, 40]);
+        assert_eq!(history.page(1, 1), vec![30]);
+        assert!(history.replace(3, &33));
+        assert!(!history.replace(0, &1));
+        assert_eq!(history.page(0, 10), vec![20, 33, 40]);
+    }
+}
@@ -25,6 +25,13 @@ impl Contract {
         String,         // owner
         String,         // croncat
         StakeThreshold, // stake config
+        U64,            // stake refresh period
+        U64,            // stake epoch length estimate
+        U64,            // max liquid unstake fee bps
+        U64,            // ft balances refresh period
+        U128,           // ft storage budget
+        Option<String>, // wrap
+        Option<U128>,   // wrap near float
     ) {
         (
             self.paused,
@@ -34,6 +41,13 @@ impl Contract {
                 .unwrap_or(AccountId::from_str("no_croncat_account").unwrap())
                 .to_string(),
             self.stake_threshold.clone(),
+            U64::from(self.stake_refresh_period),
+            U64::from(self.stake_epoch_length),
+            U64::from(self.max_liquid_unstake_fee_bps),
+            U64::from(self.ft_balances_refresh_period),
+            U128::from(self.ft_storage_budget),
+            self.wrap_id.as_ref().map(|wrap_id| wrap_id.to_string()),
+            self.wrap_near_float.map(U128::from),
         )
     }
 
@@ -88,29 +102,29 @@ impl Contract {
         for i in start..end {
             if let Some(pool_account_id) = keys.get(i) {
                 if let Some(delegation) = self.stake_delegations.get(&pool_account_id) {
-                    let mut delegation_info = StakeDelegationHumanFriendly {
+                    let tranches = self
+                        .stake_pending_delegations
+                        .get(&pool_account_id)
+                        .unwrap_or_default();
+                    let delegation_info = StakeDelegationHumanFriendly {
                         pool_account_id: pool_account_id.clone(),
                         init_balance: U128::from(delegation.init_balance),
                         balance: U128::from(delegation.balance),
                         start_block: delegation.start_block,
-                        withdraw_epoch: delegation.withdraw_epoch,
+                        // Next unlock, and everything still unstaking
+                        withdraw_epoch: tranches.iter().map(|t| t.unlock_epoch).min(),
                         withdraw_balance: Some(U128::from(
-                            delegation.withdraw_balance.unwrap_or(0),
+                            tranches.iter().map(|t| t.amount.0).sum::<Balance>(),
                         )),
                         withdraw_function: delegation.withdraw_function,
                         liquid_unstake_function: delegation.liquid_unstake_function,
+                        liquid_staking_provider: delegation.liquid_staking_provider,
                         yield_function: delegation.yield_function,
+                        harvest_token_id: delegation.harvest_token_id,
+                        harvest_route: delegation.harvest_route,
+                        weight: delegation.weight,
                     };
 
-                    // Adjust pending info if exists
-                    if let Some(pending_delegation) =
-                        self.stake_pending_delegations.get(&pool_account_id)
-                    {
-                        delegation_info.withdraw_epoch = pending_delegation.withdraw_epoch;
-                        delegation_info.withdraw_balance =
-                            Some(U128::from(pending_delegation.withdraw_balance.unwrap_or(0)));
-                    }
-
                     ret.push(delegation_info);
                 }
             }
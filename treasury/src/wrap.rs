@@ -0,0 +1,315 @@
+use crate::*;
+
+pub const GAS_WRAP_STORAGE_DEPOSIT: Gas = Gas(10_000_000_000_000);
+pub const GAS_WRAP_NEAR_DEPOSIT: Gas = Gas(10_000_000_000_000);
+pub const GAS_WRAP_NEAR_WITHDRAW: Gas = Gas(15_000_000_000_000);
+pub const GAS_WRAP_CALLBACK: Gas = Gas(10_000_000_000_000);
+
+/// Registration on the wrap contract, any excess is refunded if already registered
+pub const WRAP_STORAGE_DEPOSIT: Balance = 1_250_000_000_000_000_000_000;
+
+#[near_bindgen]
+impl Contract {
+    /// Wrap liquid NEAR into wNEAR, registering with the wrap contract if needed
+    ///
+    /// ```bash
+    /// near call treasury.testnet wrap_near '{"amount": "10000000000000000000000000"}' --accountId treasury.testnet
+    /// ```
+    pub fn wrap_near(&mut self, amount: U128) -> Promise {
+        self.assert_owner();
+        self.internal_wrap_near(amount.0)
+    }
+
+    /// Unwrap wNEAR back into liquid NEAR
+    ///
+    /// ```bash
+    /// near call treasury.testnet unwrap_near '{"amount": "10000000000000000000000000"}' --accountId treasury.testnet
+    /// ```
+    pub fn unwrap_near(&mut self, amount: U128) -> Promise {
+        self.assert_owner();
+        self.internal_unwrap_near(amount.0)
+    }
+
+    /// CALLBACK for wrap_near
+    /// NOTE: Credits the wrap contract the NEAR went to, even if the setting changed since
+    #[private]
+    pub fn callback_wrap_near(&mut self, wrap_id: AccountId, amount: U128) -> bool {
+        self.wrap_near_pending = false;
+        if !is_promise_success() {
+            return false;
+        }
+        let balance = self.ft_balances.get(&wrap_id).unwrap_or(0);
+        self.ft_balances
+            .insert(&wrap_id, &balance.saturating_add(amount.0));
+
+        events::WrapNear {
+            amount,
+            wrapped: true,
+        }
+        .emit();
+        true
+    }
+
+    /// CALLBACK for unwrap_near, restores the wNEAR balance if the withdraw failed
+    #[private]
+    pub fn callback_unwrap_near(&mut self, wrap_id: AccountId, amount: U128) -> bool {
+        self.internal_ft_settle(&wrap_id, amount.0);
+        if is_promise_success() {
+            events::WrapNear {
+                amount,
+                wrapped: false,
+            }
+            .emit();
+            return true;
+        }
+        let balance = self.ft_balances.get(&wrap_id).unwrap_or(0);
+        self.ft_balances
+            .insert(&wrap_id, &balance.saturating_add(amount.0));
+        false
+    }
+
+    /// Check if the wNEAR float is below its target, and there is liquid NEAR to top it up
+    ///
+    /// ```bash
+    /// near view treasury.testnet needs_wrap_near_topup
+    /// ```
+    pub fn needs_wrap_near_topup(&self) -> external::CroncatTriggerResponse {
+        (
+            self.get_wrap_near_topup() > 0,
+            Base64VecU8::from(json!({}).to_string().into_bytes()),
+        )
+    }
+
+    /// Top up the wNEAR float to its target, limited by liquid NEAR above the storage minimum
+    /// NOTE: Only one wrap can be in flight, so the same top up does not happen twice
+    ///
+    /// ```bash
+    /// near call treasury.testnet auto_wrap_near --accountId manager_v1.croncat.testnet
+    /// ```
+    pub fn auto_wrap_near(&mut self) -> Promise {
+        self.assert_owner_or_croncat();
+        let amount = self.get_wrap_near_topup();
+        assert_ne!(amount, 0, "No wNEAR top up needed");
+        self.internal_wrap_near(amount)
+    }
+}
+
+impl Contract {
+    /// Amount of NEAR needed to bring the wNEAR float back to target, 0 if disabled, nothing available or a wrap is in flight
+    pub(crate) fn get_wrap_near_topup(&self) -> Balance {
+        if self.wrap_near_pending {
+            return 0;
+        }
+        let (wrap_id, float) = match (&self.wrap_id, self.wrap_near_float) {
+            (Some(wrap_id), Some(float)) => (wrap_id, float),
+            _ => return 0,
+        };
+        let wrapped = self.ft_balances.get(wrap_id).unwrap_or(0);
+        let available = env::account_balance()
+            .saturating_sub(MIN_BALANCE_FOR_STORAGE)
+            .saturating_sub(WRAP_STORAGE_DEPOSIT);
+        u128::min(float.saturating_sub(wrapped), available)
+    }
+
+    /// Register (refunded if not needed), deposit NEAR, then credit the wNEAR balance
+    pub(crate) fn internal_wrap_near(&mut self, amount: Balance) -> Promise {
+        let wrap_id = self.wrap_id.clone().expect("No wrap account configured");
+        assert_ne!(amount, 0, "Nothing to wrap");
+        assert!(!self.wrap_near_pending, "Wrap already in flight");
+        assert!(
+            env::account_balance()
+                .saturating_sub(amount)
+                .saturating_sub(WRAP_STORAGE_DEPOSIT)
+                >= MIN_BALANCE_FOR_STORAGE,
+            "Account Balance Under Minimum Balance"
+        );
+        self.wrap_near_pending = true;
+
+        Promise::new(wrap_id.clone())
+            .function_call(
+                "storage_deposit".to_string(),
+                json!({
+                    "account_id": env::current_account_id(),
+                    "registration_only": true,
+                })
+                .to_string()
+                .into_bytes(),
+                WRAP_STORAGE_DEPOSIT,
+                GAS_WRAP_STORAGE_DEPOSIT,
+            )
+            .then(Promise::new(wrap_id.clone()).function_call(
+                "near_deposit".to_string(),
+                json!({}).to_string().into_bytes(),
+                amount,
+                GAS_WRAP_NEAR_DEPOSIT,
+            ))
+            .then(
+                Promise::new(env::current_account_id()).function_call(
+                    "callback_wrap_near".to_string(),
+                    json!({
+                        "wrap_id": wrap_id,
+                        "amount": U128::from(amount),
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NO_DEPOSIT,
+                    GAS_WRAP_CALLBACK,
+                ),
+            )
+    }
+
+    /// Debit the wNEAR balance, then withdraw it as NEAR
+    /// NOTE: Respects the minimum reserve of the wrap token, like any outgoing transfer
+    pub(crate) fn internal_unwrap_near(&mut self, amount: Balance) -> Promise {
+        let wrap_id = self.wrap_id.clone().expect("No wrap account configured");
+        assert_ne!(amount, 0, "Nothing to unwrap");
+        let balance = self.ft_balances.get(&wrap_id).unwrap_or(0);
+        assert!(balance >= amount, "Unwrap amount too high");
+        assert!(
+            balance - amount >= self.get_ft_min_reserve(&wrap_id),
+            "Unwrap amount exceeds token reserve"
+        );
+        self.ft_balances.insert(&wrap_id, &(balance - amount));
+        self.internal_ft_pending_add(&wrap_id, amount);
+
+        Promise::new(wrap_id.clone())
+            .function_call(
+                "near_withdraw".to_string(),
+                json!({
+                    "amount": U128::from(amount),
+                })
+                .to_string()
+                .into_bytes(),
+                ONE_YOCTO,
+                GAS_WRAP_NEAR_WITHDRAW,
+            )
+            .then(
+                Promise::new(env::current_account_id()).function_call(
+                    "callback_unwrap_near".to_string(),
+                    json!({
+                        "wrap_id": wrap_id,
+                        "amount": U128::from(amount),
+                    })
+                    .to_string()
+                    .into_bytes(),
+                    NO_DEPOSIT,
+                    GAS_WRAP_CALLBACK,
+                ),
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::*;
+    use near_sdk::test_utils::accounts;
+    use near_sdk::testing_env;
+
+    fn setup() -> Contract {
+        let mut contract = setup_contract();
+        contract.wrap_id = Some(accounts(5));
+        testing_env!(get_context(accounts(1))
+            .account_balance(100 * ONE_NEAR)
+            .build());
+        contract
+    }
+
+    #[test]
+    fn test_wrap_near() {
+        let mut contract = setup();
+        contract.wrap_near(U128::from(10 * ONE_NEAR));
+        assert!(contract.wrap_near_pending);
+
+        set_callback_context(
+            &mut get_context(accounts(0)),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        assert!(contract.callback_wrap_near(accounts(5), U128::from(10 * ONE_NEAR)));
+        assert!(!contract.wrap_near_pending);
+        assert_eq!(contract.ft_balances.get(&accounts(5)), Some(10 * ONE_NEAR));
+        let events = get_events("wrap_near");
+        assert_eq!(events[0]["amount"], "10000000000000000000000000");
+        assert_eq!(events[0]["wrapped"], true);
+    }
+
+    #[test]
+    fn test_wrap_near_failed() {
+        let mut contract = setup();
+        contract.wrap_near(U128::from(10 * ONE_NEAR));
+
+        set_callback_context(&mut get_context(accounts(0)), vec![PromiseResult::Failed]);
+        assert!(!contract.callback_wrap_near(accounts(5), U128::from(10 * ONE_NEAR)));
+        assert!(!contract.wrap_near_pending);
+        assert_eq!(contract.ft_balances.get(&accounts(5)), None);
+        assert!(get_events("wrap_near").is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Wrap already in flight")]
+    fn test_wrap_near_in_flight() {
+        let mut contract = setup();
+        contract.wrap_near(U128::from(10 * ONE_NEAR));
+        contract.wrap_near(U128::from(10 * ONE_NEAR));
+    }
+
+    #[test]
+    #[should_panic(expected = "Account Balance Under Minimum Balance")]
+    fn test_wrap_near_keeps_minimum() {
+        let mut contract = setup();
+        contract.wrap_near(U128::from(80 * ONE_NEAR));
+    }
+
+    #[test]
+    fn test_unwrap_near() {
+        let mut contract = setup();
+        contract.ft_balances.insert(&accounts(5), &(10 * ONE_NEAR));
+        contract.unwrap_near(U128::from(4 * ONE_NEAR));
+        assert_eq!(contract.ft_balances.get(&accounts(5)), Some(6 * ONE_NEAR));
+        assert_eq!(contract.ft_pending.get(&accounts(5)), Some(4 * ONE_NEAR));
+
+        set_callback_context(
+            &mut get_context(accounts(0)),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        assert!(contract.callback_unwrap_near(accounts(5), U128::from(4 * ONE_NEAR)));
+        assert_eq!(contract.ft_balances.get(&accounts(5)), Some(6 * ONE_NEAR));
+        assert_eq!(contract.ft_pending.get(&accounts(5)), None);
+        assert_eq!(get_events("wrap_near")[0]["wrapped"], false);
+    }
+
+    #[test]
+    fn test_unwrap_near_failed() {
+        let mut contract = setup();
+        contract.ft_balances.insert(&accounts(5), &(10 * ONE_NEAR));
+        contract.unwrap_near(U128::from(4 * ONE_NEAR));
+
+        set_callback_context(&mut get_context(accounts(0)), vec![PromiseResult::Failed]);
+        assert!(!contract.callback_unwrap_near(accounts(5), U128::from(4 * ONE_NEAR)));
+        assert_eq!(contract.ft_balances.get(&accounts(5)), Some(10 * ONE_NEAR));
+        assert_eq!(contract.ft_pending.get(&accounts(5)), None);
+        assert!(get_events("wrap_near").is_empty());
+    }
+
+    #[test]
+    fn test_wrap_near_topup() {
+        let mut contract = setup();
+        assert_eq!(contract.get_wrap_near_topup(), 0);
+
+        contract.wrap_near_float = Some(10 * ONE_NEAR);
+        contract.ft_balances.insert(&accounts(5), &(4 * ONE_NEAR));
+        assert_eq!(contract.get_wrap_near_topup(), 6 * ONE_NEAR);
+        assert!(contract.needs_wrap_near_topup().0);
+
+        // Limited by liquid NEAR above the storage minimum
+        testing_env!(get_context(accounts(1))
+            .account_balance(MIN_BALANCE_FOR_STORAGE + WRAP_STORAGE_DEPOSIT + ONE_NEAR)
+            .build());
+        assert_eq!(contract.get_wrap_near_topup(), ONE_NEAR);
+
+        // Topping up everything available still keeps the storage minimum, but never twice while in flight
+        contract.auto_wrap_near();
+        assert_eq!(contract.get_wrap_near_topup(), 0);
+        assert!(!contract.needs_wrap_near_topup().0);
+    }
+}